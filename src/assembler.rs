@@ -0,0 +1,540 @@
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
+use crate::instruction::Instruction;
+use crate::native::NativeFunction;
+use crate::program::Program;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+
+// Assembles the textual listing format used by `sq` and `sq-opt`.
+//
+//     CALL    .init              entry point
+//
+//     .data 36:                  initial heap, optionally sized
+//         0       "Table of squares:\n"
+//         65535   0
+//
+//     .sq 1 0:                   function name, args and locals
+//         PUSH    1
+//         FPPLUS
+//         FETCH
+//         IF
+//             ...                frame taken when the condition is non-zero
+//         ELSE
+//             ...                frame taken otherwise
+//         LOOP
+//             ...
+//
+// Nested frames are delimited by indentation. Functions are numbered in the order they
+// are declared and can be called before they are declared. Comments start with `;`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblerError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+#[derive(Debug, Clone)]
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+#[derive(Debug)]
+pub struct Assembler<'a> {
+    lines: Vec<Line<'a>>,
+    pos: usize,
+    function_ids: HashMap<&'a str, usize>,
+}
+
+impl Program {
+    pub fn from_assembly(source: &str) -> Result<Program, AssemblerError> {
+        Assembler::new(source).assemble()
+    }
+
+    pub fn from_assembly_file(file: String) -> Result<Program, AssemblerError> {
+        let source = fs::read_to_string(&file).map_err(|err| AssemblerError {
+            line: 0,
+            message: format!("unable to read {}: {}", file, err),
+        })?;
+        Program::from_assembly(&source)
+    }
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let lines = source
+            .lines()
+            .enumerate()
+            .filter_map(|(i, raw)| {
+                let text = strip_comment(raw).trim_end();
+                let trimmed = text.trim_start();
+                if trimmed.is_empty() {
+                    return None;
+                }
+                Some(Line {
+                    number: i + 1,
+                    indent: text.len() - trimmed.len(),
+                    text: trimmed,
+                })
+            })
+            .collect();
+        Self {
+            lines,
+            pos: 0,
+            function_ids: HashMap::new(),
+        }
+    }
+
+    pub fn assemble(mut self) -> Result<Program, AssemblerError> {
+        self.collect_function_ids()?;
+        let mut entry_point = None;
+        let mut heap_size = None;
+        let mut heap = Vec::new();
+        let mut functions = Vec::new();
+
+        while let Some(line) = self.peek().cloned() {
+            if line.indent != 0 {
+                return Err(error(&line, "unexpected indentation"));
+            }
+            self.pos += 1;
+            let mut words = line.text.split_whitespace();
+            match words.next() {
+                Some(word) if word.eq_ignore_ascii_case("CALL") => {
+                    if entry_point.is_some() {
+                        return Err(error(&line, "entry point declared twice"));
+                    }
+                    let target = words
+                        .next()
+                        .ok_or_else(|| error(&line, "CALL needs a function"))?;
+                    match self.call_target(&line, target)? {
+                        id if id >= 0 => entry_point = Some(id as usize),
+                        _ => return Err(error(&line, "entry point must be a function")),
+                    }
+                }
+                Some(".data:") | Some(".data") => {
+                    if let Some(size) = words.next() {
+                        let size = size.trim_end_matches(':');
+                        heap_size = Some(
+                            size.parse::<usize>()
+                                .map_err(|_| error(&line, "invalid heap size"))?,
+                        );
+                    }
+                    self.data(&mut heap)?;
+                }
+                Some(_) if line.text.starts_with('.') => {
+                    functions.push(self.function(&line)?);
+                }
+                _ => return Err(error(&line, "expected CALL, .data or a function header")),
+            }
+        }
+
+        let entry_point = match (entry_point, self.function_ids.get("init")) {
+            (Some(id), _) => id,
+            (None, Some(id)) => *id,
+            (None, None) => {
+                return Err(AssemblerError {
+                    line: 0,
+                    message: "no entry point and no init function".to_string(),
+                })
+            }
+        };
+        let heap_size = heap_size.unwrap_or_else(|| {
+            heap.iter()
+                .map(|(address, _)| address + 1)
+                .max()
+                .unwrap_or(0)
+        });
+        let mut builder = Program::builder()
+            .entry_point(entry_point)
+            .heap_size(heap_size)
+            .heap(heap);
+        for function in functions {
+            builder = builder.function(function);
+        }
+        Ok(builder.build())
+    }
+
+    fn peek(&self) -> Option<&Line<'a>> {
+        self.lines.get(self.pos)
+    }
+
+    fn collect_function_ids(&mut self) -> Result<(), AssemblerError> {
+        let mut id = 0;
+        for line in self.lines.iter().filter(|line| line.indent == 0) {
+            let first = line.text.split_whitespace().next().unwrap_or_default();
+            if !first.starts_with('.') || first == ".data" || first == ".data:" {
+                continue;
+            }
+            let (name, _, _) = parse_header(line)?;
+            if self.function_ids.insert(name, id).is_some() {
+                return Err(error(line, &format!("function {} declared twice", name)));
+            }
+            id += 1;
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, header: &Line<'a>) -> Result<Function, AssemblerError> {
+        let (name, args, locals) = parse_header(header)?;
+        let id = self.function_ids[name];
+        let data = match self.peek() {
+            Some(line) if line.indent > 0 => {
+                let indent = line.indent;
+                self.block(indent)?
+            }
+            _ => vec![],
+        };
+        let frame = Frame::builder().id(id).name(format!("{}-frame", name));
+        let frame = data.into_iter().fold(frame, |frame, data| frame.data(data));
+        Ok(Function::builder()
+            .id(id)
+            .name(name.to_string())
+            .args(args)
+            .locals(locals)
+            .frame(frame.build())
+            .build())
+    }
+
+    fn data(&mut self, heap: &mut Vec<(usize, i32)>) -> Result<(), AssemblerError> {
+        while let Some(line) = self.peek().cloned() {
+            if line.indent == 0 {
+                break;
+            }
+            self.pos += 1;
+            let (address, value) = line
+                .text
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| error(&line, "expected an address and a value"))?;
+            let address = address
+                .parse::<usize>()
+                .map_err(|_| error(&line, "invalid address"))?;
+            let value = value.trim();
+            if value.starts_with('"') {
+                let string = parse_string(&line, value)?;
+                for (i, c) in string.chars().chain(std::iter::once('\0')).enumerate() {
+                    heap.push((address + i, c as i32));
+                }
+            } else {
+                heap.push((address, parse_number(&line, value)?));
+            }
+        }
+        Ok(())
+    }
+
+    // Parses every line at exactly `indent`, descending into deeper indented frames.
+    fn block(&mut self, indent: usize) -> Result<Vec<FrameData>, AssemblerError> {
+        let mut data = Vec::new();
+        while let Some(line) = self.peek().cloned() {
+            if line.indent < indent {
+                break;
+            }
+            if line.indent > indent {
+                data.push(FrameData::Frame(self.nested_frame(indent)?));
+                continue;
+            }
+            self.pos += 1;
+            let mut words = line.text.split_whitespace();
+            let mnemonic = words.next().unwrap_or_default();
+            let operand = words.next();
+            if words.next().is_some() {
+                return Err(error(&line, "too many operands"));
+            }
+            if mnemonic.eq_ignore_ascii_case("ELSE") {
+                return Err(error(&line, "ELSE without IF"));
+            }
+            let instruction = Instruction::from_mnemonic(mnemonic)
+                .ok_or_else(|| error(&line, &format!("unknown instruction {}", mnemonic)))?;
+            data.push(FrameData::Instruction(instruction.clone(), vec![]));
+            match (instruction, operand) {
+                (Instruction::Push { .. }, Some(operand)) => {
+                    data.push(FrameData::from(parse_number(&line, operand)?));
+                }
+                (Instruction::Call { .. }, Some(operand)) => {
+                    data.push(FrameData::from(self.call_target(&line, operand)?));
+                }
+                (Instruction::Push { .. } | Instruction::Call { .. }, None) => {
+                    return Err(error(&line, &format!("{} needs an operand", mnemonic)));
+                }
+                (_, Some(_)) => {
+                    return Err(error(&line, &format!("{} takes no operand", mnemonic)));
+                }
+                (Instruction::IF { .. }, None) => {
+                    data.push(FrameData::Frame(self.nested_frame(indent)?));
+                    let has_else = matches!(
+                        self.peek(),
+                        Some(next) if next.indent == indent && next.text.eq_ignore_ascii_case("ELSE")
+                    );
+                    if has_else {
+                        self.pos += 1;
+                        data.push(FrameData::Frame(self.nested_frame(indent)?));
+                    } else {
+                        data.push(FrameData::Frame(Frame::default()));
+                    }
+                }
+                (Instruction::Loop { .. }, None) => {
+                    data.push(FrameData::Frame(self.nested_frame(indent)?));
+                }
+                (_, None) => {}
+            }
+        }
+        Ok(data)
+    }
+
+    // The frame indented under the current line, or an empty frame if there is none.
+    fn nested_frame(&mut self, indent: usize) -> Result<Frame, AssemblerError> {
+        match self.peek() {
+            Some(line) if line.indent > indent => {
+                let indent = line.indent;
+                Ok(self.block(indent)?.into_iter().collect())
+            }
+            _ => Ok(Frame::default()),
+        }
+    }
+
+    fn call_target(&self, line: &Line, target: &str) -> Result<i32, AssemblerError> {
+        if let Some(name) = target.strip_prefix('.') {
+            return self
+                .function_ids
+                .get(name)
+                .map(|id| *id as i32)
+                .ok_or_else(|| error(line, &format!("unknown function {}", name)));
+        }
+        if let Some(native) = NativeFunction::from_name(target) {
+            return Ok(native.id());
+        }
+        target
+            .parse::<i32>()
+            .map_err(|_| error(line, &format!("unknown call target {}", target)))
+    }
+}
+
+fn error(line: &Line, message: &str) -> AssemblerError {
+    AssemblerError {
+        line: line.number,
+        message: message.to_string(),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_header<'a>(line: &Line<'a>) -> Result<(&'a str, usize, usize), AssemblerError> {
+    let header = line
+        .text
+        .strip_suffix(':')
+        .ok_or_else(|| error(line, "function header must end with ':'"))?;
+    let mut words = header[1..].split_whitespace();
+    let (Some(name), Some(args), Some(locals), None) =
+        (words.next(), words.next(), words.next(), words.next())
+    else {
+        return Err(error(line, "expected .name args locals:"));
+    };
+    let args = args
+        .parse::<usize>()
+        .map_err(|_| error(line, "invalid argument count"))?;
+    let locals = locals
+        .parse::<usize>()
+        .map_err(|_| error(line, "invalid local count"))?;
+    Ok((name, args, locals))
+}
+
+fn parse_number(line: &Line, word: &str) -> Result<i32, AssemblerError> {
+    word.parse::<i32>()
+        .map_err(|_| error(line, &format!("invalid number {}", word)))
+}
+
+fn parse_string(line: &Line, literal: &str) -> Result<String, AssemblerError> {
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| error(line, "unterminated string"))?;
+    let mut s = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('t') => s.push('\t'),
+            Some('r') => s.push('\r'),
+            Some('\\') => s.push('\\'),
+            Some('"') => s.push('"'),
+            other => {
+                return Err(error(
+                    line,
+                    &format!("invalid escape \\{}", other.unwrap_or(' ')),
+                ))
+            }
+        }
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateHolder;
+    use crate::tvm::Tvm;
+
+    const SQ: &str = r#"
+CALL    .init
+
+.data 36:
+    0       "Table of squares:\n"
+    19      " squared equals "
+    65535   0
+
+.sq 1 0:
+    PUSH    1
+    FPPLUS
+    FETCH
+    PUSH    1
+    FPPLUS
+    FETCH
+    MUL
+    RETURN
+    PUSH    0
+
+.init 0 1:
+    PUSH    0
+    CALL    sprint
+    POP
+    PUSH    1
+    FPPLUS
+    PUSH    1
+    STORE
+    LOOP
+        PUSH    1
+        FPPLUS
+        FETCH
+        PUSH    10
+        GT
+        BREAK
+        PUSH    1
+        FPPLUS
+        FETCH
+        CALL    iprint
+        POP
+        PUSH    19
+        CALL    sprint
+        POP
+        PUSH    1
+        FPPLUS
+        FETCH
+        CALL    .sq
+        CALL    iprint
+        POP
+        CALL    nl
+        POP
+        PUSH    1
+        FPPLUS
+        PUSH    1
+        FPPLUS
+        FETCH
+        PUSH    1
+        ADD
+        STORE
+    PUSH    0
+"#;
+
+    #[test]
+    fn test_matches_json_tape() {
+        let assembled = Program::from_assembly(SQ).unwrap();
+        let json = Program::from_file("sq.json".to_string()).unwrap();
+        assert_eq!(assembled.entry_point, json.entry_point);
+        assert_eq!(assembled.heap_size, json.heap_size);
+        assert_eq!(assembled.functions, json.functions);
+        let mut assembled_heap = assembled.heap.clone();
+        let mut json_heap = json.heap.clone();
+        assembled_heap.sort();
+        json_heap.sort();
+        assert_eq!(assembled_heap, json_heap);
+    }
+
+    #[test]
+    fn test_runs() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_assembly(SQ).unwrap());
+        tvm.start();
+        while !tvm.is_halted() {
+            tvm.tick();
+        }
+        assert!(tvm
+            .stdout
+            .starts_with("Table of squares:\n1 squared equals 1\n"));
+        assert!(tvm.stdout.ends_with("10 squared equals 100\n"));
+    }
+
+    #[test]
+    fn test_assembles_shipped_listing() {
+        let program = Program::from_assembly_file("sq".to_string()).unwrap();
+        assert_eq!(program.entry_point, 1);
+        assert_eq!(program.functions[0].name, "sq");
+        assert_eq!(program.functions[1].locals, 1);
+    }
+
+    #[test]
+    fn test_if_else() {
+        let program = Program::from_assembly(
+            r#"
+.init 0 0:
+    PUSH    1
+    IF
+        PUSH    2
+    ELSE
+    PUSH    3
+    IF
+    ELSE
+        PUSH    4
+"#,
+        )
+        .unwrap();
+        let data = &program.functions[0].frame.data;
+        assert_eq!(data.len(), 10);
+        assert!(matches!(&data[3], FrameData::Frame(frame) if frame.data.len() == 2));
+        assert!(matches!(&data[4], FrameData::Frame(frame) if frame.data.is_empty()));
+        assert!(matches!(&data[8], FrameData::Frame(frame) if frame.data.is_empty()));
+        assert!(matches!(&data[9], FrameData::Frame(frame) if frame.data.len() == 2));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = Program::from_assembly(".init 0 0:\n    PUSH\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.to_string(), "line 2: PUSH needs an operand");
+
+        let err = Program::from_assembly(".init 0 0:\n    CALL .missing\n").unwrap_err();
+        assert_eq!(err.message, "unknown function missing");
+
+        let err = Program::from_assembly(".init 0 0:\n    JUMP 3\n").unwrap_err();
+        assert_eq!(err.message, "unknown instruction JUMP");
+
+        let err = Program::from_assembly(".init 0 0:\n    ELSE\n").unwrap_err();
+        assert_eq!(err.message, "ELSE without IF");
+    }
+}
@@ -0,0 +1,197 @@
+use crate::program::Program;
+use crate::stack::StackHolder;
+use crate::tvm::Tvm;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Runs many tapes headless on a pool of threads. Jobs share their program through an Arc, so
+// the same tape can be run against many inputs without copying it.
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub program: Arc<Program>,
+    // Scripted stdin. Jobs never read the terminal.
+    pub input: String,
+    pub max_ticks: usize,
+}
+
+impl Job {
+    pub fn new(program: impl Into<Arc<Program>>) -> Self {
+        Job {
+            program: program.into(),
+            input: String::new(),
+            max_ticks: 1_000_000,
+        }
+    }
+
+    pub fn input(mut self, input: impl Into<String>) -> Self {
+        self.input = input.into();
+        self
+    }
+
+    pub fn max_ticks(mut self, max_ticks: usize) -> Self {
+        self.max_ticks = max_ticks;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    Halted,
+    // The job ran out of ticks before halting.
+    TickLimit,
+    // The machine panicked, for example on a stack overflow or bad input.
+    Fault(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobStats {
+    pub ticks: usize,
+    pub elapsed: Duration,
+    pub max_stack_depth: usize,
+    pub heap_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobResult {
+    pub stdout: String,
+    pub status: ExitStatus,
+    pub stats: JobStats,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchRunner {
+    threads: usize,
+}
+
+impl Default for BatchRunner {
+    fn default() -> Self {
+        BatchRunner {
+            threads: thread::available_parallelism().map_or(1, usize::from),
+        }
+    }
+}
+
+impl BatchRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Runs every job and returns their results in job order.
+    pub fn run(&self, jobs: Vec<Job>) -> Vec<JobResult> {
+        let next = AtomicUsize::new(0);
+        let results: Vec<Mutex<Option<JobResult>>> =
+            jobs.iter().map(|_| Mutex::new(None)).collect();
+        thread::scope(|scope| {
+            for _ in 0..self.threads.min(jobs.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(job) = jobs.get(i) else { break };
+                    *results[i].lock().unwrap() = Some(run_job(job));
+                });
+            }
+        });
+        results
+            .into_iter()
+            .map(|result| result.into_inner().unwrap().unwrap())
+            .collect()
+    }
+}
+
+pub fn run_job(job: &Job) -> JobResult {
+    let mut tvm = Tvm {
+        input: Some(job.input.clone()),
+        ..Tvm::default()
+    };
+    tvm.load(Arc::clone(&job.program));
+    let mut max_stack_depth = 0;
+    let start = Instant::now();
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        while !tvm.is_halted() && tvm.ticks < job.max_ticks {
+            tvm.step();
+            max_stack_depth = max_stack_depth.max(tvm.get_stack_size());
+        }
+    }));
+    let status = match outcome {
+        Ok(()) if tvm.is_halted() => ExitStatus::Halted,
+        Ok(()) => ExitStatus::TickLimit,
+        Err(payload) => ExitStatus::Fault(panic_message(payload)),
+    };
+    JobResult {
+        stdout: tvm.stdout,
+        status,
+        stats: JobStats {
+            ticks: tvm.ticks,
+            elapsed: start.elapsed(),
+            max_stack_depth,
+            heap_size: tvm.heap_size,
+        },
+    }
+}
+
+pub(crate) fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECHO: &str = "fun init() {\n    var n\n    n : iread(-1)\n    iprint(.n * .n)\n}";
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_tvm_is_send() {
+        assert_send::<Tvm>();
+        assert_send::<Job>();
+    }
+
+    #[test]
+    fn test_run() {
+        let program = Arc::new(Program::from_source(ECHO).unwrap());
+        let jobs = (0..20)
+            .map(|n| Job::new(Arc::clone(&program)).input(format!("{}\n", n)))
+            .collect();
+        let results = BatchRunner::new().threads(4).run(jobs);
+        assert_eq!(results.len(), 20);
+        for (n, result) in results.iter().enumerate() {
+            assert_eq!(result.status, ExitStatus::Halted);
+            assert_eq!(result.stdout, (n * n).to_string());
+            assert!(result.stats.ticks > 0);
+            assert!(result.stats.max_stack_depth > 0);
+        }
+    }
+
+    #[test]
+    fn test_tick_limit_and_fault() {
+        let sieve = Program::from_file("sieve.json".to_string()).unwrap();
+        let echo = Program::from_source(ECHO).unwrap();
+        let results = BatchRunner::new().run(vec![
+            Job::new(sieve).max_ticks(100),
+            Job::new(echo).input("seven\n"),
+        ]);
+        assert_eq!(results[0].status, ExitStatus::TickLimit);
+        assert_eq!(results[0].stats.ticks, 100);
+        assert_eq!(
+            results[1].status,
+            ExitStatus::Fault(
+                "Failed to parse input: ParseIntError { kind: InvalidDigit }".to_string()
+            )
+        );
+    }
+}
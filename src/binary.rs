@@ -0,0 +1,292 @@
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
+use crate::program::Program;
+use std::fmt::{Display, Formatter};
+use std::fs;
+
+// Compact binary tape format. All numbers are little endian.
+//
+//     magic       b"TVM\0"
+//     version     u16
+//     entry       u32
+//     heap size   u32
+//     heap        u32 count, then (u32 address, i32 value) for every initialized cell
+//     functions   u32 count, then for every function:
+//                     u32 id, u32 name length, name, u32 args, u32 locals, frame
+//
+// A frame is a u32 byte length followed by its entries. Each entry is a tag byte, 0 for a
+// word followed by an i32, or 1 for a nested frame.
+//
+// Debug info is not carried. Use JSON tapes to keep it.
+
+pub const MAGIC: &[u8; 4] = b"TVM\0";
+pub const VERSION: u16 = 1;
+
+const TAG_WORD: u8 = 0;
+const TAG_FRAME: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Display for TapeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for TapeError {}
+
+impl Program {
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn from_binary(bytes: &[u8]) -> Result<Program, TapeError> {
+        Reader { bytes, pos: 0 }.program()
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        write_u32(&mut out, self.entry_point);
+        write_u32(&mut out, self.heap_size);
+        write_u32(&mut out, self.heap.len());
+        for (address, value) in &self.heap {
+            write_u32(&mut out, *address);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        write_u32(&mut out, self.functions.len());
+        for function in &self.functions {
+            write_u32(&mut out, function.id);
+            write_u32(&mut out, function.name.len());
+            out.extend_from_slice(function.name.as_bytes());
+            write_u32(&mut out, function.args);
+            write_u32(&mut out, function.locals);
+            write_frame(&mut out, &function.frame);
+        }
+        out
+    }
+
+    pub fn write_binary_file(&self, file: String) -> std::io::Result<()> {
+        fs::write(file, self.to_binary())
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_frame(out: &mut Vec<u8>, frame: &Frame) {
+    let mut block = Vec::new();
+    for data in &frame.data {
+        match data {
+            FrameData::Frame(nested) => {
+                block.push(TAG_FRAME);
+                write_frame(&mut block, nested);
+            }
+            word => {
+                block.push(TAG_WORD);
+                block.extend_from_slice(&word.get_id().to_le_bytes());
+            }
+        }
+    }
+    write_u32(out, block.len());
+    out.extend(block);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, TapeError> {
+        Err(TapeError {
+            offset: self.pos,
+            message: message.to_string(),
+        })
+    }
+
+    fn take(&mut self, n: usize, what: &str) -> Result<&'a [u8], TapeError> {
+        match self.bytes.get(self.pos..self.pos + n) {
+            Some(bytes) => {
+                self.pos += n;
+                Ok(bytes)
+            }
+            None => self.error(&format!("unexpected end of tape reading {}", what)),
+        }
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, TapeError> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, TapeError> {
+        Ok(u16::from_le_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<usize, TapeError> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()) as usize)
+    }
+
+    fn i32(&mut self, what: &str) -> Result<i32, TapeError> {
+        Ok(i32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    fn program(mut self) -> Result<Program, TapeError> {
+        if self.take(4, "magic")? != MAGIC {
+            self.pos = 0;
+            return self.error("not a binary tape");
+        }
+        let version = self.u16("version")?;
+        if version != VERSION {
+            self.pos -= 2;
+            return self.error(&format!("unsupported version {}", version));
+        }
+        let mut program = Program::builder()
+            .entry_point(self.u32("entry point")?)
+            .heap_size(self.u32("heap size")?);
+        let mut heap = Vec::new();
+        for _ in 0..self.u32("heap count")? {
+            heap.push((self.u32("heap address")?, self.i32("heap value")?));
+        }
+        program = program.heap(heap);
+        for _ in 0..self.u32("function count")? {
+            program = program.function(self.function()?);
+        }
+        if self.pos != self.bytes.len() {
+            return self.error("trailing bytes after functions");
+        }
+        Ok(program.build())
+    }
+
+    fn function(&mut self) -> Result<Function, TapeError> {
+        let id = self.u32("function id")?;
+        let length = self.u32("function name length")?;
+        let start = self.pos;
+        let name = match std::str::from_utf8(self.take(length, "function name")?) {
+            Ok(name) => name.to_string(),
+            Err(_) => {
+                self.pos = start;
+                return self.error("function name is not valid utf-8");
+            }
+        };
+        let args = self.u32("function args")?;
+        let locals = self.u32("function locals")?;
+        let mut frame = self.frame()?;
+        // Named and numbered the way JSON tapes load.
+        frame.id = id;
+        frame.name = format!("{}-frame", name);
+        Ok(Function::builder()
+            .id(id)
+            .name(name)
+            .args(args)
+            .locals(locals)
+            .frame(frame)
+            .build())
+    }
+
+    fn frame(&mut self) -> Result<Frame, TapeError> {
+        let length = self.u32("frame length")?;
+        let end = self.pos + length;
+        if end > self.bytes.len() {
+            return self.error("frame extends past end of tape");
+        }
+        let mut data = Vec::new();
+        while self.pos < end {
+            let data_start = self.pos;
+            match self.u8("frame entry")? {
+                TAG_WORD => data.push(FrameData::from(self.i32("word")?)),
+                TAG_FRAME => data.push(FrameData::Frame(self.frame()?)),
+                tag => {
+                    self.pos = data_start;
+                    return self.error(&format!("unknown frame entry tag {}", tag));
+                }
+            }
+        }
+        if self.pos != end {
+            return self.error("frame entry crosses end of frame");
+        }
+        Ok(data.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    fn assert_round_trip(file: &str) {
+        let program = Program::from_file(file.to_string()).unwrap();
+        let bytes = program.to_binary();
+        assert!(Program::is_binary(&bytes));
+        let read = Program::from_binary(&bytes).unwrap();
+        assert_eq!(read.entry_point, program.entry_point);
+        assert_eq!(read.heap_size, program.heap_size);
+        assert_eq!(read.heap, program.heap);
+        assert_eq!(read.functions, program.functions);
+        assert_eq!(read.to_binary(), bytes);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_round_trip("sq.json");
+        assert_round_trip("sieve.json");
+    }
+
+    #[test]
+    fn test_smaller_than_json() {
+        let json = fs::read("sieve.json").unwrap();
+        let binary = Program::from_file("sieve.json".to_string())
+            .unwrap()
+            .to_binary();
+        assert!(binary.len() < json.len());
+    }
+
+    #[test]
+    fn test_from_file_detects_format() {
+        let dir = temp_dir("binary");
+        let path = dir.join("sq.tvm").to_str().unwrap().to_string();
+        let program = Program::from_file("sq.json".to_string()).unwrap();
+        program.write_binary_file(path.clone()).unwrap();
+        let read = Program::from_file(path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(read.functions, program.functions);
+    }
+
+    #[test]
+    fn test_debug_info_is_dropped() {
+        let program = Program::from_source_file("sq.t".to_string()).unwrap();
+        let read = Program::from_binary(&program.to_binary()).unwrap();
+        let mut expected = program.clone();
+        expected.strip_debug_info();
+        assert!(!read.has_debug_info());
+        assert_eq!(read.functions, expected.functions);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = Program::from_binary(b"[[1, 2]]").unwrap_err();
+        assert_eq!(error.to_string(), "offset 0: not a binary tape");
+
+        let mut bytes = Program::from_file("sq.json".to_string())
+            .unwrap()
+            .to_binary();
+        bytes[4] = 9;
+        let error = Program::from_binary(&bytes).unwrap_err();
+        assert_eq!(error.to_string(), "offset 4: unsupported version 9");
+
+        bytes[4] = 1;
+        let truncated = &bytes[..bytes.len() - 3];
+        let error = Program::from_binary(truncated).unwrap_err();
+        assert_eq!(error.message, "frame extends past end of tape");
+
+        bytes.push(0);
+        let error = Program::from_binary(&bytes).unwrap_err();
+        assert_eq!(error.message, "trailing bytes after functions");
+    }
+}
@@ -0,0 +1,243 @@
+use crate::function::Function;
+use crate::tvm::Tvm;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+// The Tranquility call stack, rebuilt from the call states and the saved frame pointers. A
+// call pushes its arguments, then its locals, then the caller's frame pointer, and points fp at
+// that last cell:
+//
+//     fp + locals + args      first argument
+//     ...
+//     fp + locals + 1         last argument
+//     fp + locals             last local
+//     ...
+//     fp + 1                  first local
+//     fp                      caller's fp
+
+// A parameter or local of a call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variable {
+    // From the debug info, or `arg0`, `local0` and so on without it.
+    pub name: String,
+    pub address: usize,
+    pub value: i32,
+}
+
+// A function call in progress.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallFrame {
+    pub function: Arc<Function>,
+    // Address of the saved frame pointer. None while the call is about to push its frame.
+    pub frame_pointer: Option<usize>,
+    pub args: Vec<Variable>,
+    pub locals: Vec<Variable>,
+    // Index of each nested frame being evaluated, ending with the PC of the innermost frame.
+    pub pc: Vec<usize>,
+    pub line: Option<usize>,
+    // Where execution carries on once the call returns: the caller and its PC path. None for
+    // the entry point.
+    pub return_to: Option<(String, Vec<usize>)>,
+}
+
+impl CallFrame {
+    // The stack cells the call owns, from its saved frame pointer up to its first argument.
+    pub fn stack_cells(&self) -> Option<RangeInclusive<usize>> {
+        let fp = self.frame_pointer?;
+        Some(fp..=fp + self.function.locals + self.function.args)
+    }
+}
+
+impl Tvm {
+    /// The calls in progress, innermost first.
+    pub fn call_stack(&self) -> Vec<CallFrame> {
+        let mut calls: Vec<CallFrame> = Vec::new();
+        // The halt state still points at the last instruction, but every frame has been popped.
+        if self.is_halted() {
+            return calls;
+        }
+        let mut depth = self.state.depth();
+        let mut frame_pointer = self.frame_pointer;
+        while let Some(position) = self.state.get_code_position_below(depth) {
+            let mut states = self.state.iter_below(depth);
+            // A function call on top has not run yet, so its frame is not on the stack.
+            let pushed = !matches!(states.next(), Some((_, state)) if state.is_function_call());
+            // Carry on below the call the position is in.
+            depth = self
+                .state
+                .iter_below(depth)
+                .find(|(_, state)| state.is_function_call())
+                .map_or(0, |(index, _)| index);
+
+            let function = position.function;
+            // The pointers can be edited to anything, so the walk stops at the first frame that
+            // does not fit in memory.
+            let call = if pushed {
+                let fp = frame_pointer;
+                let Some(saved) = self.memory.get(fp) else {
+                    break;
+                };
+                frame_pointer = usize::try_from(*saved).unwrap_or(usize::MAX);
+                let locals: Option<Vec<Variable>> = (1..=function.locals)
+                    .map(|i| self.variable(&function, fp, fp + i, format!("local{}", i - 1)))
+                    .collect();
+                let args: Option<Vec<Variable>> = (0..function.args)
+                    .map(|i| {
+                        let address = fp + function.locals + function.args - i;
+                        self.variable(&function, fp, address, format!("arg{}", i))
+                    })
+                    .collect();
+                let (Some(locals), Some(args)) = (locals, args) else {
+                    break;
+                };
+                CallFrame {
+                    frame_pointer: Some(fp),
+                    args,
+                    locals,
+                    pc: position.path,
+                    line: position.line,
+                    return_to: None,
+                    function,
+                }
+            } else {
+                // The arguments are on top of the stack, first pushed deepest.
+                let args: Option<Vec<Variable>> = (0..function.args)
+                    .map(|i| {
+                        let address = self.stack_pointer + function.args - i;
+                        // Named by where the argument will sit once the frame is pushed.
+                        let offset = function.locals + function.args - i;
+                        Some(Variable {
+                            name: variable_name(&function, offset, format!("arg{}", i)),
+                            address,
+                            value: *self.memory.get(address)?,
+                        })
+                    })
+                    .collect();
+                let Some(args) = args else {
+                    break;
+                };
+                CallFrame {
+                    frame_pointer: None,
+                    args,
+                    locals: Vec::new(),
+                    pc: position.path,
+                    line: position.line,
+                    return_to: None,
+                    function,
+                }
+            };
+            if let Some(callee) = calls.last_mut() {
+                callee.return_to = Some((call.function.name.clone(), call.pc.clone()));
+            }
+            calls.push(call);
+        }
+        calls
+    }
+
+    /// How many function calls are in progress, counting one about to start. Unlike
+    /// `call_stack`, this does not walk the states.
+    pub fn call_depth(&self) -> usize {
+        if self.is_halted() {
+            return 0;
+        }
+        self.state.call_count()
+    }
+
+    fn variable(
+        &self,
+        function: &Function,
+        fp: usize,
+        address: usize,
+        default: String,
+    ) -> Option<Variable> {
+        Some(Variable {
+            name: variable_name(function, address - fp, default),
+            address,
+            value: *self.memory.get(address)?,
+        })
+    }
+}
+
+// The debug name of the variable `offset` cells above the frame pointer.
+fn variable_name(function: &Function, offset: usize, default: String) -> String {
+    function
+        .debug
+        .as_ref()
+        .and_then(|debug| debug.variables.iter().find(|(_, o)| *o == offset as i32))
+        .map_or(default, |(name, _)| name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    #[test]
+    fn test_call_stack() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_source_file("sq.t".to_string()).unwrap());
+        assert!(tvm.call_stack().is_empty());
+        tvm.breakpoints.insert("sq".to_string());
+        while tvm.at_breakpoint().is_none() {
+            tvm.step();
+        }
+
+        // About to call sq(1) from main.
+        let calls = tvm.call_stack();
+        assert_eq!(tvm.call_depth(), 2);
+        let names: Vec<&str> = calls.iter().map(|c| c.function.name.as_str()).collect();
+        assert_eq!(names, ["sq", "init"]);
+        assert_eq!(calls[0].frame_pointer, None);
+        assert_eq!(calls[0].args[0].value, 1);
+        assert_eq!(calls[0].return_to.as_ref().unwrap().0, "init");
+        assert_eq!(calls[1].return_to, None);
+        assert_eq!(
+            calls[1].frame_pointer,
+            Some(65535 - calls[1].function.locals)
+        );
+
+        tvm.step();
+        let calls = tvm.call_stack();
+        let sq = &calls[0];
+        assert_eq!(sq.frame_pointer, Some(tvm.frame_pointer));
+        assert_eq!(sq.args.len(), 1);
+        assert_eq!(sq.args[0].name, "n");
+        assert_eq!(sq.args[0].value, 1);
+        assert_eq!(sq.locals.len(), sq.function.locals);
+        assert_eq!(
+            sq.stack_cells(),
+            Some(tvm.frame_pointer..=tvm.frame_pointer + sq.function.locals + 1)
+        );
+        assert_eq!(
+            calls[1].frame_pointer,
+            Some(tvm.memory[tvm.frame_pointer] as usize)
+        );
+        assert!(calls[1].locals.iter().any(|l| l.name == "i"));
+
+        tvm.breakpoints.clear();
+        tvm.run(usize::MAX);
+        assert!(tvm.call_stack().is_empty());
+    }
+
+    #[test]
+    fn test_call_stack_after_pointer_edits() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_source_file("sq.t".to_string()).unwrap());
+        tvm.breakpoints.insert("sq".to_string());
+        while tvm.at_breakpoint().is_none() {
+            tvm.step();
+        }
+        tvm.step();
+        let saved = tvm.frame_pointer;
+
+        // The caller's saved fp is not an address, so the walk stops above the caller.
+        tvm.edit_cell(saved, -1).unwrap();
+        let calls = tvm.call_stack();
+        let names: Vec<&str> = calls.iter().map(|c| c.function.name.as_str()).collect();
+        assert_eq!(names, ["sq"]);
+
+        // The locals of a frame at the top of memory would be past its end.
+        tvm.edit_frame_pointer(65535).unwrap();
+        assert!(tvm.call_stack().is_empty());
+    }
+}
@@ -1,4 +1,6 @@
 use crate::function::Function;
+use crate::heap::HeapHolder;
+use crate::log::{Category, Level};
 use crate::native::NativeFunction;
 use crate::stack::StackHolder;
 use crate::state::StateResult::Return;
@@ -6,11 +8,11 @@ use crate::state::{StateHolder, StateResult};
 use crate::tvm::Tvm;
 use rand::Rng;
 use std::fmt::{Debug, Display, Formatter};
-
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Callable {
-    Function(Function),
+    Function(Arc<Function>),
     Native(NativeFunction),
 }
 
@@ -55,10 +57,22 @@ pub trait Caller: Debug + Clone {
 impl Caller for Tvm {
     fn do_call(&mut self, callable: Callable) {
         self.state.set_result(StateResult::Continue);
+        let category = match callable {
+            Callable::Function(_) => Category::Call,
+            Callable::Native(_) => Category::Native,
+        };
+        if self.log.is_enabled(Level::Debug) {
+            self.log.push(
+                self.ticks,
+                Level::Debug,
+                category,
+                format_args!("Calling {}", callable.name()),
+            );
+        }
         match callable {
             Callable::Function(function) => {
                 // println!("Calling function: {}", function);
-                let frame = function.frame;
+                let frame = function.frame.clone();
                 // expect that arguments have already been pushed to the stack
                 // push zero to the stack for the local data
                 for _ in 0..function.locals {
@@ -78,7 +92,7 @@ impl Caller for Tvm {
                 // // copy the top of the stack into the frame pointer
                 // self.frame_pointer = self.peek() as usize;
                 // // Increment the stack pointer by the number of local variables and parameters of the function
-                // self.stack_pointer += (function.locals + function.args) as usize;
+                // self.stack_pointer += function.locals + function.args;
                 // // push the return value to the stack
                 // self.push(r);
             }
@@ -100,36 +114,29 @@ impl Caller for Tvm {
                 }
                 NativeFunction::IRead { .. } => {
                     let prompt_addr = self.pop();
-                    let mut prompt = String::new();
-                    if prompt_addr == -1 {
-                        prompt = "Integer input: ".to_string();
+                    let prompt = if prompt_addr == -1 {
+                        "Integer input: ".to_string()
                     } else {
-                        prompt = self.a2s(prompt_addr as usize);
-                    }
-                    let mut input = String::new();
-                    print!("{}", prompt);
-                    std::io::stdin()
-                        .read_line(&mut input)
-                        .expect("Failed to read line");
+                        self.a2s(prompt_addr as usize)
+                    };
+                    let input = self.read_line(&prompt);
                     let arg = input.trim().parse::<i32>().expect("Failed to parse input");
                     self.push(arg);
                     self.state.set_result(Return);
                 }
                 NativeFunction::SRead { .. } => {
+                    // Reads a line into the buffer at the second argument.
+                    let address = self.pop();
                     let prompt_addr = self.pop();
-                    let mut prompt = String::new();
-                    if prompt_addr == -1 {
-                        prompt = "String input: ".to_string();
+                    let prompt = if prompt_addr == -1 {
+                        "String input: ".to_string()
                     } else {
-                        prompt = self.a2s(prompt_addr as usize);
-                    }
-                    let mut input = String::new();
-                    print!("{}", prompt);
-                    std::io::stdin()
-                        .read_line(&mut input)
-                        .expect("Failed to read line");
-                    let arg = input.trim().parse::<i32>().expect("Failed to parse input");
-                    self.push(arg);
+                        self.a2s(prompt_addr as usize)
+                    };
+                    let input = self.read_line(&prompt);
+                    let line = input.trim_end_matches(['\r', '\n']).to_string();
+                    self.write_string(address as usize, line);
+                    self.push(0);
                     self.state.set_result(Return);
                 }
                 NativeFunction::NL { .. } => {
@@ -145,42 +152,44 @@ impl Caller for Tvm {
                     self.state.set_result(Return);
                 }
                 NativeFunction::Timer { .. } => {
-                    let id = self.pop();
-                    let time = self.pop();
+                    let _id = self.pop();
+                    let _time = self.pop();
                     // println!("Timer {} set to {}", id, time);
                     self.push(0);
                     self.state.set_result(Return);
                 }
                 NativeFunction::StopTimer { .. } => {
-                    let id = self.pop();
-                    let time = self.pop();
-                    // println!("Timer {} stopped at {}", id, time);
+                    let _id = self.pop();
                     self.push(0);
                     self.state.set_result(Return);
                 }
                 NativeFunction::Alloc { .. } => {
                     let size = self.pop();
-                    self.push(self.heap_size as i32);
-                    self.heap_size += size as usize;
+                    let address = self.allocate(size as usize);
+                    self.push(address as i32);
                     // println!("Allocating {} bytes", size);
                     self.state.set_result(Return);
                 }
                 NativeFunction::Free { .. } => {
                     let addr = self.pop();
                     // println!("Freeing {}", addr);
+                    self.allocations.retain(|a| a.address != addr as usize);
                     self.push(0);
                     self.state.set_result(Return);
                 }
                 NativeFunction::I2S { .. } => {
-                    let arg = self.pop();
+                    // The buffer is on top, with the number below it.
                     let addr = self.pop();
-                    self.write_string(addr as usize, arg.to_string());
-                    self.push(0);
+                    let arg = self.pop();
+                    let text = arg.to_string();
+                    let len = text.len();
+                    self.write_string(addr as usize, text);
+                    self.push(len as i32);
                     self.state.set_result(Return);
                 }
                 _ => {
                     // println!("Calling native function: {:?}", native_function);
-                },
+                }
             },
         }
     }
@@ -201,7 +210,7 @@ impl Caller for Tvm {
                 let r = self.pop();
                 self.stack_pointer = self.frame_pointer;
                 self.frame_pointer = self.peek() as usize;
-                self.stack_pointer += (function.locals + function.args) as usize;
+                self.stack_pointer += function.locals + function.args;
                 self.push(r);
             }
             Callable::Native(_) => {}
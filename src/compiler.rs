@@ -0,0 +1,998 @@
+use crate::debug_info::{DebugInfo, FunctionDebugInfo};
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
+use crate::instruction::Instruction;
+use crate::native::NativeFunction;
+use crate::program::Program;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+
+// Compiles Tranquility source (`.t` files) into a program.
+//
+//     fun sq(n) {
+//         var m
+//         return .n * .n
+//     }
+//
+// A bare name evaluates to the address of a variable and `.` fetches from an address, so
+// `i : .i + 1` increments `i` and `(.prime + .i) : 1` stores through a pointer. String
+// literals are placed on the heap and evaluate to their address. Execution starts at `init`.
+//
+// Locals live at `fp + 1 ..= fp + locals` in declaration order, followed by the arguments in
+// reverse order, matching the frame built by `Caller::do_call`. Locals that are never
+// referenced are not allocated.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl Program {
+    pub fn from_source(source: &str) -> Result<Program, CompileError> {
+        Compiler::new(source)?.compile()
+    }
+
+    pub fn from_source_file(file: String) -> Result<Program, CompileError> {
+        let source = fs::read_to_string(&file).map_err(|err| CompileError {
+            line: 0,
+            message: format!("unable to read {}: {}", file, err),
+        })?;
+        Compiler::new(&source)?.source_name(file).compile()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(i32),
+    Str(String),
+    Symbol(&'static str),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Symbol(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// Longest symbols first so that `<=` is not read as `<` followed by `=`.
+const SYMBOLS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "(", ")", "{", "}", ",", ":", ".", "+", "-", "*", "/", "%",
+    "!", "&", "|", "^", "<", ">",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        let error = |message: String| CompileError { line, message };
+        match c {
+            '\n' => {
+                line += 1;
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '#' => {
+                while matches!(chars.peek(), Some(&(_, c)) if c != '\n') {
+                    chars.next();
+                }
+            }
+            '0'..='9' => {
+                let mut end = i;
+                while let Some(&(j, c)) = chars.peek() {
+                    if !c.is_ascii_digit() {
+                        break;
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                let n = source[i..end]
+                    .parse::<i32>()
+                    .map_err(|_| error(format!("number {} is too large", &source[i..end])))?;
+                tokens.push((Token::Number(n), line));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut end = i;
+                while let Some(&(j, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push((Token::Ident(source[i..end].to_string()), line));
+            }
+            '"' | '\'' => {
+                let quote = c;
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, c)) if c == quote => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => s.push('\n'),
+                            Some((_, 't')) => s.push('\t'),
+                            Some((_, 'r')) => s.push('\r'),
+                            Some((_, '0')) => s.push('\0'),
+                            Some((_, c @ ('\\' | '"' | '\''))) => s.push(c),
+                            Some((_, c)) => return Err(error(format!("invalid escape \\{}", c))),
+                            None => return Err(error("unterminated literal".to_string())),
+                        },
+                        Some((_, '\n')) | None => {
+                            return Err(error("unterminated literal".to_string()))
+                        }
+                        Some((_, c)) => s.push(c),
+                    }
+                }
+                if quote == '"' {
+                    tokens.push((Token::Str(s), line));
+                } else {
+                    let mut chars = s.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => tokens.push((Token::Number(c as i32), line)),
+                        _ => {
+                            return Err(error(
+                                "character literal must be one character".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => {
+                let rest = &source[i..];
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|symbol| rest.starts_with(**symbol))
+                    .ok_or_else(|| error(format!("unexpected character {:?}", c)))?;
+                for _ in 0..symbol.len() {
+                    chars.next();
+                }
+                tokens.push((Token::Symbol(symbol), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i32),
+    Str(String),
+    // The address of a variable.
+    Var(String, usize),
+    Fetch(Box<Expr>),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Instruction, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>, usize),
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Var(Vec<(String, usize)>),
+    Assign(Expr, Expr),
+    Expr(Expr),
+    Loop(Block),
+    Until(Expr),
+    If(Expr, Block, Block),
+    Return(Option<Expr>),
+}
+
+// Statements with the line they start on.
+type Block = Vec<(Stmt, usize)>;
+
+#[derive(Debug, Clone)]
+struct FunctionDecl {
+    name: String,
+    params: Vec<String>,
+    body: Block,
+    line: usize,
+    end_line: usize,
+}
+
+// Code for a frame with the source line of every word.
+#[derive(Debug, Default)]
+struct Code {
+    data: Vec<FrameData>,
+    lines: Vec<usize>,
+    line: usize,
+}
+
+impl Code {
+    fn word(&mut self, data: FrameData) {
+        // Nested frames carry their own lines, tapes have no line for the frame itself.
+        let line = match data {
+            FrameData::Frame(_) => 0,
+            _ => self.line,
+        };
+        self.data.push(data);
+        self.lines.push(line);
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.word(FrameData::Instruction(instruction, vec![]));
+    }
+
+    fn push(&mut self, value: i32) {
+        self.emit(Instruction::push());
+        self.word(FrameData::from(value));
+    }
+}
+
+type Operator = (&'static str, fn() -> Instruction);
+
+// Binary operators from loosest to tightest binding.
+const PRECEDENCE: [&[Operator]; 8] = [
+    &[("|", Instruction::or)],
+    &[("^", Instruction::xor)],
+    &[("&", Instruction::and)],
+    &[("==", Instruction::eq), ("!=", Instruction::neq)],
+    &[
+        ("<", Instruction::lt),
+        ("<=", Instruction::leq),
+        (">", Instruction::gt),
+        (">=", Instruction::geq),
+    ],
+    &[("<<", Instruction::lshift), (">>", Instruction::rshift)],
+    &[("+", Instruction::add), ("-", Instruction::sub)],
+    &[
+        ("*", Instruction::mul),
+        ("/", Instruction::div),
+        ("%", Instruction::mod_),
+    ],
+];
+
+#[derive(Debug)]
+pub struct Compiler {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    function_ids: HashMap<String, (usize, usize)>,
+    strings: HashMap<String, usize>,
+    heap: Vec<(usize, i32)>,
+    heap_size: usize,
+    source_name: Option<String>,
+}
+
+impl Compiler {
+    pub fn new(source: &str) -> Result<Self, CompileError> {
+        Ok(Self {
+            tokens: tokenize(source)?,
+            pos: 0,
+            function_ids: HashMap::new(),
+            strings: HashMap::new(),
+            heap: vec![(65535, 0)],
+            heap_size: 0,
+            source_name: None,
+        })
+    }
+
+    // The file name recorded in the debug info.
+    pub fn source_name(mut self, name: String) -> Self {
+        self.source_name = Some(name);
+        self
+    }
+
+    pub fn compile(mut self) -> Result<Program, CompileError> {
+        let mut declarations = Vec::new();
+        while self.peek().is_some() {
+            declarations.push(self.function_decl()?);
+        }
+        for (id, decl) in declarations.iter().enumerate() {
+            let previous = self
+                .function_ids
+                .insert(decl.name.clone(), (id, decl.params.len()));
+            if previous.is_some() {
+                return Err(CompileError {
+                    line: decl.line,
+                    message: format!("function {} declared twice", decl.name),
+                });
+            }
+        }
+        let entry_point = match self.function_ids.get("init") {
+            Some((id, _)) => *id,
+            None => {
+                return Err(CompileError {
+                    line: 0,
+                    message: "no init function".to_string(),
+                })
+            }
+        };
+        let mut functions = Vec::new();
+        for (id, decl) in declarations.iter().enumerate() {
+            functions.push(self.function(id, decl)?);
+        }
+        let mut strings: Vec<(usize, String)> = self
+            .strings
+            .iter()
+            .map(|(s, address)| (*address, format!("{:?}", s)))
+            .collect();
+        strings.sort();
+        let mut builder = Program::builder()
+            .entry_point(entry_point)
+            .heap_size(self.heap_size)
+            .heap(self.heap)
+            .debug(DebugInfo {
+                source: self.source_name,
+                strings,
+            });
+        for function in functions {
+            builder = builder.function(function);
+        }
+        Ok(builder.build())
+    }
+
+    // Parsing
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map(|(_, line)| *line)
+            .unwrap_or(0)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, CompileError> {
+        Err(CompileError {
+            line: self.line(),
+            message,
+        })
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(s)) if s == keyword)
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), CompileError> {
+        if self.is_symbol(symbol) {
+            self.pos += 1;
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => self.error(format!("expected {} but found {}", symbol, token)),
+            None => self.error(format!("expected {} at end of input", symbol)),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), CompileError> {
+        if self.is_keyword(keyword) {
+            self.pos += 1;
+            return Ok(());
+        }
+        self.expect_symbol(keyword)
+    }
+
+    fn ident(&mut self) -> Result<String, CompileError> {
+        match self.peek().cloned() {
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(name)
+            }
+            Some(token) => self.error(format!("expected a name but found {}", token)),
+            None => self.error("expected a name at end of input".to_string()),
+        }
+    }
+
+    fn function_decl(&mut self) -> Result<FunctionDecl, CompileError> {
+        let line = self.line();
+        self.expect_keyword("fun")?;
+        let name = self.ident()?;
+        self.expect_symbol("(")?;
+        let mut params = Vec::new();
+        while !self.is_symbol(")") {
+            if !params.is_empty() {
+                self.expect_symbol(",")?;
+            }
+            params.push(self.ident()?);
+        }
+        self.expect_symbol(")")?;
+        let body = self.block()?;
+        let end_line = self.tokens[self.pos - 1].1;
+        Ok(FunctionDecl {
+            name,
+            params,
+            body,
+            line,
+            end_line,
+        })
+    }
+
+    fn block(&mut self) -> Result<Block, CompileError> {
+        self.expect_symbol("{")?;
+        let mut statements = Vec::new();
+        while !self.is_symbol("}") {
+            if self.peek().is_none() {
+                return self.error("expected } at end of input".to_string());
+            }
+            let line = self.line();
+            statements.push((self.statement()?, line));
+        }
+        self.expect_symbol("}")?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let keyword = match self.peek() {
+            Some(Token::Ident(keyword)) => keyword.clone(),
+            _ => String::new(),
+        };
+        match keyword.as_str() {
+            "var" => {
+                self.pos += 1;
+                let mut names = vec![(self.ident()?, self.line())];
+                while self.is_symbol(",") {
+                    self.pos += 1;
+                    names.push((self.ident()?, self.line()));
+                }
+                Ok(Stmt::Var(names))
+            }
+            "loop" => {
+                self.pos += 1;
+                Ok(Stmt::Loop(self.block()?))
+            }
+            "until" => {
+                self.pos += 1;
+                Ok(Stmt::Until(self.expr()?))
+            }
+            "if" => {
+                self.pos += 1;
+                let condition = self.expr()?;
+                let then = self.block()?;
+                let otherwise = if self.is_keyword("else") {
+                    self.pos += 1;
+                    if self.is_keyword("if") {
+                        let line = self.line();
+                        vec![(self.statement()?, line)]
+                    } else {
+                        self.block()?
+                    }
+                } else {
+                    vec![]
+                };
+                Ok(Stmt::If(condition, then, otherwise))
+            }
+            "return" => {
+                self.pos += 1;
+                if self.is_symbol("}") {
+                    Ok(Stmt::Return(None))
+                } else {
+                    Ok(Stmt::Return(Some(self.expr()?)))
+                }
+            }
+            _ => {
+                let expr = self.expr()?;
+                if self.is_symbol(":") {
+                    self.pos += 1;
+                    Ok(Stmt::Assign(expr, self.expr()?))
+                } else {
+                    Ok(Stmt::Expr(expr))
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, CompileError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, CompileError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        'operators: loop {
+            for (symbol, instruction) in PRECEDENCE[level] {
+                if self.is_symbol(symbol) {
+                    self.pos += 1;
+                    let right = self.binary(level + 1)?;
+                    left = Expr::Binary(instruction(), Box::new(left), Box::new(right));
+                    continue 'operators;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        let constructor = match self.peek() {
+            Some(Token::Symbol("-")) => Expr::Negate,
+            Some(Token::Symbol("!")) => Expr::Not,
+            Some(Token::Symbol(".")) => Expr::Fetch,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(constructor(Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, CompileError> {
+        let line = self.line();
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            }
+            Some(Token::Str(s)) => {
+                self.pos += 1;
+                Ok(Expr::Str(s))
+            }
+            Some(Token::Symbol("(")) => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                // A parenthesis on the next line starts a new statement rather than a call.
+                if !self.is_symbol("(") || self.line() != line {
+                    return Ok(Expr::Var(name, line));
+                }
+                self.pos += 1;
+                let mut args = Vec::new();
+                while !self.is_symbol(")") {
+                    if !args.is_empty() {
+                        self.expect_symbol(",")?;
+                    }
+                    args.push(self.expr()?);
+                }
+                self.expect_symbol(")")?;
+                Ok(Expr::Call(name, args, line))
+            }
+            Some(token) => self.error(format!("unexpected {}", token)),
+            None => self.error("unexpected end of input".to_string()),
+        }
+    }
+
+    // Code generation
+
+    fn function(&mut self, id: usize, decl: &FunctionDecl) -> Result<Function, CompileError> {
+        let mut declared = Vec::new();
+        collect_locals(&decl.body, &mut declared);
+        let mut used = Vec::new();
+        collect_references(&decl.body, &mut used);
+
+        let mut offsets = HashMap::new();
+        let mut locals = 0;
+        for (name, line) in &declared {
+            if decl.params.contains(name) || offsets.contains_key(name.as_str()) {
+                return Err(CompileError {
+                    line: *line,
+                    message: format!("variable {} declared twice", name),
+                });
+            }
+            if used.contains(name) {
+                locals += 1;
+                offsets.insert(name.as_str(), locals as i32);
+            } else {
+                // Still reserve the name so duplicates are reported.
+                offsets.insert(name.as_str(), 0);
+            }
+        }
+        offsets.retain(|_, offset| *offset != 0);
+        for (i, name) in decl.params.iter().enumerate() {
+            if offsets.contains_key(name.as_str()) {
+                return Err(CompileError {
+                    line: decl.line,
+                    message: format!("parameter {} declared twice", name),
+                });
+            }
+            offsets.insert(name.as_str(), (locals + decl.params.len() - i) as i32);
+        }
+
+        let mut code = Code::default();
+        self.statements(&decl.body, &offsets, &mut code)?;
+        // Functions that fall off the end return 0.
+        code.line = decl.end_line;
+        code.push(0);
+
+        let frame = Frame::builder()
+            .id(id)
+            .name(format!("{}-frame", decl.name))
+            .lines(code.lines);
+        let frame = code
+            .data
+            .into_iter()
+            .fold(frame, |frame, data| frame.data(data));
+        let mut variables: Vec<(String, i32)> = offsets
+            .iter()
+            .map(|(name, offset)| (name.to_string(), *offset))
+            .collect();
+        variables.sort_by_key(|(_, offset)| *offset);
+        Ok(Function::builder()
+            .id(id)
+            .name(decl.name.clone())
+            .args(decl.params.len())
+            .locals(locals)
+            .frame(frame.build())
+            .debug(FunctionDebugInfo { variables })
+            .build())
+    }
+
+    fn statements(
+        &mut self,
+        statements: &[(Stmt, usize)],
+        offsets: &HashMap<&str, i32>,
+        code: &mut Code,
+    ) -> Result<(), CompileError> {
+        for (statement, line) in statements {
+            code.line = *line;
+            match statement {
+                Stmt::Var(_) => {}
+                Stmt::Assign(target, value) => {
+                    self.expression(target, offsets, code)?;
+                    self.expression(value, offsets, code)?;
+                    code.emit(Instruction::store());
+                }
+                Stmt::Expr(expr) => {
+                    self.expression(expr, offsets, code)?;
+                    code.emit(Instruction::pop());
+                }
+                Stmt::Loop(body) => {
+                    code.emit(Instruction::loop_());
+                    code.word(FrameData::Frame(self.frame(body, offsets)?));
+                }
+                Stmt::Until(condition) => {
+                    self.expression(condition, offsets, code)?;
+                    code.emit(Instruction::break_());
+                }
+                Stmt::If(condition, then, otherwise) => {
+                    self.expression(condition, offsets, code)?;
+                    code.emit(Instruction::if_());
+                    code.word(FrameData::Frame(self.frame(then, offsets)?));
+                    code.word(FrameData::Frame(self.frame(otherwise, offsets)?));
+                }
+                Stmt::Return(value) => {
+                    match value {
+                        Some(value) => self.expression(value, offsets, code)?,
+                        None => code.push(0),
+                    }
+                    code.emit(Instruction::return_());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn frame(
+        &mut self,
+        statements: &[(Stmt, usize)],
+        offsets: &HashMap<&str, i32>,
+    ) -> Result<Frame, CompileError> {
+        let mut code = Code::default();
+        self.statements(statements, offsets, &mut code)?;
+        let mut frame: Frame = code.data.into_iter().collect();
+        frame.lines = code.lines;
+        Ok(frame)
+    }
+
+    fn expression(
+        &mut self,
+        expr: &Expr,
+        offsets: &HashMap<&str, i32>,
+        code: &mut Code,
+    ) -> Result<(), CompileError> {
+        match expr {
+            Expr::Number(n) => code.push(*n),
+            Expr::Str(s) => {
+                let address = self.string(s);
+                code.push(address as i32);
+            }
+            Expr::Var(name, line) => match offsets.get(name.as_str()) {
+                Some(offset) => {
+                    code.push(*offset);
+                    code.emit(Instruction::fp_plus());
+                }
+                None => {
+                    return Err(CompileError {
+                        line: *line,
+                        message: format!("unknown variable {}", name),
+                    })
+                }
+            },
+            Expr::Fetch(address) => {
+                self.expression(address, offsets, code)?;
+                code.emit(Instruction::fetch());
+            }
+            Expr::Negate(value) => {
+                code.push(0);
+                self.expression(value, offsets, code)?;
+                code.emit(Instruction::sub());
+            }
+            Expr::Not(value) => {
+                self.expression(value, offsets, code)?;
+                code.emit(Instruction::not());
+            }
+            Expr::Binary(instruction, left, right) => {
+                self.expression(left, offsets, code)?;
+                self.expression(right, offsets, code)?;
+                code.word(FrameData::Instruction(instruction.clone(), vec![]));
+            }
+            Expr::Call(name, args, line) => {
+                let (id, arity) =
+                    match (self.function_ids.get(name), NativeFunction::from_name(name)) {
+                        (Some((id, arity)), _) => (*id as i32, *arity),
+                        (None, Some(native)) => (native.id(), native.args() as usize),
+                        (None, None) => {
+                            return Err(CompileError {
+                                line: *line,
+                                message: format!("unknown function {}", name),
+                            })
+                        }
+                    };
+                // Natives pop a fixed number of words, so a wrong count corrupts the stack.
+                if arity != args.len() {
+                    return Err(CompileError {
+                        line: *line,
+                        message: format!(
+                            "{} takes {} arguments but {} were given",
+                            name,
+                            arity,
+                            args.len()
+                        ),
+                    });
+                }
+                for arg in args {
+                    self.expression(arg, offsets, code)?;
+                }
+                code.emit(Instruction::call());
+                code.word(FrameData::from(id));
+            }
+        }
+        Ok(())
+    }
+
+    // Places a zero terminated string on the heap, reusing identical literals.
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(address) = self.strings.get(s) {
+            return *address;
+        }
+        let address = self.heap_size;
+        for (i, c) in s.chars().chain(std::iter::once('\0')).enumerate() {
+            self.heap.push((address + i, c as i32));
+        }
+        self.heap_size += s.chars().count() + 1;
+        self.strings.insert(s.to_string(), address);
+        address
+    }
+}
+
+fn collect_locals(statements: &[(Stmt, usize)], declared: &mut Vec<(String, usize)>) {
+    for (statement, _) in statements {
+        match statement {
+            Stmt::Var(names) => declared.extend(names.iter().cloned()),
+            Stmt::Loop(body) => collect_locals(body, declared),
+            Stmt::If(_, then, otherwise) => {
+                collect_locals(then, declared);
+                collect_locals(otherwise, declared);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn collect_references(statements: &[(Stmt, usize)], used: &mut Vec<String>) {
+    fn expr_references(expr: &Expr, used: &mut Vec<String>) {
+        match expr {
+            Expr::Number(_) | Expr::Str(_) => {}
+            Expr::Var(name, _) => used.push(name.clone()),
+            Expr::Fetch(value) | Expr::Negate(value) | Expr::Not(value) => {
+                expr_references(value, used)
+            }
+            Expr::Binary(_, left, right) => {
+                expr_references(left, used);
+                expr_references(right, used);
+            }
+            Expr::Call(_, args, _) => args.iter().for_each(|arg| expr_references(arg, used)),
+        }
+    }
+    for (statement, _) in statements {
+        match statement {
+            Stmt::Var(_) | Stmt::Return(None) => {}
+            Stmt::Assign(target, value) => {
+                expr_references(target, used);
+                expr_references(value, used);
+            }
+            Stmt::Expr(expr) | Stmt::Until(expr) | Stmt::Return(Some(expr)) => {
+                expr_references(expr, used)
+            }
+            Stmt::Loop(body) => collect_references(body, used),
+            Stmt::If(condition, then, otherwise) => {
+                expr_references(condition, used);
+                collect_references(then, used);
+                collect_references(otherwise, used);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateHolder;
+    use crate::tvm::Tvm;
+
+    fn assert_matches_tape(source: &str, tape: &str) {
+        let mut compiled = Program::from_source_file(source.to_string()).unwrap();
+        compiled.strip_debug_info();
+        let expected = Program::from_file(tape.to_string()).unwrap();
+        assert_eq!(compiled.entry_point, expected.entry_point);
+        assert_eq!(compiled.heap_size, expected.heap_size);
+        assert_eq!(compiled.functions, expected.functions);
+        let mut compiled_heap = compiled.heap;
+        let mut expected_heap = expected.heap;
+        compiled_heap.sort();
+        expected_heap.sort();
+        assert_eq!(compiled_heap, expected_heap);
+    }
+
+    fn run(source: &str) -> String {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_source(source).unwrap());
+        tvm.start();
+        while !tvm.is_halted() {
+            tvm.tick();
+        }
+        tvm.stdout
+    }
+
+    #[test]
+    fn test_matches_shipped_tapes() {
+        assert_matches_tape("sq.t", "sq.json");
+        assert_matches_tape("sieve.t", "sieve.json");
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let program = Program::from_source_file("sieve.t".to_string()).unwrap();
+        let debug = program.debug.as_ref().unwrap();
+        assert_eq!(debug.source.as_deref(), Some("sieve.t"));
+        assert_eq!(debug.strings, vec![(0, "\"\\n\"".to_string())]);
+
+        let sieve = &program.functions[0];
+        let variables = &sieve.debug.as_ref().unwrap().variables;
+        assert_eq!(variables[0], ("i".to_string(), 1));
+        assert_eq!(variables[3], ("n".to_string(), 4));
+        assert_eq!(sieve.frame.get_line(), Some(4));
+        assert_eq!(sieve.frame.lines.len(), sieve.frame.data.len());
+        assert!(matches!(&sieve.frame.data[8], FrameData::Frame(then) if then.lines == vec![5; 6]));
+        assert_eq!(sieve.frame.lines.last(), Some(&39));
+    }
+
+    #[test]
+    fn test_hello() {
+        let source = fs::read_to_string("hello.t").unwrap();
+        assert_eq!(run(&source), "10");
+    }
+
+    #[test]
+    fn test_sieve() {
+        let source = fs::read_to_string("sieve.t").unwrap();
+        let stdout = run(&source);
+        assert!(stdout.starts_with("0\n1\n2\n3\n5\n7\n11\n"));
+        assert!(stdout.ends_with("89\n97\n"));
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = r#"
+fun show(v) {
+    iprint(.v)
+}
+
+fun init() {
+    var i
+    i : 0
+    loop {
+        until .i > 3
+        if .i == 2 {
+            show(10 * .i)
+        } else {
+            iprint(.i)
+        }
+        i : .i + 1
+    }
+}
+"#;
+        assert_eq!(run(source), "01203");
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = r#"
+fun add(a, b) {
+    return .a - .b
+}
+
+fun init() {
+    var x
+    x : 2 + 3 * 4
+    iprint(.x)
+    nl()
+    iprint(add(10, 4))
+    nl()
+    iprint(-(1 << 3) | 1)
+    nl()
+    if .x == 14 {
+        sprint("yes")
+    } else {
+        sprint("no")
+    }
+}
+"#;
+        assert_eq!(run(source), "14\n6\n-7\nyes");
+    }
+
+    #[test]
+    fn test_stoptimer() {
+        assert_eq!(run("fun init() {\n    iprint(stoptimer(5))\n}"), "0");
+    }
+
+    #[test]
+    fn test_i2s() {
+        let source = r#"
+fun init() {
+    var buf
+    buf : alloc(8)
+    iprint(i2s(-42, .buf))
+    nl()
+    sprint(.buf)
+}
+"#;
+        assert_eq!(run(source), "3\n-42");
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = Program::from_source("fun init() {\n    iprint(.y)\n}").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown variable y");
+
+        let err = Program::from_source("fun init() {\n    foo()\n}").unwrap_err();
+        assert_eq!(err.message, "unknown function foo");
+
+        let err = Program::from_source("fun f(a) {\n}\nfun init() {\n    f()\n}").unwrap_err();
+        assert_eq!(err.message, "f takes 1 arguments but 0 were given");
+
+        let err = Program::from_source("fun init() {\n    nl(1)\n}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: nl takes 0 arguments but 1 were given"
+        );
+
+        let err = Program::from_source("fun init() {\n    iprint()\n}").unwrap_err();
+        assert_eq!(err.message, "iprint takes 1 arguments but 0 were given");
+
+        let err = Program::from_source("fun init() {\n    stoptimer(1, 2)\n}").unwrap_err();
+        assert_eq!(err.message, "stoptimer takes 1 arguments but 2 were given");
+
+        let err = Program::from_source("fun init() {\n    var x\n    x : \n}").unwrap_err();
+        assert_eq!(err.message, "unexpected }");
+
+        let err = Program::from_source("fun main() {\n}").unwrap_err();
+        assert_eq!(err.message, "no init function");
+    }
+}
@@ -0,0 +1,606 @@
+use crate::call_stack::{CallFrame, Variable};
+use crate::frame::{Frame, FrameData};
+use crate::program::Program;
+use crate::repl::{run_until, Stop};
+use crate::tvm::Tvm;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+// A Debug Adapter Protocol server, so editors can debug Tranquility programs. Messages are JSON
+// objects preceded by a `Content-Length` header, read from one stream and written to another,
+// usually stdin and stdout.
+//
+// There is a single thread. Frames are numbered from the innermost call, and each frame has two
+// variable references, one for its arguments and one for its locals. With debug info, steps
+// and breakpoints work on source lines, otherwise steps are single instructions and only
+// function breakpoints can be set.
+
+const THREAD_ID: i64 = 1;
+
+// How execution is resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+}
+
+pub struct DapServer {
+    pub tvm: Tvm,
+    // The source file stack frames point at, if the program has line info.
+    source: Option<String>,
+    // Source lines with a breakpoint.
+    lines: BTreeSet<usize>,
+    stop_on_entry: bool,
+    seq: i64,
+    // Ticks a single request may run for before pausing.
+    pub max_ticks: usize,
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        DapServer::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> Self {
+        DapServer {
+            // Program input comes from the launch arguments and the debug console, as stdin
+            // carries the protocol.
+            tvm: Tvm {
+                input: Some(String::new()),
+                ..Tvm::default()
+            },
+            source: None,
+            lines: BTreeSet::new(),
+            stop_on_entry: false,
+            seq: 0,
+            max_ticks: 100_000_000,
+        }
+    }
+
+    /// Handles requests from `input` until the client disconnects or closes the stream.
+    pub fn serve(&mut self, mut input: impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        while let Some(request) = read_message(&mut input)? {
+            if !self.handle(&request, out)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a single request, returning false once the client has disconnected.
+    pub fn handle(&mut self, request: &Value, out: &mut impl Write) -> io::Result<bool> {
+        let arguments = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let resume = match command {
+            "continue" => Some(Resume::Continue),
+            "stepIn" => Some(Resume::StepIn),
+            "next" => Some(Resume::Next),
+            "stepOut" => Some(Resume::StepOut),
+            _ => None,
+        };
+        if let Some(resume) = resume {
+            self.respond(request, Ok(json!({ "allThreadsContinued": true })), out)?;
+            return self.resume(resume, out).map(|_| true);
+        }
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+            })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(arguments)),
+            "configurationDone" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(scopes(arguments["frameId"].as_u64().unwrap_or(0))),
+            "variables" => Ok(self.variables(arguments["variablesReference"].as_u64())),
+            "evaluate" => self.evaluate(arguments),
+            // Requests run to completion before the next is read, so there is never anything
+            // running to pause.
+            "pause" => Ok(Value::Null),
+            "disconnect" => {
+                self.respond(request, Ok(Value::Null), out)?;
+                return Ok(false);
+            }
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        self.respond(request, result, out)?;
+        match command {
+            "initialize" => self.event("initialized", Value::Null, out)?,
+            "configurationDone" if self.stop_on_entry => {
+                self.stopped("entry", None, out)?;
+            }
+            "configurationDone" => self.resume(Resume::Continue, out)?,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("launch needs a program")?;
+        let program = Program::from_path(path).map_err(|e| e.to_string())?;
+        program.verify().map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+            errors.join("\n")
+        })?;
+        self.source = if path.ends_with(".t") {
+            Some(path.to_string())
+        } else {
+            program
+                .debug
+                .as_ref()
+                .and_then(|debug| debug.source.as_ref())
+                .map(|source| source_path(path, source))
+        };
+        self.tvm.load(program);
+        self.tvm.reset();
+        if let (Some(input), Some(given)) = (&mut self.tvm.input, arguments["input"].as_str()) {
+            input.push_str(given);
+        }
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(Value::Null)
+    }
+
+    // Replaces the line breakpoints. A line is verified if some code was compiled from it.
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let code_lines = self.code_lines();
+        self.lines.clear();
+        let mut breakpoints = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let verified = code_lines.contains(&line);
+            if verified {
+                self.lines.insert(line);
+            }
+            breakpoints.push(json!({ "verified": verified, "line": line }));
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Value) -> Value {
+        self.tvm.breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let name = breakpoint["name"].as_str().unwrap_or_default();
+            let verified = self.tvm.program.functions.iter().any(|f| f.name == name);
+            if verified {
+                self.tvm.breakpoints.insert(name.to_string());
+            }
+            breakpoints.push(json!({ "verified": verified }));
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&self) -> Value {
+        let frames: Vec<Value> = self
+            .tvm
+            .call_stack()
+            .iter()
+            .enumerate()
+            .map(|(id, call)| self.stack_frame(id, call))
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn stack_frame(&self, id: usize, call: &CallFrame) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": call.function.name,
+            "line": call.line.unwrap_or(0),
+            "column": 0,
+        });
+        if let (Some(source), Some(_)) = (&self.source, call.line) {
+            frame["source"] = json!({ "path": source });
+        }
+        frame
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Value {
+        let calls = self.tvm.call_stack();
+        let variables: &[Variable] = match reference {
+            Some(reference) if reference > 0 => {
+                let id = (reference as usize - 1) / 2;
+                match calls.get(id) {
+                    Some(call) if reference % 2 == 1 => &call.args,
+                    Some(call) => &call.locals,
+                    None => &[],
+                }
+            }
+            _ => &[],
+        };
+        let variables: Vec<Value> = variables
+            .iter()
+            .map(|v| {
+                json!({
+                    "name": v.name,
+                    "value": v.value.to_string(),
+                    "variablesReference": 0,
+                    "memoryReference": v.address.to_string(),
+                })
+            })
+            .collect();
+        json!({ "variables": variables })
+    }
+
+    // Looks up a variable of a frame or a memory cell by address. In the debug console,
+    // `input <line>` gives a line to iread or sread.
+    fn evaluate(&mut self, arguments: &Value) -> Result<Value, String> {
+        let expression = arguments["expression"].as_str().unwrap_or_default().trim();
+        if let Some(line) = expression.strip_prefix("input ") {
+            if let Some(input) = &mut self.tvm.input {
+                input.push_str(line);
+                input.push('\n');
+            }
+            return Ok(json!({ "result": "", "variablesReference": 0 }));
+        }
+        let value = match expression.parse::<usize>() {
+            Ok(address) if address < self.tvm.memory.len() => self.tvm.memory[address],
+            _ => {
+                let id = arguments["frameId"].as_u64().unwrap_or(0) as usize;
+                let calls = self.tvm.call_stack();
+                calls
+                    .get(id)
+                    .and_then(|call| {
+                        call.args
+                            .iter()
+                            .chain(&call.locals)
+                            .find(|v| v.name == expression)
+                    })
+                    .ok_or_else(|| format!("no address or variable {}", expression))?
+                    .value
+            }
+        };
+        Ok(json!({ "result": value.to_string(), "variablesReference": 0 }))
+    }
+
+    // Runs the machine and reports where it stopped.
+    fn resume(&mut self, resume: Resume, out: &mut impl Write) -> io::Result<()> {
+        let start_line = line(&self.tvm);
+        let lines = &self.lines;
+        let by_line = self.tvm.program.has_debug_info();
+        // Whether execution has moved on to another line, or another call of the same line.
+        let new_line = |tvm: &Tvm, depth: usize| {
+            !by_line
+                || line(tvm).is_some() && (line(tvm) != start_line || tvm.call_depth() != depth)
+        };
+        let at_breakpoint = |tvm: &Tvm, depth: usize| {
+            line(tvm).is_some_and(|line| lines.contains(&line)) && new_line(tvm, depth)
+        };
+        let stop = run_until(
+            &mut self.tvm,
+            &mut BTreeMap::new(),
+            self.max_ticks,
+            |tvm, depth| {
+                at_breakpoint(tvm, depth)
+                    || match resume {
+                        Resume::Continue => false,
+                        Resume::StepIn => new_line(tvm, depth),
+                        Resume::Next => tvm.call_depth() <= depth && new_line(tvm, depth),
+                        Resume::StepOut => tvm.call_depth() < depth,
+                    }
+            },
+        );
+        match stop {
+            Stop::Halted => {
+                self.output(out)?;
+                self.event("exited", json!({ "exitCode": 0 }), out)?;
+                self.event("terminated", Value::Null, out)
+            }
+            Stop::Stepped
+                if resume == Resume::Continue
+                    || line(&self.tvm).is_some_and(|line| self.lines.contains(&line)) =>
+            {
+                self.stopped("breakpoint", None, out)
+            }
+            Stop::Stepped | Stop::Watch { .. } => self.stopped("step", None, out),
+            Stop::Breakpoint(_) => self.stopped("function breakpoint", None, out),
+            Stop::Input => self.stopped("pause", Some(stop.to_string()), out),
+            Stop::TickLimit => self.stopped("pause", Some(stop.to_string()), out),
+            Stop::Fault(message) => self.stopped("exception", Some(message), out),
+        }
+    }
+
+    // Sends any new program output, then the stopped event.
+    fn stopped(
+        &mut self,
+        reason: &str,
+        text: Option<String>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        self.output(out)?;
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body, out)
+    }
+
+    fn output(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if self.tvm.stdout.is_empty() {
+            return Ok(());
+        }
+        let output = std::mem::take(&mut self.tvm.stdout);
+        self.event(
+            "output",
+            json!({ "category": "stdout", "output": output }),
+            out,
+        )
+    }
+
+    fn respond(
+        &mut self,
+        request: &Value,
+        result: Result<Value, String>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        self.seq += 1;
+        let mut response = json!({
+            "seq": self.seq,
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        write_message(out, &response)
+    }
+
+    fn event(&mut self, event: &str, body: Value, out: &mut impl Write) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = json!({ "seq": self.seq, "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        write_message(out, &message)
+    }
+
+    // Every source line some code was compiled from.
+    fn code_lines(&self) -> BTreeSet<usize> {
+        fn collect(frame: &Frame, lines: &mut BTreeSet<usize>) {
+            lines.extend(frame.lines.iter().filter(|line| **line > 0));
+            for data in &frame.data {
+                if let FrameData::Frame(nested) = data {
+                    collect(nested, lines);
+                }
+            }
+        }
+        let mut lines = BTreeSet::new();
+        for function in &self.tvm.program.functions {
+            collect(&function.frame, &mut lines);
+        }
+        lines
+    }
+}
+
+fn scopes(frame_id: u64) -> Value {
+    json!({ "scopes": [
+        { "name": "Arguments", "variablesReference": frame_id * 2 + 1, "expensive": false },
+        { "name": "Locals", "variablesReference": frame_id * 2 + 2, "expensive": false },
+    ] })
+}
+
+fn line(tvm: &Tvm) -> Option<usize> {
+    tvm.state.get_code_position()?.line
+}
+
+// The source named in a tape's debug info, found next to the tape if it is relative.
+fn source_path(tape: &str, source: &str) -> String {
+    match Path::new(tape).parent() {
+        Some(dir) if Path::new(source).is_relative() => dir.join(source).display().to_string(),
+        _ => source.to_string(),
+    }
+}
+
+/// Reads a message, or None at the end of the stream.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    // Runs a session of requests, returning every message sent back.
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut out = Vec::new();
+        DapServer::new().serve(input.as_slice(), &mut out).unwrap();
+        let mut messages = Vec::new();
+        let mut out = out.as_slice();
+        while let Some(message) = read_message(&mut out).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn request(command: &str, arguments: Value) -> Value {
+        json!({ "command": command, "arguments": arguments })
+    }
+
+    fn find<'a>(messages: &'a [Value], kind: &str, name: &str) -> Vec<&'a Value> {
+        let key = if kind == "event" { "event" } else { "command" };
+        messages
+            .iter()
+            .filter(|m| m["type"] == kind && m[key] == name)
+            .collect()
+    }
+
+    #[test]
+    fn test_line_breakpoint_and_stepping() {
+        let messages = session(&[
+            request("initialize", json!({ "adapterID": "tvm" })),
+            request("launch", json!({ "program": "sq.t" })),
+            request(
+                "setBreakpoints",
+                json!({ "source": { "path": "sq.t" }, "breakpoints": [{ "line": 3 }, { "line": 6 }] }),
+            ),
+            request("configurationDone", json!({})),
+            request("stackTrace", json!({ "threadId": 1 })),
+            request("scopes", json!({ "frameId": 0 })),
+            request("variables", json!({ "variablesReference": 1 })),
+            request("evaluate", json!({ "expression": "i", "frameId": 1 })),
+            request("stepOut", json!({ "threadId": 1 })),
+            request("stackTrace", json!({ "threadId": 1 })),
+            request("continue", json!({ "threadId": 1 })),
+            request("variables", json!({ "variablesReference": 1 })),
+            request("disconnect", json!({})),
+        ]);
+        assert!(messages.iter().all(|m| m["success"] != false));
+        assert_eq!(find(&messages, "event", "initialized").len(), 1);
+
+        let breakpoints = &find(&messages, "response", "setBreakpoints")[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0], json!({ "verified": true, "line": 3 }));
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        let stopped = find(&messages, "event", "stopped");
+        assert_eq!(stopped.len(), 3);
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        assert_eq!(stopped[1]["body"]["reason"], "step");
+        assert_eq!(stopped[2]["body"]["reason"], "breakpoint");
+        let output = find(&messages, "event", "output");
+        assert_eq!(
+            output[0]["body"]["output"],
+            "Table of squares:\n1 squared equals "
+        );
+
+        let traces = find(&messages, "response", "stackTrace");
+        let frames = &traces[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "sq");
+        assert_eq!(frames[0]["line"], 3);
+        assert_eq!(frames[0]["source"]["path"], "sq.t");
+        assert_eq!(frames[1]["name"], "init");
+        assert_eq!(frames[1]["line"], 15);
+        assert_eq!(traces[1]["body"]["stackFrames"][0]["name"], "init");
+
+        let variables = find(&messages, "response", "variables");
+        assert_eq!(variables[0]["body"]["variables"][0]["name"], "n");
+        assert_eq!(variables[0]["body"]["variables"][0]["value"], "1");
+        assert_eq!(variables[1]["body"]["variables"][0]["value"], "2");
+        let scopes = &find(&messages, "response", "scopes")[0]["body"]["scopes"];
+        assert_eq!(scopes[1]["variablesReference"], 2);
+        assert_eq!(
+            find(&messages, "response", "evaluate")[0]["body"]["result"],
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_function_breakpoints_and_exit() {
+        let messages = session(&[
+            request("initialize", json!({})),
+            request(
+                "launch",
+                json!({ "program": "sq.json", "stopOnEntry": true }),
+            ),
+            request(
+                "setFunctionBreakpoints",
+                json!({ "breakpoints": [{ "name": "sq" }, { "name": "cube" }] }),
+            ),
+            request("configurationDone", json!({})),
+            request("continue", json!({ "threadId": 1 })),
+            request("stackTrace", json!({ "threadId": 1 })),
+            request("setFunctionBreakpoints", json!({ "breakpoints": [] })),
+            request("next", json!({ "threadId": 1 })),
+            request("continue", json!({ "threadId": 1 })),
+            request("frobnicate", json!({})),
+        ]);
+        let breakpoints =
+            &find(&messages, "response", "setFunctionBreakpoints")[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        let stopped = find(&messages, "event", "stopped");
+        let reasons: Vec<&Value> = stopped.iter().map(|s| &s["body"]["reason"]).collect();
+        assert_eq!(reasons, ["entry", "function breakpoint", "step"]);
+        // Without debug info there is no source to point at.
+        let frames = &find(&messages, "response", "stackTrace")[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "sq");
+        assert!(frames[0].get("source").is_none());
+
+        assert_eq!(find(&messages, "event", "exited").len(), 1);
+        assert_eq!(find(&messages, "event", "terminated").len(), 1);
+        let output: String = find(&messages, "event", "output")
+            .iter()
+            .map(|o| o["body"]["output"].as_str().unwrap())
+            .collect();
+        assert_eq!(output, std::fs::read_to_string("sq.stdout").unwrap());
+        let unsupported = find(&messages, "response", "frobnicate")[0];
+        assert_eq!(unsupported["success"], false);
+    }
+
+    #[test]
+    fn test_input_from_the_console() {
+        let path = temp_dir("dap-input").join("double.t");
+        std::fs::write(&path, "fun init() {\n    iprint(iread(-1) * 2)\n}").unwrap();
+        let messages = session(&[
+            request("launch", json!({ "program": path.display().to_string() })),
+            request("configurationDone", json!({})),
+            request(
+                "evaluate",
+                json!({ "expression": "input 21", "context": "repl" }),
+            ),
+            request("continue", json!({ "threadId": 1 })),
+        ]);
+        let stopped = find(&messages, "event", "stopped")[0];
+        assert_eq!(stopped["body"]["reason"], "pause");
+        assert_eq!(
+            stopped["body"]["text"],
+            "waiting for input, give it with `input`"
+        );
+        assert_eq!(
+            find(&messages, "event", "output")[0]["body"]["output"],
+            "42"
+        );
+        assert_eq!(find(&messages, "event", "exited").len(), 1);
+    }
+}
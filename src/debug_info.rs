@@ -0,0 +1,314 @@
+use crate::frame::{Frame, FrameData};
+use crate::program::Program;
+use crate::program_parser::{array, error, unsigned, word, ParserError};
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+
+// Optional debug section of a tape. Tapes without it load as before.
+//
+// The program section is an object following the functions:
+//
+//     {"source": "sq.t", "strings": [[0, "\"Table of squares:\\n\""], ...]}
+//
+// and each function may carry a sixth element:
+//
+//     [0, "sq", 1, 0, [1, 1, 9, ...], {"variables": [["n", 1]], "lines": [2, 2, 2, ...]}]
+//
+// where `lines` mirrors the shape of the frame, giving the source line of every word and a
+// nested array for every nested frame.
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DebugInfo {
+    pub source: Option<String>,
+    // Names of the string constants placed on the heap, by address.
+    pub strings: Vec<(usize, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FunctionDebugInfo {
+    // Parameter and local names with their offset from the frame pointer.
+    pub variables: Vec<(String, i32)>,
+}
+
+impl DebugInfo {
+    // Reads the program section found at `path`.
+    pub fn from_json(json: &Value, path: &str) -> Result<DebugInfo, ParserError> {
+        let source = match &json["source"] {
+            Value::Null => None,
+            source => Some(string(source, &format!("{}.source", path))?.to_string()),
+        };
+        let mut strings = Vec::new();
+        for (i, entry) in optional_array(&json["strings"], &format!("{}.strings", path))?
+            .iter()
+            .enumerate()
+        {
+            let path = format!("{}.strings[{}]", path, i);
+            match array(entry, &path)?.as_slice() {
+                [address, name] => strings.push((
+                    unsigned(address, &format!("{}[0]", path))?,
+                    string(name, &format!("{}[1]", path))?.to_string(),
+                )),
+                _ => return Err(error(&path, "expected [address, name]")),
+            }
+        }
+        Ok(DebugInfo { source, strings })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        if let Some(source) = &self.source {
+            object.insert("source".to_string(), json!(source));
+        }
+        object.insert("strings".to_string(), json!(self.strings));
+        Value::Object(object)
+    }
+
+    pub fn get_string_name(&self, address: usize) -> Option<&str> {
+        self.strings
+            .iter()
+            .find(|(a, _)| *a == address)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+impl FunctionDebugInfo {
+    // Reads the function section found at `path`, filling in the line table of `frame`.
+    pub fn from_json(
+        json: &Value,
+        path: &str,
+        frame: &mut Frame,
+    ) -> Result<FunctionDebugInfo, ParserError> {
+        if !json["lines"].is_null() {
+            lines_from_json(&json["lines"], &format!("{}.lines", path), frame)?;
+        }
+        let mut variables = Vec::new();
+        for (i, entry) in optional_array(&json["variables"], &format!("{}.variables", path))?
+            .iter()
+            .enumerate()
+        {
+            let path = format!("{}.variables[{}]", path, i);
+            match array(entry, &path)?.as_slice() {
+                [name, offset] => variables.push((
+                    string(name, &format!("{}[0]", path))?.to_string(),
+                    word(offset, &format!("{}[1]", path))?,
+                )),
+                _ => return Err(error(&path, "expected [name, offset]")),
+            }
+        }
+        Ok(FunctionDebugInfo { variables })
+    }
+
+    // The function section, taking the line table from `frame`.
+    pub fn to_json(&self, frame: &Frame) -> Value {
+        let mut object = Map::new();
+        object.insert("variables".to_string(), json!(self.variables));
+        if !frame.lines.is_empty() {
+            object.insert("lines".to_string(), lines_to_json(frame));
+        }
+        Value::Object(object)
+    }
+
+    pub fn get_variable_name(&self, offset: i32) -> Option<&str> {
+        self.variables
+            .iter()
+            .find(|(_, o)| *o == offset)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn get_variable_offset(&self, name: &str) -> Option<i32> {
+        self.variables
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+// Reads a line table, which must have the shape of `frame`: a line for every word and the
+// table of every nested frame.
+fn lines_from_json(json: &Value, path: &str, frame: &mut Frame) -> Result<(), ParserError> {
+    let lines = array(json, path)?;
+    if lines.len() != frame.data.len() {
+        return Err(error(
+            path,
+            &format!(
+                "expected {} entries to match the frame, found {}",
+                frame.data.len(),
+                lines.len()
+            ),
+        ));
+    }
+    let mut table = Vec::with_capacity(lines.len());
+    for (i, (data, line)) in frame.data.iter_mut().zip(lines).enumerate() {
+        let path = format!("{}[{}]", path, i);
+        match data {
+            FrameData::Frame(nested) => {
+                if !line.is_array() {
+                    return Err(error(&path, "expected the lines of a nested frame"));
+                }
+                lines_from_json(line, &path, nested)?;
+                table.push(0);
+            }
+            _ => table.push(unsigned(line, &path)?),
+        }
+    }
+    frame.lines = table;
+    Ok(())
+}
+
+// Absent arrays read as empty.
+fn optional_array<'v>(value: &'v Value, path: &str) -> Result<&'v [Value], ParserError> {
+    match value {
+        Value::Null => Ok(&[]),
+        value => Ok(array(value, path)?),
+    }
+}
+
+fn string<'v>(value: &'v Value, path: &str) -> Result<&'v str, ParserError> {
+    value
+        .as_str()
+        .ok_or_else(|| error(path, "expected a string"))
+}
+
+fn lines_to_json(frame: &Frame) -> Value {
+    Value::Array(
+        frame
+            .data
+            .iter()
+            .enumerate()
+            .map(|(i, data)| match data {
+                FrameData::Frame(nested) => lines_to_json(nested),
+                _ => json!(frame.lines.get(i).copied().unwrap_or(0)),
+            })
+            .collect(),
+    )
+}
+
+impl Frame {
+    // The source line of the word at the current PC, if known.
+    pub fn get_line(&self) -> Option<usize> {
+        let pc = self.pc.min(self.lines.len().checked_sub(1)?);
+        match self.lines[pc] {
+            0 => None,
+            line => Some(line),
+        }
+    }
+
+    fn strip_lines(&mut self) {
+        self.lines.clear();
+        for data in &mut self.data {
+            if let FrameData::Frame(nested) = data {
+                nested.strip_lines();
+            }
+        }
+    }
+}
+
+impl Program {
+    pub fn has_debug_info(&self) -> bool {
+        self.debug.is_some()
+    }
+
+    pub fn strip_debug_info(&mut self) {
+        self.debug = None;
+        for function in &mut self.functions {
+            let function = Arc::make_mut(function);
+            function.debug = None;
+            function.frame.strip_lines();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_loads_without_debug_info() {
+        let program = Program::from_file("sq.json".to_string()).unwrap();
+        assert!(!program.has_debug_info());
+        assert!(program.functions.iter().all(|f| f.debug.is_none()));
+        assert!(program.functions[0].frame.lines.is_empty());
+        assert_eq!(program.functions[0].frame.get_line(), None);
+    }
+
+    #[test]
+    fn test_function_debug_info() {
+        let mut frame: Frame = vec![
+            FrameData::from(5),
+            FrameData::Frame(
+                vec![FrameData::from(1), FrameData::from(3)]
+                    .into_iter()
+                    .collect(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let debug = FunctionDebugInfo::from_json(
+            &json!({"variables": [["i", 1], ["n", 2]], "lines": [4, [5, 5]]}),
+            "functions[0].debug",
+            &mut frame,
+        )
+        .unwrap();
+        assert_eq!(debug.get_variable_name(2), Some("n"));
+        assert_eq!(debug.get_variable_offset("i"), Some(1));
+        assert_eq!(frame.lines, vec![4, 0]);
+        assert!(matches!(&frame.data[1], FrameData::Frame(nested) if nested.lines == vec![5, 5]));
+        assert_eq!(frame.get_line(), Some(4));
+    }
+
+    #[test]
+    fn test_program_debug_info() {
+        let debug = DebugInfo::from_json(
+            &json!({"source": "sq.t", "strings": [[19, "squared"]]}),
+            "debug",
+        )
+        .unwrap();
+        assert_eq!(debug.source.as_deref(), Some("sq.t"));
+        assert_eq!(debug.get_string_name(19), Some("squared"));
+        assert_eq!(debug.get_string_name(0), None);
+    }
+
+    #[test]
+    fn test_malformed_debug_info() {
+        let error = |tape: &str| Program::from_json(tape).unwrap_err().to_string();
+        let tape = |debug: &str| {
+            format!(
+                "[[1, 0], [], [0, \"f\", 0, 0, [1, 2]], [1, \"g\", 0, 0, [1, 2, [3, 4]], {}]]",
+                debug
+            )
+        };
+        assert_eq!(
+            error(&tape(r#"{"lines": [1, 2, [3, 3], 4]}"#)),
+            "functions[1].debug.lines: expected 3 entries to match the frame, found 4"
+        );
+        assert_eq!(
+            error(&tape(r#"{"lines": [1, -2, [3, 3]]}"#)),
+            "functions[1].debug.lines[1]: expected a non-negative integer"
+        );
+        assert_eq!(
+            error(&tape(r#"{"lines": [1, 2, 3]}"#)),
+            "functions[1].debug.lines[2]: expected the lines of a nested frame"
+        );
+        assert_eq!(
+            error(&tape(r#"{"lines": [1, 2, [3]]}"#)),
+            "functions[1].debug.lines[2]: expected 2 entries to match the frame, found 1"
+        );
+        assert_eq!(
+            error(&tape(r#"{"variables": [["n", 1], [2, 1]]}"#)),
+            "functions[1].debug.variables[1][0]: expected a string"
+        );
+        assert_eq!(
+            error(&tape(r#"{"variables": [["n"]]}"#)),
+            "functions[1].debug.variables[0]: expected [name, offset]"
+        );
+        assert_eq!(
+            error(r#"[[0, 0], [], [0, "f", 0, 0, []], {"strings": [[0, "a"], [-1, "b"]]}]"#),
+            "debug.strings[1][0]: expected a non-negative integer"
+        );
+        assert_eq!(
+            error(r#"[[0, 0], [], [0, "f", 0, 0, []], {"source": 7}]"#),
+            "debug.source: expected a string"
+        );
+    }
+}
@@ -0,0 +1,483 @@
+use crate::batch::panic_message;
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
+use crate::instruction::Instruction;
+use crate::program::Program;
+use crate::reference::Reference;
+use crate::state::StateHolder;
+use crate::tvm::Tvm;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+// Differential testing of the machine against the reference interpreter. Both run in lockstep
+// and are compared before every instruction: which function and instruction is next, the
+// registers, the output and the active memory.
+
+// The machine at one point of a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    // Function and mnemonic of the next instruction, or "halted".
+    pub instruction: String,
+    pub sp: usize,
+    pub fp: usize,
+    pub heap_size: usize,
+    pub stdout: String,
+    // The heap followed by the stack.
+    pub memory: Vec<(usize, i32)>,
+}
+
+impl Snapshot {
+    fn of_reference(reference: &Reference, ir: Option<i32>) -> Self {
+        let instruction = match (ir, reference.calls.last()) {
+            (Some(ir), Some(function)) => {
+                format!(
+                    "{} {}",
+                    function,
+                    Instruction::get_instruction(ir as u32).mnemonic()
+                )
+            }
+            _ => "halted".to_string(),
+        };
+        Snapshot {
+            instruction,
+            sp: reference.sp,
+            fp: reference.fp,
+            heap_size: reference.heap_size,
+            stdout: reference.stdout.clone(),
+            memory: active_memory(&reference.memory, reference.heap_size, reference.sp),
+        }
+    }
+
+    fn of_tvm(tvm: &Tvm) -> Self {
+        let instruction = match (tvm.next_instruction(), tvm.state.get_code_position()) {
+            (Some(instruction), Some(position)) => {
+                format!("{} {}", position.function.name, instruction.mnemonic())
+            }
+            _ => "halted".to_string(),
+        };
+        Snapshot {
+            instruction,
+            sp: tvm.stack_pointer,
+            fp: tvm.frame_pointer,
+            heap_size: tvm.heap_size,
+            stdout: tvm.stdout.clone(),
+            memory: active_memory(&tvm.memory, tvm.heap_size, tvm.stack_pointer),
+        }
+    }
+}
+
+fn active_memory(memory: &[i32], heap_size: usize, sp: usize) -> Vec<(usize, i32)> {
+    (0..heap_size.min(sp))
+        .chain(sp + 1..memory.len())
+        .map(|i| (i, memory[i]))
+        .collect()
+}
+
+// The first point where the machine and the reference disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Instructions both executed before disagreeing.
+    pub step: usize,
+    pub reason: String,
+    pub reference: Snapshot,
+    pub tvm: Snapshot,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "diverged after {} instructions: {}",
+            self.step, self.reason
+        )?;
+        writeln!(f, "{:<12}{:<24}{:<24}", "", "reference", "tvm")?;
+        let (r, t) = (&self.reference, &self.tvm);
+        writeln!(
+            f,
+            "{:<12}{:<24}{:<24}",
+            "next", r.instruction, t.instruction
+        )?;
+        writeln!(f, "{:<12}{:<24}{:<24}", "sp", r.sp, t.sp)?;
+        writeln!(f, "{:<12}{:<24}{:<24}", "fp", r.fp, t.fp)?;
+        writeln!(
+            f,
+            "{:<12}{:<24}{:<24}",
+            "heap size", r.heap_size, t.heap_size
+        )?;
+        writeln!(
+            f,
+            "{:<12}{:<24}{:<24}",
+            "stdout",
+            format!("{:?}", r.stdout),
+            format!("{:?}", t.stdout)
+        )?;
+        // Every address either side has live, with differing cells marked.
+        let mut addresses: Vec<usize> = r.memory.iter().chain(&t.memory).map(|c| c.0).collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+        let cell = |memory: &[(usize, i32)], address| {
+            memory
+                .iter()
+                .find(|c| c.0 == address)
+                .map_or("-".to_string(), |c| c.1.to_string())
+        };
+        for address in addresses {
+            let (a, b) = (cell(&r.memory, address), cell(&t.memory, address));
+            let mark = if a != b { "  <-" } else { "" };
+            writeln!(f, "{:<12}{:<24}{:<24}{}", address, a, b, mark)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+// Ticks the machine until it is about to execute an instruction or halts.
+fn advance(tvm: &mut Tvm, max_ticks: usize) -> Result<(), String> {
+    loop {
+        tvm.tick();
+        if tvm.is_halted() || tvm.next_instruction().is_some() {
+            return Ok(());
+        }
+        if tvm.ticks >= max_ticks {
+            return Err("the machine ran out of ticks".to_string());
+        }
+    }
+}
+
+/// Runs a program on the machine and the reference with the same input, returning how many
+/// instructions they agreed on, or where they first disagree.
+pub fn compare(
+    program: impl Into<Arc<Program>>,
+    input: &str,
+    max_ticks: usize,
+) -> Result<usize, Box<Divergence>> {
+    let program = program.into();
+    let mut tvm = Tvm {
+        input: Some(input.to_string()),
+        ..Tvm::default()
+    };
+    tvm.load(Arc::clone(&program));
+    tvm.start();
+    let mut reference = Reference::new(program);
+    reference.input = input.to_string();
+
+    let mut divergence = None;
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        reference.run_with(&mut |reference, ir| {
+            let advanced = panic::catch_unwind(AssertUnwindSafe(|| advance(&mut tvm, max_ticks)))
+                .unwrap_or_else(|payload| {
+                    Err(format!("the machine panicked: {}", panic_message(payload)))
+                });
+            let expected = Snapshot::of_reference(reference, Some(ir));
+            let reason = match advanced {
+                Err(reason) => Some(reason),
+                Ok(()) => first_difference(&expected, &Snapshot::of_tvm(&tvm)),
+            };
+            if let Some(reason) = reason {
+                divergence = Some(diverge(reference, Some(ir), &tvm, reason));
+                return false;
+            }
+            true
+        })
+    }));
+    if let Some(divergence) = divergence {
+        return Err(divergence);
+    }
+    let reason = match outcome {
+        Err(payload) => Some(format!(
+            "the reference panicked: {}",
+            panic_message(payload)
+        )),
+        // The reference finished, so the machine should halt without executing anything else.
+        Ok(_) => match advance(&mut tvm, max_ticks) {
+            Err(reason) => Some(reason),
+            Ok(()) => first_difference(
+                &Snapshot::of_reference(&reference, None),
+                &Snapshot::of_tvm(&tvm),
+            ),
+        },
+    };
+    match reason {
+        Some(reason) => Err(diverge(&reference, None, &tvm, reason)),
+        None => Ok(reference.steps),
+    }
+}
+
+fn diverge(reference: &Reference, ir: Option<i32>, tvm: &Tvm, reason: String) -> Box<Divergence> {
+    Box::new(Divergence {
+        step: reference.steps,
+        reason,
+        reference: Snapshot::of_reference(reference, ir),
+        tvm: Snapshot::of_tvm(tvm),
+    })
+}
+
+fn first_difference(reference: &Snapshot, tvm: &Snapshot) -> Option<String> {
+    if reference.instruction != tvm.instruction {
+        Some(format!(
+            "next instruction is {} but the machine is at {}",
+            reference.instruction, tvm.instruction
+        ))
+    } else if (reference.sp, reference.fp, reference.heap_size) != (tvm.sp, tvm.fp, tvm.heap_size) {
+        Some("registers differ".to_string())
+    } else if reference.stdout != tvm.stdout {
+        Some("output differs".to_string())
+    } else if reference.memory != tvm.memory {
+        Some("memory differs".to_string())
+    } else {
+        None
+    }
+}
+
+// Opcodes used by the generator.
+const PUSH: i32 = 1;
+const FETCH: i32 = 2;
+const STORE: i32 = 3;
+const IF: i32 = 4;
+const LOOP: i32 = 5;
+const BREAK: i32 = 6;
+const RETURN: i32 = 7;
+const CALL: i32 = 8;
+const FPPLUS: i32 = 9;
+const ADD: i32 = 10;
+const SUB: i32 = 11;
+const MUL: i32 = 12;
+const NOT: i32 = 15;
+const AND: i32 = 16;
+const LEQ: i32 = 22;
+const POP: i32 = 25;
+const IPRINT: i32 = -101;
+const OPERATORS: [i32; 12] = [ADD, SUB, MUL, AND, 17, 18, 19, 20, 21, LEQ, 23, 24];
+// Plain variables of every generated function, and how many loop counters it can have.
+const VARIABLES: usize = 2;
+const COUNTERS: usize = 4;
+
+/// Generates a random well formed program that always halts. Programs nest IF and LOOP
+/// frames, leave loops with BREAK from inside IF branches and RETURN from inside loops, and
+/// call helper functions with arguments, which are the paths where the machine keeps the most
+/// state.
+pub fn generate(seed: u64) -> Program {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let helpers = rng.gen_range(0..3);
+    let mut program = Program::builder().entry_point(helpers);
+    let mut signatures = Vec::new();
+    for id in 0..=helpers {
+        let (name, args) = if id == helpers {
+            ("init".to_string(), 0)
+        } else {
+            (format!("f{}", id), rng.gen_range(0..3))
+        };
+        let mut generator = Generator {
+            rng: &mut rng,
+            signatures: &signatures,
+            args,
+            counters: 0,
+            loops: 0,
+        };
+        let mut data = generator.block(3);
+        generator.expression(&mut data, 2);
+        data.push(FrameData::from(RETURN));
+        let mut frame: Frame = data.into_iter().collect();
+        frame.id = id;
+        frame.name = format!("{}-frame", name);
+        program = program.function(
+            Function::builder()
+                .id(id)
+                .name(name)
+                .args(args)
+                .locals(VARIABLES + COUNTERS)
+                .frame(frame)
+                .build(),
+        );
+        signatures.push(args);
+    }
+    program.build()
+}
+
+struct Generator<'a> {
+    rng: &'a mut StdRng,
+    // Argument counts of the functions that can be called, which are the ones before this.
+    signatures: &'a [usize],
+    args: usize,
+    // Loop counters handed out so far.
+    counters: usize,
+    // Loops the code being generated is nested in.
+    loops: usize,
+}
+
+impl Generator<'_> {
+    fn words(data: &mut Vec<FrameData>, words: &[i32]) {
+        data.extend(words.iter().map(|word| FrameData::from(*word)));
+    }
+
+    fn frame(data: Vec<FrameData>) -> FrameData {
+        FrameData::Frame(data.into_iter().collect())
+    }
+
+    // Pushes one value. Arithmetic is masked so values stay small and never overflow.
+    fn expression(&mut self, data: &mut Vec<FrameData>, depth: usize) {
+        match self.rng.gen_range(0..if depth == 0 { 2 } else { 5 }) {
+            0 => Self::words(data, &[PUSH, self.rng.gen_range(-9..10)]),
+            1 => {
+                // A variable or an argument. Arguments live above the locals.
+                let slot = self.rng.gen_range(1..=VARIABLES + self.args);
+                let slot = if slot > VARIABLES {
+                    slot + COUNTERS
+                } else {
+                    slot
+                };
+                Self::words(data, &[PUSH, slot as i32, FPPLUS, FETCH]);
+            }
+            2 => {
+                self.expression(data, depth - 1);
+                Self::words(data, &[NOT]);
+            }
+            3 if !self.signatures.is_empty() => {
+                // Arguments are pushed first to last.
+                let id = self.rng.gen_range(0..self.signatures.len());
+                for _ in 0..self.signatures[id] {
+                    self.expression(data, depth - 1);
+                }
+                Self::words(data, &[CALL, id as i32]);
+            }
+            _ => {
+                self.expression(data, depth - 1);
+                self.expression(data, depth - 1);
+                let op = OPERATORS[self.rng.gen_range(0..OPERATORS.len())];
+                Self::words(data, &[op]);
+                if [ADD, SUB, MUL].contains(&op) {
+                    Self::words(data, &[PUSH, 1023, AND]);
+                }
+            }
+        }
+    }
+
+    // Leaves the stack as it found it.
+    fn block(&mut self, depth: usize) -> Vec<FrameData> {
+        let mut data = Vec::new();
+        for _ in 0..self.rng.gen_range(1..4) {
+            self.statement(&mut data, depth);
+        }
+        data
+    }
+
+    fn statement(&mut self, data: &mut Vec<FrameData>, depth: usize) {
+        let kinds = match (depth, self.loops) {
+            (0, _) => 2,
+            (_, 0) => 5,
+            _ => 7,
+        };
+        match self.rng.gen_range(0..kinds) {
+            0 => {
+                let slot = self.rng.gen_range(1..=VARIABLES) as i32;
+                Self::words(data, &[PUSH, slot, FPPLUS]);
+                self.expression(data, 2);
+                Self::words(data, &[STORE]);
+            }
+            1 => {
+                self.expression(data, 2);
+                Self::words(data, &[CALL, IPRINT, POP]);
+            }
+            2 => {
+                self.expression(data, 1);
+                let then = self.block(depth - 1);
+                let otherwise = self.block(depth - 1);
+                Self::words(data, &[IF]);
+                data.push(Self::frame(then));
+                data.push(Self::frame(otherwise));
+            }
+            3 if self.counters < COUNTERS => self.counted_loop(data, depth),
+            3 | 4 => {
+                // Return early, from wherever this is nested.
+                self.expression(data, 1);
+                let mut then = Vec::new();
+                self.expression(&mut then, 1);
+                Self::words(&mut then, &[RETURN]);
+                Self::words(data, &[IF]);
+                data.push(Self::frame(then));
+                data.push(Self::frame(vec![]));
+            }
+            5 => {
+                self.expression(data, 1);
+                Self::words(data, &[BREAK]);
+            }
+            _ => {
+                // Break from inside an IF, which has to unwind its frame as well.
+                self.expression(data, 1);
+                Self::words(data, &[IF]);
+                data.push(Self::frame(
+                    [PUSH, 1, BREAK].into_iter().map(FrameData::from).collect(),
+                ));
+                data.push(Self::frame(self.block(0)));
+            }
+        }
+    }
+
+    // A loop that runs at most a few times, counting down a local of its own.
+    fn counted_loop(&mut self, data: &mut Vec<FrameData>, depth: usize) {
+        self.counters += 1;
+        let counter = (VARIABLES + self.counters) as i32;
+        let times = self.rng.gen_range(0..4);
+        Self::words(data, &[PUSH, counter, FPPLUS, PUSH, times, STORE]);
+        let mut body = Vec::new();
+        Self::words(
+            &mut body,
+            &[
+                PUSH, counter, FPPLUS, FETCH, PUSH, 0, LEQ, BREAK, // stop at zero
+                PUSH, counter, FPPLUS, PUSH, counter, FPPLUS, FETCH, PUSH, 1, SUB, STORE,
+            ],
+        );
+        self.loops += 1;
+        body.extend(self.block(depth - 1));
+        self.loops -= 1;
+        Self::words(data, &[LOOP]);
+        data.push(Self::frame(body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shipped_tapes() {
+        for file in ["sq.json", "sieve.json"] {
+            let program = Program::from_file(file.to_string()).unwrap();
+            assert!(compare(program, "", 10_000_000).unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn test_generated_programs() {
+        for seed in 0..50 {
+            let program = generate(seed);
+            program.verify().unwrap();
+            if let Err(divergence) = compare(program, "", 1_000_000) {
+                panic!("seed {}: {}", seed, divergence);
+            }
+        }
+    }
+
+    #[test]
+    fn test_divergence() {
+        // tvm.js floors division where the machine truncates.
+        let program = Program::from_assembly(
+            "CALL .init\n\n.init 0 0:\n    PUSH -7\n    PUSH 2\n    DIV\n    CALL iprint\n    RETURN\n",
+        )
+        .unwrap();
+        let divergence = compare(program, "", 1000).unwrap_err();
+        assert_eq!(divergence.step, 3);
+        assert_eq!(divergence.reason, "memory differs");
+        assert_eq!(divergence.reference.instruction, "init CALL");
+        let cell = |snapshot: &Snapshot| snapshot.memory.iter().find(|c| c.0 == 65534).copied();
+        assert_eq!(cell(&divergence.reference), Some((65534, -4)));
+        assert_eq!(cell(&divergence.tvm), Some((65534, -3)));
+        assert!(divergence
+            .to_string()
+            .contains("65534       -4                      -3                        <-"));
+    }
+}
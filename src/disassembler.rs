@@ -0,0 +1,282 @@
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
+use crate::instruction::Instruction;
+use crate::native::NativeFunction;
+use crate::program::Program;
+use std::fmt::Write;
+
+// Renders a program in the listing format read by the assembler.
+
+const INDENT: &str = "    ";
+
+impl Program {
+    pub fn to_assembly(&self) -> String {
+        Disassembler::new(self).disassemble()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    pub text: String,
+    // Indices leading to the frame data this line was rendered from, one per nested frame.
+    pub path: Option<Vec<usize>>,
+}
+
+#[derive(Debug)]
+pub struct Disassembler<'a> {
+    program: &'a Program,
+    lines: Vec<ListingLine>,
+    path: Vec<usize>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(program: &'a Program) -> Self {
+        Self {
+            program,
+            lines: Vec::new(),
+            path: Vec::new(),
+        }
+    }
+
+    pub fn disassemble(mut self) -> String {
+        let entry = self.call_target(self.program.entry_point as i32);
+        self.line(0, &format!("{:<8}{}", "CALL", entry));
+        self.heap();
+        for function in &self.program.functions {
+            self.blank();
+            self.header(function);
+            self.frame(&function.frame, 1);
+        }
+        self.lines
+            .iter()
+            .map(|line| format!("{}\n", line.text))
+            .collect()
+    }
+
+    // The listing of a single function, used by the code view of the debugger.
+    pub fn function(mut self, function: &Function) -> Vec<ListingLine> {
+        self.header(function);
+        self.frame(&function.frame, 1);
+        self.lines
+    }
+
+    fn header(&mut self, function: &Function) {
+        self.line(
+            0,
+            &format!(".{} {} {}:", function.name, function.args, function.locals),
+        );
+    }
+
+    fn blank(&mut self) {
+        self.line(0, "");
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        self.lines.push(ListingLine {
+            text: format!("{}{}", INDENT.repeat(depth), text),
+            path: None,
+        });
+    }
+
+    fn data_line(&mut self, depth: usize, index: usize, text: &str) {
+        self.line(depth, text);
+        let mut path = self.path.clone();
+        path.push(index);
+        self.lines.last_mut().unwrap().path = Some(path);
+    }
+
+    fn nested_frame(&mut self, frame: &Frame, depth: usize, index: usize) {
+        self.path.push(index);
+        self.frame(frame, depth);
+        self.path.pop();
+    }
+
+    fn heap(&mut self) {
+        if self.program.heap.is_empty() && self.program.heap_size == 0 {
+            return;
+        }
+        self.blank();
+        self.line(0, &format!(".data {}:", self.program.heap_size));
+        let mut heap = self.program.heap.clone();
+        heap.sort();
+        let mut i = 0;
+        while i < heap.len() {
+            let (address, value) = heap[i];
+            match decode_string(&heap[i..]) {
+                Some(s) => {
+                    self.line(1, &format!("{:<8}{}", address, quote(&s)));
+                    i += s.chars().count() + 1;
+                }
+                None => {
+                    self.line(1, &format!("{:<8}{}", address, value));
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    fn frame(&mut self, frame: &Frame, depth: usize) {
+        let mut pc = 0;
+        while pc < frame.data.len() {
+            let index = pc;
+            let data = &frame.data[pc];
+            pc += 1;
+            let instruction = match data {
+                FrameData::Instruction(instruction, _) => instruction,
+                FrameData::Frame(nested) => {
+                    self.nested_frame(nested, depth + 1, index);
+                    continue;
+                }
+                other => {
+                    self.data_line(depth, index, &format!("; stray word {}", other.get_id()));
+                    continue;
+                }
+            };
+            let operand = frame.data.get(pc).map(FrameData::get_id);
+            match (instruction, operand) {
+                (Instruction::Push { .. }, Some(value)) => {
+                    self.data_line(depth, index, &format!("{:<8}{}", "PUSH", value));
+                    pc += 1;
+                }
+                (Instruction::Call { .. }, Some(id)) => {
+                    let target = self.call_target(id);
+                    self.data_line(depth, index, &format!("{:<8}{}", "CALL", target));
+                    pc += 1;
+                }
+                (Instruction::IF { .. }, _) => {
+                    self.data_line(depth, index, "IF");
+                    if let Some(FrameData::Frame(then)) = frame.data.get(pc) {
+                        self.nested_frame(then, depth + 1, pc);
+                        pc += 1;
+                    }
+                    if let Some(FrameData::Frame(otherwise)) = frame.data.get(pc) {
+                        // An empty else frame is implied, unless the next word is another frame
+                        // which would otherwise be read back as part of this one.
+                        let next_is_frame =
+                            matches!(frame.data.get(pc + 1), Some(FrameData::Frame(_)));
+                        if !otherwise.data.is_empty() || next_is_frame {
+                            self.line(depth, "ELSE");
+                            self.nested_frame(otherwise, depth + 1, pc);
+                        }
+                        pc += 1;
+                    }
+                }
+                (Instruction::Loop { .. }, _) => {
+                    self.data_line(depth, index, "LOOP");
+                    if let Some(FrameData::Frame(body)) = frame.data.get(pc) {
+                        self.nested_frame(body, depth + 1, pc);
+                        pc += 1;
+                    }
+                }
+                (instruction, _) => self.data_line(depth, index, instruction.mnemonic()),
+            }
+        }
+    }
+
+    fn call_target(&self, id: i32) -> String {
+        match id {
+            n @ -111..=-101 => NativeFunction::get_native(n).name(),
+            n if n >= 0 => match self.program.functions.get(n as usize) {
+                Some(function) => format!(".{}", function.name),
+                None => n.to_string(),
+            },
+            n => n.to_string(),
+        }
+    }
+}
+
+// Reads a zero terminated run of printable characters at consecutive addresses.
+fn decode_string(heap: &[(usize, i32)]) -> Option<String> {
+    let start = heap.first()?.0;
+    let mut s = String::new();
+    for (i, (address, value)) in heap.iter().enumerate() {
+        if *address != start + i {
+            return None;
+        }
+        match *value {
+            0 if s.is_empty() => return None,
+            0 => return Some(s),
+            c @ (32..=126 | 9 | 10 | 13) => s.push(c as u8 as char),
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            c => write!(out, "{}", c).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trip(file: &str) {
+        let program = Program::from_file(file.to_string()).unwrap();
+        let listing = program.to_assembly();
+        let assembled = Program::from_assembly(&listing).unwrap();
+        assert_eq!(assembled.entry_point, program.entry_point);
+        assert_eq!(assembled.heap_size, program.heap_size);
+        assert_eq!(assembled.functions, program.functions);
+        let mut expected = program.heap.clone();
+        let mut actual = assembled.heap;
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_round_trip("sq.json");
+        assert_round_trip("sieve.json");
+    }
+
+    #[test]
+    fn test_listing() {
+        let listing = Program::from_file("sq.json".to_string())
+            .unwrap()
+            .to_assembly();
+        assert!(listing.starts_with("CALL    .init\n\n.data 36:\n"));
+        assert!(listing.contains("    0       \"Table of squares:\\n\"\n"));
+        assert!(listing.contains("    19      \" squared equals \"\n"));
+        assert!(listing.contains("    65535   0\n"));
+        assert!(listing.contains(".sq 1 0:\n    PUSH    1\n    FPPLUS\n"));
+        assert!(listing.contains("    LOOP\n        PUSH    1\n"));
+        assert!(listing.contains("        CALL    .sq\n        CALL    iprint\n"));
+    }
+
+    #[test]
+    fn test_function_paths() {
+        let program = Program::from_file("sq.json".to_string()).unwrap();
+        let lines = Disassembler::new(&program).function(&program.functions[1]);
+        assert_eq!(lines[0].text, ".init 0 1:");
+        assert_eq!(lines[0].path, None);
+        assert_eq!(lines[1].text, "    PUSH    0");
+        assert_eq!(lines[1].path, Some(vec![0]));
+        assert_eq!(lines[2].path, Some(vec![2]));
+        let body = lines.iter().position(|l| l.text == "    LOOP").unwrap();
+        assert_eq!(lines[body].path, Some(vec![11]));
+        assert_eq!(lines[body + 1].text, "        PUSH    1");
+        assert_eq!(lines[body + 1].path, Some(vec![12, 0]));
+    }
+
+    #[test]
+    fn test_if_else() {
+        let listing = Program::from_file("sieve.json".to_string())
+            .unwrap()
+            .to_assembly();
+        assert!(listing.contains("    IF\n        PUSH    0\n        PUSH    1\n        SUB\n        RETURN\n    PUSH    2\n"));
+    }
+}
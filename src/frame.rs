@@ -10,6 +10,11 @@ pub struct Frame {
     pub name: String,
     pub data: Vec<FrameData>,
     pub pc: usize,
+    // Source line of each entry in data. Empty when the tape carries no debug info.
+    pub lines: Vec<usize>,
+    // Where this frame sits in the data of the frame that entered it, set when a branch or
+    // loop body is entered.
+    pub index: usize,
 }
 
 impl FromIterator<FrameData> for Frame {
@@ -19,6 +24,8 @@ impl FromIterator<FrameData> for Frame {
             name: "".to_string(),
             data: iter.into_iter().collect(),
             pc: 0,
+            lines: vec![],
+            index: 0,
         }
     }
 }
@@ -80,6 +87,18 @@ impl FrameData {
     }
 }
 
+// Tapes do not distinguish operands from opcodes, so a bare word is decoded the same way
+// wherever it appears. Operands are read back through `get_id`.
+impl From<i32> for FrameData {
+    fn from(word: i32) -> Self {
+        match word {
+            n @ -111..=-101 => FrameData::Callable(Callable::get_native(n), vec![]),
+            n @ 1..=27 => FrameData::Instruction(Instruction::get_instruction(n as u32), vec![]),
+            n => FrameData::Primitive(n),
+        }
+    }
+}
+
 pub trait FrameEvaluator {
     fn do_frame_eval(&mut self, frame: Frame);
 }
@@ -98,6 +117,7 @@ pub struct FrameBuilder {
     id: usize,
     name: String,
     data: Vec<FrameData>,
+    lines: Vec<usize>,
 }
 
 impl FrameBuilder {
@@ -127,17 +147,29 @@ impl FrameBuilder {
         self
     }
 
+    pub fn data(mut self, data: FrameData) -> Self {
+        self.data.push(data);
+        self
+    }
+
     pub fn primitive(mut self, primitive: i32) -> Self {
         self.data.push(FrameData::Primitive(primitive));
         self
     }
 
+    pub fn lines(mut self, lines: Vec<usize>) -> Self {
+        self.lines = lines;
+        self
+    }
+
     pub fn build(self) -> Frame {
         Frame {
             id: self.id,
             name: self.name,
             data: self.data,
             pc: 0,
+            lines: self.lines,
+            index: 0,
         }
     }
 }
@@ -145,7 +177,6 @@ impl FrameBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::state;
     use crate::state::{EvalState, TvmState};
 
     #[test]
@@ -159,7 +190,7 @@ mod tests {
             .build();
         tvm.do_frame_eval(frame);
         assert!(
-            matches!(tvm.state, TvmState::Eval(EvalState { frame, .. }) if frame.name == "test")
+            matches!(tvm.state.top(), TvmState::Eval(EvalState { frame, .. }) if frame.name == "test")
         );
     }
 
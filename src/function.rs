@@ -1,3 +1,4 @@
+use crate::debug_info::FunctionDebugInfo;
 use crate::frame::Frame;
 use std::fmt::Display;
 
@@ -8,6 +9,7 @@ pub struct Function {
     pub args: usize,
     pub locals: usize,
     pub frame: Frame,
+    pub debug: Option<FunctionDebugInfo>,
 }
 
 impl Function {
@@ -29,6 +31,7 @@ pub struct FunctionBuilder {
     args: usize,
     locals: usize,
     frame: Frame,
+    debug: Option<FunctionDebugInfo>,
 }
 
 impl FunctionBuilder {
@@ -61,6 +64,11 @@ impl FunctionBuilder {
         self
     }
 
+    pub fn debug(mut self, debug: FunctionDebugInfo) -> Self {
+        self.debug = Some(debug);
+        self
+    }
+
     pub fn build(self) -> Function {
         Function {
             id: self.id,
@@ -68,6 +76,7 @@ impl FunctionBuilder {
             args: self.args,
             locals: self.locals,
             frame: self.frame,
+            debug: self.debug,
         }
     }
 }
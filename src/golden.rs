@@ -0,0 +1,296 @@
+use crate::batch::{BatchRunner, ExitStatus, Job};
+use crate::program::Program;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Golden-output tests over a directory of JSON tapes. Next to `name.json` live:
+//
+//     name.stdout   expected output
+//     name.stdin    input for iread and sread, optional
+//     name.status   expected exit status, optional, `halted` when missing
+//
+// Blessing writes the sidecars from the actual run instead of comparing against them.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail {
+        // Unified style line diff, if the output differs.
+        stdout_diff: Option<String>,
+        // Expected and actual status, if they differ.
+        status: Option<(String, String)>,
+    },
+    Blessed,
+    // The tape or its sidecars could not be read.
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub tape: PathBuf,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| matches!(result.outcome, Outcome::Pass | Outcome::Blessed))
+    }
+}
+
+impl Display for TestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut failed = 0;
+        for result in &self.results {
+            let name = result.tape.display();
+            match &result.outcome {
+                Outcome::Pass => writeln!(f, "ok      {}", name)?,
+                Outcome::Blessed => writeln!(f, "blessed {}", name)?,
+                Outcome::Error(message) => {
+                    failed += 1;
+                    writeln!(f, "ERROR   {}: {}", name, message)?
+                }
+                Outcome::Fail {
+                    stdout_diff,
+                    status,
+                } => {
+                    failed += 1;
+                    writeln!(f, "FAIL    {}", name)?;
+                    if let Some((expected, actual)) = status {
+                        writeln!(f, "  expected status {}, got {}", expected, actual)?;
+                    }
+                    if let Some(diff) = stdout_diff {
+                        writeln!(f, "  stdout (-expected +actual):")?;
+                        for line in diff.lines() {
+                            writeln!(f, "  {}", line)?;
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(
+            f,
+            "{} passed, {} failed",
+            self.results.len() - failed,
+            failed
+        )
+    }
+}
+
+impl Display for ExitStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitStatus::Halted => write!(f, "halted"),
+            ExitStatus::TickLimit => write!(f, "tick limit"),
+            ExitStatus::Fault(message) => write!(f, "fault: {}", message),
+        }
+    }
+}
+
+/// Runs every `.json` tape in `dir`, in name order, against its sidecar files.
+pub fn run_directory(dir: &Path, bless: bool, max_ticks: usize) -> io::Result<TestReport> {
+    let mut tapes: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    tapes.retain(|path| path.extension().is_some_and(|e| e == "json"));
+    tapes.sort();
+
+    let mut results: Vec<TestResult> = Vec::new();
+    let mut jobs = Vec::new();
+    // Indices into results of the tapes that loaded and are waiting on a job.
+    let mut pending = Vec::new();
+    for tape in tapes {
+        let program = fs::read_to_string(&tape)
+            .map_err(|e| e.to_string())
+            .and_then(|json| Program::from_json(&json).map_err(|e| e.to_string()));
+        let input = read_sidecar(&tape, "stdin");
+        let outcome = match (program, input) {
+            (Ok(program), Ok(input)) => {
+                pending.push(results.len());
+                jobs.push(
+                    Job::new(program)
+                        .input(input.unwrap_or_default())
+                        .max_ticks(max_ticks),
+                );
+                // Replaced once the job has run.
+                Outcome::Pass
+            }
+            (Err(message), _) | (_, Err(message)) => Outcome::Error(message),
+        };
+        results.push(TestResult { tape, outcome });
+    }
+
+    for (i, job) in pending.into_iter().zip(BatchRunner::new().run(jobs)) {
+        let tape = &results[i].tape;
+        let status = job.status.to_string();
+        results[i].outcome = if bless {
+            bless_tape(tape, &job.stdout, &status)
+        } else {
+            check_tape(tape, &job.stdout, &status)
+        };
+    }
+    Ok(TestReport { results })
+}
+
+fn check_tape(tape: &Path, stdout: &str, status: &str) -> Outcome {
+    let expected_stdout = match read_sidecar(tape, "stdout") {
+        Ok(Some(expected)) => expected,
+        Ok(None) => return Outcome::Error("no .stdout file, run with --bless".to_string()),
+        Err(message) => return Outcome::Error(message),
+    };
+    let expected_status = match read_sidecar(tape, "status") {
+        Ok(expected) => expected.map_or("halted".to_string(), |s| s.trim().to_string()),
+        Err(message) => return Outcome::Error(message),
+    };
+    let stdout_diff = (expected_stdout != stdout).then(|| diff(&expected_stdout, stdout));
+    let status = (expected_status != status).then(|| (expected_status, status.to_string()));
+    if stdout_diff.is_none() && status.is_none() {
+        Outcome::Pass
+    } else {
+        Outcome::Fail {
+            stdout_diff,
+            status,
+        }
+    }
+}
+
+fn bless_tape(tape: &Path, stdout: &str, status: &str) -> Outcome {
+    let written = fs::write(tape.with_extension("stdout"), stdout).and_then(|_| {
+        let path = tape.with_extension("status");
+        if status == "halted" {
+            // Halting is the default, so the file is only kept for other statuses.
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        } else {
+            fs::write(path, format!("{}\n", status))
+        }
+    });
+    match written {
+        Ok(()) => Outcome::Blessed,
+        Err(e) => Outcome::Error(e.to_string()),
+    }
+}
+
+fn read_sidecar(tape: &Path, extension: &str) -> Result<Option<String>, String> {
+    match fs::read_to_string(tape.with_extension(extension)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("{}: {}", extension, e)),
+    }
+}
+
+/// Line diff of two texts, with removed lines marked `-`, added lines `+` and common lines
+/// indented.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    // Longest common subsequence lengths of every pair of suffixes.
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push_str(&format!(" {}\n", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push_str(&format!("-{}\n", a[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+{}\n", b[j]));
+            j += 1;
+        }
+    }
+    // Output that only differs in a trailing newline would otherwise show no change.
+    if out.lines().all(|line| line.starts_with(' ')) {
+        out.push_str(&format!(
+            "(trailing newline: expected {}, actual {})\n",
+            expected.ends_with('\n'),
+            actual.ends_with('\n')
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn test_shipped_tapes() {
+        let report = run_directory(Path::new("."), false, 10_000_000).unwrap();
+        let tapes: Vec<&Path> = report.results.iter().map(|r| r.tape.as_path()).collect();
+        assert_eq!(tapes, [Path::new("./sieve.json"), Path::new("./sq.json")]);
+        assert!(report.passed(), "{}", report);
+    }
+
+    #[test]
+    fn test_bless_and_fail() {
+        let dir = temp_dir("golden-bless");
+        fs::copy("sq.json", dir.join("sq.json")).unwrap();
+        let echo = Program::from_source("fun init() {\n    iprint(iread(-1))\n}").unwrap();
+        echo.write_file(dir.join("echo.json").to_str().unwrap().to_string())
+            .unwrap();
+        fs::write(dir.join("echo.stdin"), "12\n").unwrap();
+        fs::write(dir.join("broken.json"), "[[0, 0]").unwrap();
+
+        let report = run_directory(&dir, false, 1_000_000).unwrap();
+        assert!(!report.passed());
+        assert!(
+            matches!(&report.results[0].outcome, Outcome::Error(m) if m.starts_with("invalid json"))
+        );
+        assert!(matches!(&report.results[1].outcome, Outcome::Error(m) if m.contains("--bless")));
+
+        fs::remove_file(dir.join("broken.json")).unwrap();
+        let report = run_directory(&dir, true, 1_000_000).unwrap();
+        assert!(report.results.iter().all(|r| r.outcome == Outcome::Blessed));
+        assert_eq!(fs::read_to_string(dir.join("echo.stdout")).unwrap(), "12");
+        assert!(run_directory(&dir, false, 1_000_000).unwrap().passed());
+
+        fs::write(dir.join("echo.stdin"), "13\n").unwrap();
+        let report = run_directory(&dir, false, 100).unwrap();
+        assert_eq!(
+            report.results[0].outcome,
+            Outcome::Fail {
+                stdout_diff: Some("-12\n+13\n".to_string()),
+                status: None,
+            }
+        );
+        assert!(matches!(
+            &report.results[1].outcome,
+            Outcome::Fail { status: Some((expected, actual)), .. }
+                if expected == "halted" && actual == "tick limit"
+        ));
+        assert!(report.to_string().ends_with("0 passed, 2 failed\n"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+        assert_eq!(
+            diff("a\n", "a"),
+            " a\n(trailing newline: expected true, actual false)\n"
+        );
+    }
+}
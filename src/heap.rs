@@ -1,6 +1,43 @@
 use crate::tvm::Tvm;
+use std::ops::Range;
 
-trait HeapHolder {
+// A block handed out by alloc that has not been freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+}
+
+impl Allocation {
+    pub fn contains(&self, address: usize) -> bool {
+        (self.address..self.address + self.size).contains(&address)
+    }
+}
+
+// What is known about a heap cell, for the heap inspector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapCell {
+    pub address: usize,
+    pub value: i32,
+    // The zero terminated string starting here, if this cell starts one.
+    pub string: Option<String>,
+    // Whether the cell is part of a string, including its terminator.
+    pub in_string: bool,
+    // The live allocation the cell belongs to.
+    pub allocation: Option<Allocation>,
+    // Tick of the last write to the cell.
+    pub written: Option<usize>,
+}
+
+// Strings shorter than this are too likely to be numbers that happen to be printable.
+const MIN_STRING: usize = 2;
+
+// Printable ASCII and the whitespace strings usually carry.
+fn is_printable(value: i32) -> bool {
+    (32..127).contains(&value) || [9, 10, 13].contains(&value)
+}
+
+pub trait HeapHolder {
     fn get_heap(&self) -> &[i32];
     fn get_heap_size(&self) -> usize;
     fn allocate(&mut self, size: usize) -> usize;
@@ -19,17 +56,80 @@ impl HeapHolder for Tvm {
     fn allocate(&mut self, size: usize) -> usize {
         let address = self.heap_size;
         self.heap_size += size;
+        self.allocations.push(Allocation { address, size });
         address
     }
 
     fn deallocate(&mut self, address: usize) {
         self.heap_size = address;
+        self.allocations.retain(|a| a.address < address);
+    }
+}
+
+impl Tvm {
+    /// Describes the heap cells in `range`, which is clipped to the heap.
+    pub fn heap_cells(&self, range: Range<usize>) -> Vec<HeapCell> {
+        let heap = self.get_heap();
+        let range = range.start.min(heap.len())..range.end.min(heap.len());
+        let mut cells: Vec<HeapCell> = range
+            .clone()
+            .map(|address| HeapCell {
+                address,
+                value: heap[address],
+                string: None,
+                in_string: false,
+                allocation: self.allocation_at(address),
+                written: self
+                    .writes
+                    .as_ref()
+                    .and_then(|writes| writes.get(&address))
+                    .copied(),
+            })
+            .collect();
+        // A string can start before the range, so the scan starts where its first cell's run does.
+        let mut start = range.start
+            - heap[..range.start]
+                .iter()
+                .rev()
+                .take_while(|v| is_printable(**v))
+                .count();
+        while start < range.end {
+            let end = start
+                + heap[start..]
+                    .iter()
+                    .take_while(|v| is_printable(**v))
+                    .count();
+            if end - start >= MIN_STRING && heap.get(end) == Some(&0) {
+                if start >= range.start {
+                    cells[start - range.start].string =
+                        Some(heap[start..end].iter().map(|v| *v as u8 as char).collect());
+                }
+                let first = start.max(range.start) - range.start;
+                let last = (end + 1).min(range.end) - range.start;
+                for cell in &mut cells[first..last] {
+                    cell.in_string = true;
+                }
+            }
+            start = end + 1;
+        }
+        cells
+    }
+
+    // Allocations are kept in address order and do not overlap.
+    fn allocation_at(&self, address: usize) -> Option<Allocation> {
+        let after = self.allocations.partition_point(|a| a.address <= address);
+        after
+            .checked_sub(1)
+            .map(|i| self.allocations[i])
+            .filter(|a| a.contains(address))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::program::Program;
+    use std::collections::HashMap;
 
     #[test]
     fn test_get_heap() {
@@ -53,6 +153,69 @@ mod test {
         assert_eq!(tvm.get_heap(), &[0, 0, 0]);
     }
 
+    #[test]
+    fn test_heap_cells() {
+        let mut tvm = Tvm {
+            writes: Some(HashMap::new()),
+            ..Tvm::default()
+        };
+        tvm.load(
+            Program::builder()
+                .heap_size(12)
+                .heap(vec![
+                    (0, 'h' as i32),
+                    (1, 'i' as i32),
+                    (3, 'x' as i32),
+                    (5, 7),
+                ])
+                .build(),
+        );
+        let block = tvm.allocate(4);
+        tvm.ticks = 9;
+        tvm.write_string(block, "ok".to_string());
+        let cells = tvm.heap_cells(0..100);
+        assert_eq!(cells.len(), 16);
+        assert_eq!(cells[0].string.as_deref(), Some("hi"));
+        assert!(cells[1].in_string && cells[2].in_string);
+        // A single printable cell is not taken for a string.
+        assert!(!cells[3].in_string);
+        assert_eq!(cells[12].string.as_deref(), Some("ok"));
+        assert_eq!(
+            cells[12].allocation,
+            Some(Allocation {
+                address: 12,
+                size: 4
+            })
+        );
+        assert_eq!(cells[14].written, Some(9));
+        assert_eq!(cells[15].written, None);
+        assert_eq!(cells[11].allocation, None);
+
+        // Part of the heap is described as it is in the whole.
+        assert_eq!(tvm.heap_cells(1..13), cells[1..13]);
+        assert_eq!(tvm.heap_cells(13..14), cells[13..14]);
+    }
+
+    #[test]
+    fn test_free() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_source(
+                "fun init() {\n    var a\n    a : alloc(3)\n    alloc(2)\n    free(.a)\n}",
+            )
+            .unwrap(),
+        );
+        assert!(tvm.run(1000));
+        assert_eq!(tvm.heap_size, 5);
+        assert_eq!(
+            tvm.allocations,
+            vec![Allocation {
+                address: 3,
+                size: 2
+            }]
+        );
+    }
+
     #[test]
     fn test_deallocate() {
         let mut tvm = Tvm::default();
@@ -1,5 +1,6 @@
 use crate::callable::Caller;
 use crate::frame::{Frame, FrameData};
+use crate::log::{Category, Level};
 use crate::stack::StackHolder;
 use crate::state::{StateHolder, StateResult};
 use crate::tvm::Tvm;
@@ -154,145 +155,293 @@ impl Display for Instruction {
 impl Instruction {
     pub fn get_instruction(op_code: u32) -> Instruction {
         match op_code {
-            1 => Instruction::Push {
-                op: 1,
-                name: "push".to_string(),
-                num_operands: 0,
-            },
-            2 => Instruction::Fetch {
-                op: 2,
-                name: "fetch".to_string(),
-                num_operands: 0,
-            },
-            3 => Instruction::Store {
-                op: 3,
-                name: "store".to_string(),
-                num_operands: 0,
-            },
-            4 => Instruction::IF {
-                op: 4,
-                name: "if".to_string(),
-                num_operands: 0,
-            },
-            5 => Instruction::Loop {
-                op: 5,
-                name: "loop".to_string(),
-                num_operands: 0,
-            },
-            6 => Instruction::Break {
-                op: 6,
-                name: "break".to_string(),
-                num_operands: 0,
-            },
-            7 => Instruction::Return {
-                op: 7,
-                name: "return".to_string(),
-                num_operands: 0,
-            },
-            8 => Instruction::Call {
-                op: 8,
-                name: "call".to_string(),
-                num_operands: 0,
-            },
-            9 => Instruction::FPPlus {
-                op: 9,
-                name: "fp+".to_string(),
-                num_operands: 0,
-            },
-            10 => Instruction::Add {
-                op: 10,
-                name: "+".to_string(),
-                num_operands: 0,
-            },
-            11 => Instruction::Sub {
-                op: 11,
-                name: "-".to_string(),
-                num_operands: 0,
-            },
-            12 => Instruction::Mul {
-                op: 12,
-                name: "*".to_string(),
-                num_operands: 0,
-            },
-            13 => Instruction::Div {
-                op: 13,
-                name: "/".to_string(),
-                num_operands: 0,
-            },
-            14 => Instruction::Mod {
-                op: 14,
-                name: "%".to_string(),
-                num_operands: 0,
-            },
-            15 => Instruction::Not {
-                op: 15,
-                name: "!".to_string(),
-                num_operands: 0,
-            },
-            16 => Instruction::And {
-                op: 16,
-                name: "&".to_string(),
-                num_operands: 0,
-            },
-            17 => Instruction::OR {
-                op: 17,
-                name: "|".to_string(),
-                num_operands: 0,
-            },
-            18 => Instruction::Xor {
-                op: 18,
-                name: "^".to_string(),
-                num_operands: 0,
-            },
-            19 => Instruction::EQ {
-                op: 19,
-                name: "==".to_string(),
-                num_operands: 0,
-            },
-            20 => Instruction::Neq {
-                op: 20,
-                name: "!=".to_string(),
-                num_operands: 0,
-            },
-            21 => Instruction::LT {
-                op: 21,
-                name: "<".to_string(),
-                num_operands: 0,
-            },
-            22 => Instruction::Leq {
-                op: 22,
-                name: "<=".to_string(),
-                num_operands: 0,
-            },
-            23 => Instruction::GT {
-                op: 23,
-                name: ">".to_string(),
-                num_operands: 0,
-            },
-            24 => Instruction::Geq {
-                op: 24,
-                name: ">=".to_string(),
-                num_operands: 0,
-            },
-            25 => Instruction::Pop {
-                op: 25,
-                name: "pop".to_string(),
-                num_operands: 0,
-            },
-            26 => Instruction::LShift {
-                op: 26,
-                name: "<<".to_string(),
-                num_operands: 0,
-            },
-            27 => Instruction::RShift {
-                op: 27,
-                name: ">>".to_string(),
-                num_operands: 0,
-            },
+            1 => Instruction::push(),
+            2 => Instruction::fetch(),
+            3 => Instruction::store(),
+            4 => Instruction::if_(),
+            5 => Instruction::loop_(),
+            6 => Instruction::break_(),
+            7 => Instruction::return_(),
+            8 => Instruction::call(),
+            9 => Instruction::fp_plus(),
+            10 => Instruction::add(),
+            11 => Instruction::sub(),
+            12 => Instruction::mul(),
+            13 => Instruction::div(),
+            14 => Instruction::mod_(),
+            15 => Instruction::not(),
+            16 => Instruction::and(),
+            17 => Instruction::or(),
+            18 => Instruction::xor(),
+            19 => Instruction::eq(),
+            20 => Instruction::neq(),
+            21 => Instruction::lt(),
+            22 => Instruction::leq(),
+            23 => Instruction::gt(),
+            24 => Instruction::geq(),
+            25 => Instruction::pop(),
+            26 => Instruction::lshift(),
+            27 => Instruction::rshift(),
             _ => Instruction::Unknown(op_code),
         }
     }
 
+    pub fn push() -> Instruction {
+        Instruction::Push {
+            op: 1,
+            name: "push".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn fetch() -> Instruction {
+        Instruction::Fetch {
+            op: 2,
+            name: "fetch".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn store() -> Instruction {
+        Instruction::Store {
+            op: 3,
+            name: "store".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn if_() -> Instruction {
+        Instruction::IF {
+            op: 4,
+            name: "if".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn loop_() -> Instruction {
+        Instruction::Loop {
+            op: 5,
+            name: "loop".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn break_() -> Instruction {
+        Instruction::Break {
+            op: 6,
+            name: "break".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn return_() -> Instruction {
+        Instruction::Return {
+            op: 7,
+            name: "return".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn call() -> Instruction {
+        Instruction::Call {
+            op: 8,
+            name: "call".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn fp_plus() -> Instruction {
+        Instruction::FPPlus {
+            op: 9,
+            name: "fp+".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn add() -> Instruction {
+        Instruction::Add {
+            op: 10,
+            name: "+".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn sub() -> Instruction {
+        Instruction::Sub {
+            op: 11,
+            name: "-".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn mul() -> Instruction {
+        Instruction::Mul {
+            op: 12,
+            name: "*".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn div() -> Instruction {
+        Instruction::Div {
+            op: 13,
+            name: "/".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn mod_() -> Instruction {
+        Instruction::Mod {
+            op: 14,
+            name: "%".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn not() -> Instruction {
+        Instruction::Not {
+            op: 15,
+            name: "!".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn and() -> Instruction {
+        Instruction::And {
+            op: 16,
+            name: "&".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn or() -> Instruction {
+        Instruction::OR {
+            op: 17,
+            name: "|".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn xor() -> Instruction {
+        Instruction::Xor {
+            op: 18,
+            name: "^".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn eq() -> Instruction {
+        Instruction::EQ {
+            op: 19,
+            name: "==".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn neq() -> Instruction {
+        Instruction::Neq {
+            op: 20,
+            name: "!=".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn lt() -> Instruction {
+        Instruction::LT {
+            op: 21,
+            name: "<".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn leq() -> Instruction {
+        Instruction::Leq {
+            op: 22,
+            name: "<=".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn gt() -> Instruction {
+        Instruction::GT {
+            op: 23,
+            name: ">".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn geq() -> Instruction {
+        Instruction::Geq {
+            op: 24,
+            name: ">=".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn pop() -> Instruction {
+        Instruction::Pop {
+            op: 25,
+            name: "pop".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn lshift() -> Instruction {
+        Instruction::LShift {
+            op: 26,
+            name: "<<".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn rshift() -> Instruction {
+        Instruction::RShift {
+            op: 27,
+            name: ">>".to_string(),
+            num_operands: 0,
+        }
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Instruction> {
+        (1..=27)
+            .map(Instruction::get_instruction)
+            .find(|instruction| instruction.mnemonic().eq_ignore_ascii_case(mnemonic))
+    }
+
+    // The upper case name used by the assembly listings.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Push { .. } => "PUSH",
+            Instruction::Fetch { .. } => "FETCH",
+            Instruction::Store { .. } => "STORE",
+            Instruction::IF { .. } => "IF",
+            Instruction::Loop { .. } => "LOOP",
+            Instruction::Break { .. } => "BREAK",
+            Instruction::Return { .. } => "RETURN",
+            Instruction::Call { .. } => "CALL",
+            Instruction::FPPlus { .. } => "FPPLUS",
+            Instruction::Add { .. } => "ADD",
+            Instruction::Sub { .. } => "SUB",
+            Instruction::Mul { .. } => "MUL",
+            Instruction::Div { .. } => "DIV",
+            Instruction::Mod { .. } => "MOD",
+            Instruction::Not { .. } => "NOT",
+            Instruction::And { .. } => "AND",
+            Instruction::OR { .. } => "OR",
+            Instruction::Xor { .. } => "XOR",
+            Instruction::EQ { .. } => "EQ",
+            Instruction::Neq { .. } => "NEQ",
+            Instruction::LT { .. } => "LT",
+            Instruction::Leq { .. } => "LEQ",
+            Instruction::GT { .. } => "GT",
+            Instruction::Geq { .. } => "GEQ",
+            Instruction::Pop { .. } => "POP",
+            Instruction::LShift { .. } => "LSHIFT",
+            Instruction::RShift { .. } => "RSHIFT",
+            Instruction::Unknown(_) => "UNKNOWN",
+        }
+    }
+
     pub fn op(&self) -> u32 {
         match self {
             Instruction::Push { op, .. } => *op,
@@ -370,12 +519,22 @@ impl Evaluator for Tvm {
         if frame.pc >= frame.data.len() {
             if self.state.check_in_loop() {
                 // println!("loop detected");
-                self.log.push_str("loop_detected\n");
+                self.log.push(
+                    self.ticks,
+                    Level::Debug,
+                    Category::State,
+                    format_args!("loop_detected"),
+                );
                 frame.pc = 0;
                 self.state.set_result(StateResult::Continue)
             } else {
                 // println!("program finished");
-                self.log.push_str("Attempting to exit frame\n");
+                self.log.push(
+                    self.ticks,
+                    Level::Debug,
+                    Category::State,
+                    format_args!("Attempting to exit frame"),
+                );
                 self.state.set_result(StateResult::Exit);
             }
             return;
@@ -387,7 +546,12 @@ impl Evaluator for Tvm {
             FrameData::Frame(frame) => self.frame_eval(frame.clone()),
             FrameData::Instruction(instruction, ..) => {
                 // println!("Evaluating instruction: {}", instruction);
-                self.log.push_str(format!("Evaluating instruction: {}\n", instruction).as_str());
+                self.log.push(
+                    self.ticks,
+                    Level::Trace,
+                    Category::State,
+                    format_args!("Evaluating instruction: {}", instruction),
+                );
                 match instruction {
                     Instruction::Push { .. } => {
                         let x = &frame.data[frame.pc].get_id();
@@ -401,7 +565,7 @@ impl Evaluator for Tvm {
                     Instruction::Store { .. } => {
                         let value = self.pop();
                         let index = self.pop();
-                        self.memory[index as usize] = value;
+                        self.store(index as usize, value);
                     }
                     Instruction::IF { .. } => {
                         let condition = self.pop();
@@ -409,10 +573,21 @@ impl Evaluator for Tvm {
                             .expect("could not get next frame");
                         next_frame.name = "if-".to_string();
                         next_frame.name.push_str(&frame.name);
-                        self.log.push_str(format!("next frame: {}\n", next_frame.name).as_str());
-                        self.log.push_str(format!("condition: {}\n", condition).as_str());
+                        self.log.push(
+                            self.ticks,
+                            Level::Debug,
+                            Category::State,
+                            format_args!("next frame: {}", next_frame.name),
+                        );
+                        self.log.push(
+                            self.ticks,
+                            Level::Debug,
+                            Category::State,
+                            format_args!("condition: {}", condition),
+                        );
                         if condition != 0 {
                             next_frame.name.push_str("-0");
+                            next_frame.index = frame.pc;
                             frame.pc += 2;
                             self.frame_eval(next_frame);
                         } else {
@@ -422,6 +597,7 @@ impl Evaluator for Tvm {
                             next_frame.name = "if-".to_string();
                             next_frame.name.push_str(&frame.name);
                             next_frame.name.push_str("-1");
+                            next_frame.index = frame.pc + 1;
                             frame.pc += 2;
                             // frame.pc += 1;
                             self.frame_eval(next_frame);
@@ -439,6 +615,7 @@ impl Evaluator for Tvm {
                             .expect("could not get next frame for loop");
                         next_frame.name = "loop-".to_string();
                         next_frame.name.push_str(&frame.name);
+                        next_frame.index = frame.pc;
                         // frame.pc += 1;
                         self.frame_eval(next_frame)
                     }
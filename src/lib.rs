@@ -0,0 +1,70 @@
+//! A virtual machine for tvm tapes, the stack machine programs run by `tvm.js`.
+//!
+//! A [`Program`] is loaded from a JSON or binary tape, an assembly listing or Tranquility
+//! source, or put together with [`ProgramBuilder`]. A [`Tvm`] then runs it a tick at a time:
+//!
+//! ```
+//! use tvm_rs_2::{Program, Tvm};
+//!
+//! let program = Program::from_source("fun init() {\n    iprint(6 * 7)\n}").unwrap();
+//! let mut tvm = Tvm::default();
+//! tvm.load(program);
+//! assert!(tvm.run(10_000));
+//! assert_eq!(tvm.stdout, "42");
+//! ```
+//!
+//! Single functions can be called with arguments, which is handy for testing them one at a time:
+//!
+//! ```
+//! # use tvm_rs_2::{Program, Tvm};
+//! let mut tvm = Tvm::default();
+//! tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+//! assert_eq!(tvm.call_function("sq", &[7], 1000), Ok(49));
+//! ```
+//!
+//! [`Tvm::step`] advances a single tick, which is what the debuggers build on. The terminal
+//! debugger is behind the default `tui` feature; build with `default-features = false` to use
+//! the machine without the terminal stack.
+
+pub mod assembler;
+pub mod batch;
+pub mod binary;
+pub mod call_stack;
+pub mod callable;
+pub mod compiler;
+pub mod dap;
+pub mod debug_info;
+pub mod differential;
+pub mod disassembler;
+pub mod frame;
+pub mod function;
+pub mod golden;
+pub mod heap;
+pub mod instruction;
+pub mod log;
+pub mod native;
+pub mod optimizer;
+pub mod program;
+pub mod program_parser;
+pub mod reference;
+pub mod repl;
+pub mod rpc;
+pub mod stack;
+pub mod state;
+pub mod state_utils;
+#[cfg(test)]
+mod test_utils;
+pub mod tvm;
+#[cfg(feature = "tui")]
+pub mod ui;
+pub mod verifier;
+
+pub use batch::{BatchRunner, Job, JobResult};
+pub use callable::Callable;
+pub use frame::{Frame, FrameBuilder, FrameData};
+pub use function::{Function, FunctionBuilder};
+pub use instruction::Instruction;
+pub use native::NativeFunction;
+pub use program::{Program, ProgramBuilder};
+pub use state::{StateHolder, StateStack, TvmState};
+pub use tvm::{FunctionRef, Tvm, TvmError};
@@ -0,0 +1,281 @@
+use std::collections::VecDeque;
+use std::fmt::{Arguments, Display, Formatter};
+
+// What the machine did, tick by tick, for the debugger's log pane. Records are kept in a ring
+// buffer, so the oldest are dropped once it is full. A log with no capacity keeps nothing and
+// skips formatting entirely, which is how headless runs use it.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    // Every push, pop and instruction.
+    #[default]
+    Trace,
+    // State changes.
+    Debug,
+    // Resets and edits made from the debugger.
+    Info,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Stack,
+    State,
+    Call,
+    Native,
+    // Things done to the machine from outside, like resets and edits.
+    Debugger,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Stack,
+        Category::State,
+        Category::Call,
+        Category::Native,
+        Category::Debugger,
+    ];
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+        };
+        f.pad(name)
+    }
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Category::Stack => "stack",
+            Category::State => "state",
+            Category::Call => "call",
+            Category::Native => "native",
+            Category::Debugger => "debugger",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub tick: usize,
+    pub level: Level,
+    pub category: Category,
+    pub message: String,
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>6} {:<5} {:<8} {}",
+            self.tick, self.level, self.category, self.message
+        )
+    }
+}
+
+/// Which records to show: the categories wanted, the lowest level and text to look for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    pub categories: Vec<Category>,
+    pub level: Level,
+    pub search: String,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            categories: Category::ALL.to_vec(),
+            level: Level::Trace,
+            search: String::new(),
+        }
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        record.level >= self.level
+            && self.categories.contains(&record.category)
+            && (self.search.is_empty() || record.message.contains(&self.search))
+    }
+
+    // Shows or hides a category.
+    pub fn toggle(&mut self, category: Category) {
+        match self.categories.iter().position(|c| *c == category) {
+            Some(i) => {
+                self.categories.remove(i);
+            }
+            None => self.categories.push(category),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Log {
+    records: VecDeque<Record>,
+    capacity: usize,
+    // Records below this level are not kept.
+    pub level: Level,
+    // Records pushed out by newer ones since the log was last cleared.
+    pub dropped: usize,
+}
+
+impl Log {
+    pub const DEFAULT_CAPACITY: usize = 10_000;
+
+    /// A log keeping the last `capacity` records.
+    pub fn new(capacity: usize) -> Self {
+        Log {
+            capacity,
+            ..Log::default()
+        }
+    }
+
+    /// A log that keeps nothing.
+    pub fn off() -> Self {
+        Log::default()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the size cap, dropping the oldest records if there are now too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    pub fn is_enabled(&self, level: Level) -> bool {
+        self.capacity > 0 && level >= self.level
+    }
+
+    /// Adds a record. The message is only formatted if the log keeps it.
+    pub fn push(&mut self, tick: usize, level: Level, category: Category, message: Arguments) {
+        if !self.is_enabled(level) {
+            return;
+        }
+        self.records.push_back(Record {
+            tick,
+            level,
+            category,
+            message: message.to_string(),
+        });
+        self.trim();
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.dropped = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The records kept, oldest first.
+    pub fn records(&self) -> impl DoubleEndedIterator<Item = &Record> + ExactSizeIterator {
+        self.records.iter()
+    }
+
+    pub fn filtered<'a>(
+        &'a self,
+        filter: &'a Filter,
+    ) -> impl DoubleEndedIterator<Item = &'a Record> {
+        self.records.iter().filter(|record| filter.matches(record))
+    }
+
+    fn trim(&mut self) {
+        while self.records.len() > self.capacity {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+    }
+}
+
+impl Display for Log {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for record in &self.records {
+            writeln!(f, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(log: &mut Log, tick: usize, level: Level, category: Category, message: &str) {
+        log.push(tick, level, category, format_args!("{}", message));
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut log = Log::new(3);
+        for tick in 0..5 {
+            push(&mut log, tick, Level::Trace, Category::Stack, "push");
+        }
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.dropped, 2);
+        assert_eq!(log.records().next().unwrap().tick, 2);
+
+        log.set_capacity(1);
+        assert_eq!(log.records().map(|r| r.tick).collect::<Vec<_>>(), [4]);
+        assert_eq!(log.dropped, 4);
+        assert_eq!(log.to_string(), "     4 trace stack    push\n");
+    }
+
+    #[test]
+    fn test_off_and_level() {
+        let mut log = Log::off();
+        push(&mut log, 0, Level::Info, Category::Debugger, "Reset");
+        assert!(log.is_empty());
+
+        let mut log = Log::new(10);
+        log.level = Level::Debug;
+        push(&mut log, 0, Level::Trace, Category::Stack, "push");
+        push(&mut log, 0, Level::Debug, Category::State, "tick");
+        assert_eq!(log.len(), 1);
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut log = Log::new(10);
+        push(
+            &mut log,
+            0,
+            Level::Trace,
+            Category::Stack,
+            "Pushing 1 to stack",
+        );
+        push(&mut log, 0, Level::Debug, Category::State, "Tick 0");
+        push(
+            &mut log,
+            1,
+            Level::Trace,
+            Category::Stack,
+            "Popping 1 from stack",
+        );
+        push(&mut log, 1, Level::Info, Category::Debugger, "Reset");
+
+        let mut filter = Filter::default();
+        assert_eq!(log.filtered(&filter).count(), 4);
+        filter.toggle(Category::Stack);
+        assert_eq!(log.filtered(&filter).count(), 2);
+        filter.toggle(Category::Stack);
+        filter.search = "Popping".to_string();
+        assert_eq!(log.filtered(&filter).next().unwrap().tick, 1);
+        filter.search.clear();
+        filter.level = Level::Debug;
+        assert_eq!(log.filtered(&filter).count(), 2);
+    }
+}
@@ -1,174 +1,168 @@
-extern crate core;
+use std::error::Error;
+use std::io::{self, BufReader, IsTerminal};
 
-use crossterm::event::KeyModifiers;
-use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
-};
+use tvm_rs_2::dap::DapServer;
+use tvm_rs_2::repl::Repl;
+use tvm_rs_2::rpc::RpcServer;
+use tvm_rs_2::{differential, golden, Program, Tvm};
 
-use std::{error::Error, io};
-
-use tui::layout::Direction;
-use tui::widgets::{List, ListState, Paragraph};
-use tui::{
-    backend::{Backend, CrosstermBackend},
-    layout::{Constraint, Layout},
-    style::{Color, Modifier, Style},
-    widgets::{Block, Borders, Cell, Row, Table},
-    Frame, Terminal,
-};
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.as_slice() {
+        [_, command, path] if command == "disasm" => {
+            print!("{}", load_program(path.clone())?.to_assembly());
+            Ok(())
+        }
+        [_, command, path] if command == "verify" => {
+            verify(&load_program(path.clone())?)?;
+            eprintln!("{}: ok", path);
+            Ok(())
+        }
+        [_, command, input, output] if command == "convert" => {
+            let program = load_program(input.clone())?;
+            if output.ends_with(".json") {
+                program.write_file(output.clone())?;
+            } else {
+                program.write_binary_file(output.clone())?;
+            }
+            Ok(())
+        }
+        [_, command, path, flags @ ..] if command == "optimize" => {
+            optimize(path.clone(), flags.iter().any(|f| f == "--verify"))
+        }
+        [_, command, path] if command == "run" => run(path.clone()),
+        [_, command, path, script @ ..] if command == "repl" && script.len() <= 1 => {
+            repl(path.clone(), script.first())
+        }
+        [_, command] if command == "dap" => {
+            DapServer::new().serve(io::stdin().lock(), &mut io::stdout())?;
+            Ok(())
+        }
+        [_, command, address @ ..] if command == "rpc" && address.len() <= 1 => {
+            let address = address.first().map_or("127.0.0.1:7700", |a| a.as_str());
+            eprintln!("listening on {}", address);
+            RpcServer::new().listen(address)?;
+            Ok(())
+        }
+        [_, command, flag, count] if command == "differential" && flag == "--generate" => {
+            differential_generated(count.parse()?)
+        }
+        [_, command, path] if command == "differential" => {
+            let steps = differential::compare(load_program(path.clone())?, "", 100_000_000)?;
+            eprintln!("{}: {} instructions agree", path, steps);
+            Ok(())
+        }
+        [_, command, rest @ ..] if command == "test" => {
+            let bless = rest.iter().any(|a| a == "--bless");
+            let dir = rest
+                .iter()
+                .find(|a| *a != "--bless")
+                .map_or(".", |d| d.as_str());
+            test(dir, bless)
+        }
+        _ => debug(
+            args.get(1)
+                .cloned()
+                .unwrap_or_else(|| "sq.json".to_string()),
+        ),
+    }
+}
 
-use crate::program::Program;
-use crate::state::StateHolder;
-use crate::tvm::Tvm;
+// Opens a tape in the terminal debugger.
+#[cfg(feature = "tui")]
+fn debug(path: String) -> Result<(), Box<dyn Error>> {
+    let program = load_program(path.clone())?;
+    verify(&program)?;
+    let mut tvm = Tvm::default();
+    tvm.load(program);
+    tvm_rs_2::ui::run(&mut tvm, &path)
+}
 
-mod callable;
-mod frame;
-mod function;
-mod heap;
-mod instruction;
-mod native;
-mod program;
-mod stack;
-mod state;
-mod state_utils;
-mod tvm;
-mod ui;
-mod program_parser;
+// Built without the debugger, tapes are run to completion instead.
+#[cfg(not(feature = "tui"))]
+fn debug(path: String) -> Result<(), Box<dyn Error>> {
+    run(path)
+}
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let program = Program::from_file("sq.json".to_string());
+// Runs a tape to completion without the debugger, printing its output.
+fn run(path: String) -> Result<(), Box<dyn Error>> {
+    let program = load_program(path)?;
+    verify(&program)?;
     let mut tvm = Tvm::default();
     tvm.load(program);
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-    let res = run_tvm(&mut terminal, &mut tvm);
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-    if let Err(err) = res {
-        eprintln!("Error: {}", err);
-    }
+    tvm.run(usize::MAX);
+    print!("{}", tvm.stdout);
     Ok(())
 }
 
-fn run_tvm<B: Backend>(terminal: &mut Terminal<B>, tvm: &mut Tvm) -> io::Result<()> {
-    loop {
-        terminal.draw(|f| ui(f, tvm))?;
-        if let Event::Key(key) = event::read()? {
-            match (key.code, key.modifiers) {
-                (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(()),
-                (KeyCode::Char('u'), KeyModifiers::CONTROL) => tvm.update_table_state(),
-                (KeyCode::Char('t'), KeyModifiers::NONE) => {
-                    tvm.tick();
-                    tvm.update_table_state();
-                }
-                (KeyCode::Char('s'), KeyModifiers::NONE) => {
-                    tvm.start();
-                    tvm.update_table_state();
-                }
-                (KeyCode::Char('r'), KeyModifiers::NONE) => {
-                    tvm.reset();
-                    tvm.update_table_state();
-                }
-                _ => {}
-            }
+// Debugs a tape a line at a time, running a command file first if one is given.
+fn repl(path: String, script: Option<&String>) -> Result<(), Box<dyn Error>> {
+    let program = load_program(path)?;
+    verify(&program)?;
+    let mut tvm = Tvm::default();
+    tvm.load(program);
+    let mut repl = Repl::new(tvm);
+    let mut out = io::stdout();
+    if let Some(script) = script {
+        let file = std::fs::File::open(script)?;
+        if repl.run(BufReader::new(file), &mut out, true)? {
+            return Ok(());
         }
     }
+    // Piped commands are echoed so the transcript reads like a terminal session.
+    let stdin = io::stdin();
+    repl.run(stdin.lock(), &mut out, !stdin.is_terminal())?;
+    Ok(())
 }
 
-fn ui<B: Backend>(f: &mut Frame<B>, tvm: &mut Tvm) {
-    let main_layout = Layout::default()
-        .constraints(
-            [
-                Constraint::Percentage(15),
-                Constraint::Percentage(45),
-                Constraint::Percentage(40),
-            ]
-            .as_ref(),
-        )
-        .margin(3)
-        .direction(Direction::Horizontal)
-        .split(f.size());
-
-    let selected_style = Style::default().add_modifier(Modifier::REVERSED);
-    let normal_style = Style::default().bg(Color::Blue);
-    let header_cells = ["Address", "Value"]
-        .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Red)));
-    let header = Row::new(header_cells)
-        .style(normal_style)
-        .height(1)
-        .bottom_margin(1);
-    let stack = tvm.get_stack_vec();
-    let rows = stack.iter().map(|(k, v)| {
-        let cells = vec![Cell::from(k.to_string()), Cell::from(v.to_string())];
-        Row::new(cells)
-    });
-    let t = Table::new(rows)
-        .header(header)
-        .block(Block::default().borders(Borders::ALL).title("Stack"))
-        .highlight_style(selected_style)
-        .highlight_symbol(">> ")
-        .widths(&[
-            Constraint::Percentage(50),
-            Constraint::Length(30),
-            Constraint::Min(10),
-        ]);
-
-    let state_layout = Layout::default()
-        .constraints([Constraint::Percentage(10), Constraint::Percentage(90)].as_ref())
-        .margin(0)
-        .direction(Direction::Vertical)
-        .split(main_layout[1]);
-
-    let state = Paragraph::new(tvm.state.get_name()).block(
-        Block::default()
-            .borders(Borders::ALL)
-            .title("Current State"),
-    );
+fn load_program(path: String) -> Result<Program, Box<dyn Error>> {
+    Program::from_path(&path)
+}
 
-    let state_history = List::new(Tvm::state_history_to_list_items(&tvm.state_history))
-        .block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title("State History"),
-        )
-        .highlight_style(selected_style)
-        .highlight_symbol(">> ");
+// Reports every problem found by the static verifier on stderr.
+fn verify(program: &Program) -> Result<(), Box<dyn Error>> {
+    program.verify().map_err(|errors| {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        format!("tape failed verification with {} errors", errors.len()).into()
+    })
+}
 
-    let mut state_history_state = ListState::default();
-    if !tvm.state_history.is_empty() {
-        state_history_state.select(Some(tvm.state_history.len() - 1));
+// Runs the golden-output tests of a directory of tapes, or rewrites them with `bless`.
+fn test(dir: &str, bless: bool) -> Result<(), Box<dyn Error>> {
+    let report = golden::run_directory(std::path::Path::new(dir), bless, 10_000_000)?;
+    print!("{}", report);
+    if report.passed() {
+        Ok(())
+    } else {
+        Err("golden tests failed".into())
     }
+}
 
-    f.render_widget(state, state_layout[0]);
-    f.render_stateful_widget(state_history, state_layout[1], &mut state_history_state);
+// Checks generated programs against the reference interpreter, stopping at the first that
+// diverges.
+fn differential_generated(count: u64) -> Result<(), Box<dyn Error>> {
+    for seed in 0..count {
+        let program = differential::generate(seed);
+        if let Err(divergence) = differential::compare(program.clone(), "", 100_000_000) {
+            print!("{}", program.to_assembly());
+            return Err(format!("seed {} {}", seed, divergence).into());
+        }
+    }
+    eprintln!("{} generated programs agree", count);
+    Ok(())
+}
 
-    let output_layout = Layout::default()
-        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-        .margin(0)
-        .direction(Direction::Vertical)
-        .split(main_layout[2]);
-    let stdout = Paragraph::new(tvm.stdout.as_ref())
-        .block(Block::default().borders(Borders::ALL).title("Stdout"));
-    let log_items = Tvm::log_to_list_items(&tvm.log);
-    let log = List::new(log_items.clone())
-        .block(Block::default().borders(Borders::ALL).title("Log"))
-        .highlight_style(selected_style)
-        .highlight_symbol(">> ");
-    if !log_items.is_empty() {
-        tvm.log_state.select(Some(log_items.len() - 1));
+// Prints the optimized listing, with the instruction counts on stderr.
+fn optimize(path: String, verify: bool) -> Result<(), Box<dyn Error>> {
+    let program = load_program(path)?;
+    let (optimized, report) = program.optimize();
+    print!("{}", optimized.to_assembly());
+    eprint!("{}", report);
+    if verify {
+        program.verify_optimized(&optimized, 1_000_000)?;
+        eprintln!("verified: output matches");
     }
-    f.render_stateful_widget(t, main_layout[0], &mut tvm.table_state);
-    f.render_widget(stdout, output_layout[0]);
-    f.render_stateful_widget(log, output_layout[1], &mut tvm.log_state);
+    Ok(())
 }
@@ -78,12 +78,18 @@ impl NativeFunction {
             -111 => NativeFunction::I2S {
                 id,
                 name: "i2s".to_string(),
-                args: 1,
+                args: 2,
             },
             n => NativeFunction::Unknown(n),
         }
     }
 
+    pub fn from_name(name: &str) -> Option<Self> {
+        (-111..=-101)
+            .map(NativeFunction::get_native)
+            .find(|native| native.name() == name)
+    }
+
     pub fn id(&self) -> i32 {
         match self {
             NativeFunction::IPrint { id, .. } => *id,
@@ -117,4 +123,21 @@ impl NativeFunction {
             NativeFunction::Unknown(n) => format!("Unknown({})", n),
         }
     }
+
+    pub fn args(&self) -> u32 {
+        match self {
+            NativeFunction::IPrint { args, .. } => *args,
+            NativeFunction::SPrint { args, .. } => *args,
+            NativeFunction::IRead { args, .. } => *args,
+            NativeFunction::SRead { args, .. } => *args,
+            NativeFunction::NL { args, .. } => *args,
+            NativeFunction::Random { args, .. } => *args,
+            NativeFunction::Timer { args, .. } => *args,
+            NativeFunction::StopTimer { args, .. } => *args,
+            NativeFunction::Alloc { args, .. } => *args,
+            NativeFunction::Free { args, .. } => *args,
+            NativeFunction::I2S { args, .. } => *args,
+            NativeFunction::Unknown(_) => 0,
+        }
+    }
 }
@@ -0,0 +1,409 @@
+use crate::frame::{Frame, FrameData};
+use crate::instruction::Instruction;
+use crate::program::Program;
+use crate::tvm::Tvm;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+// Peephole optimizer, doing automatically what was done by hand for `sq-opt`:
+//
+//     PUSH 2; PUSH 3; ADD      ->  PUSH 5
+//     PUSH 7; NOT              ->  PUSH -8
+//     PUSH x; POP              ->
+//     NOT; NOT                 ->
+//     RETURN; ...              ->  RETURN
+//
+// Frames contain no jumps, so every rewrite is local to a frame. Nested frames are optimized
+// on their own. Rewrites are repeated until none applies.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionReport {
+    pub name: String,
+    pub before: usize,
+    pub after: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OptimizationReport {
+    pub functions: Vec<FunctionReport>,
+}
+
+impl Display for OptimizationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<16}{:>8}{:>8}", "function", "before", "after")?;
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:<16}{:>8}{:>8}",
+                function.name, function.before, function.after
+            )?;
+        }
+        let before: usize = self.functions.iter().map(|f| f.before).sum();
+        let after: usize = self.functions.iter().map(|f| f.after).sum();
+        writeln!(f, "{:<16}{:>8}{:>8}", "total", before, after)
+    }
+}
+
+// Output of the original and optimized program when they disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationError {
+    pub original: String,
+    pub optimized: String,
+}
+
+impl Display for VerificationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "optimized program diverges\noriginal:\n{}\noptimized:\n{}",
+            self.original, self.optimized
+        )
+    }
+}
+
+impl std::error::Error for VerificationError {}
+
+impl Program {
+    pub fn optimize(&self) -> (Program, OptimizationReport) {
+        let mut program = self.clone();
+        let mut report = OptimizationReport::default();
+        for function in &mut program.functions {
+            let function = Arc::make_mut(function);
+            let before = function.frame.instruction_count();
+            function.frame = optimize_frame(&function.frame);
+            report.functions.push(FunctionReport {
+                name: function.name.clone(),
+                before,
+                after: function.frame.instruction_count(),
+            });
+        }
+        (program, report)
+    }
+
+    // Runs this program and `optimized` for at most `max_ticks` each and compares their output.
+    pub fn verify_optimized(
+        &self,
+        optimized: &Program,
+        max_ticks: usize,
+    ) -> Result<(), VerificationError> {
+        let original = run(self, max_ticks);
+        let optimized = run(optimized, max_ticks);
+        if original == optimized {
+            Ok(())
+        } else {
+            Err(VerificationError {
+                original,
+                optimized,
+            })
+        }
+    }
+}
+
+fn run(program: &Program, max_ticks: usize) -> String {
+    let mut tvm = Tvm::default();
+    tvm.load(program.clone());
+    if !tvm.run(max_ticks) {
+        tvm.stdout.push_str("<tick limit reached>");
+    }
+    tvm.stdout
+}
+
+impl Frame {
+    // Counts instructions in this frame and its nested frames. Operands are not counted.
+    pub fn instruction_count(&self) -> usize {
+        decode(self)
+            .iter()
+            .map(|(op, _)| match op {
+                Op::If(then, otherwise) => {
+                    1 + then.instruction_count() + otherwise.instruction_count()
+                }
+                Op::Loop(body) => 1 + body.instruction_count(),
+                Op::Frame(frame) => frame.instruction_count(),
+                Op::Word(_) => 0,
+                _ => 1,
+            })
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op {
+    Push(i32),
+    Call(i32),
+    If(Frame, Frame),
+    Loop(Frame),
+    Frame(Frame),
+    Instruction(Instruction),
+    // A word that is not an instruction, left as is.
+    Word(FrameData),
+}
+
+// Each op keeps the source lines of its words, empty when the frame has none.
+type Ops = Vec<(Op, Vec<usize>)>;
+
+fn decode(frame: &Frame) -> Ops {
+    let mut ops = Vec::new();
+    let mut pc = 0;
+    let data = &frame.data;
+    while pc < data.len() {
+        let start = pc;
+        let nested = |i: usize| match data.get(i) {
+            Some(FrameData::Frame(frame)) => Some(frame.clone()),
+            _ => None,
+        };
+        let op = match &data[pc] {
+            FrameData::Frame(frame) => Op::Frame(frame.clone()),
+            FrameData::Instruction(Instruction::Push { .. }, _) if pc + 1 < data.len() => {
+                pc += 1;
+                Op::Push(data[pc].get_id())
+            }
+            FrameData::Instruction(Instruction::Call { .. }, _) if pc + 1 < data.len() => {
+                pc += 1;
+                Op::Call(data[pc].get_id())
+            }
+            FrameData::Instruction(instruction @ Instruction::IF { .. }, _) => {
+                match (nested(pc + 1), nested(pc + 2)) {
+                    (Some(then), Some(otherwise)) => {
+                        pc += 2;
+                        Op::If(then, otherwise)
+                    }
+                    _ => Op::Instruction(instruction.clone()),
+                }
+            }
+            FrameData::Instruction(instruction @ Instruction::Loop { .. }, _) => {
+                match nested(pc + 1) {
+                    Some(body) => {
+                        pc += 1;
+                        Op::Loop(body)
+                    }
+                    None => Op::Instruction(instruction.clone()),
+                }
+            }
+            FrameData::Instruction(instruction, _) => Op::Instruction(instruction.clone()),
+            other => Op::Word(other.clone()),
+        };
+        pc += 1;
+        let lines = frame.lines.get(start..pc).unwrap_or_default().to_vec();
+        ops.push((op, lines));
+    }
+    ops
+}
+
+fn encode(template: &Frame, ops: Ops) -> Frame {
+    let mut data = Vec::new();
+    let mut lines = Vec::new();
+    for (op, op_lines) in ops {
+        match op {
+            Op::Push(value) => data.extend([FrameData::from(1), FrameData::from(value)]),
+            Op::Call(id) => data.extend([FrameData::from(8), FrameData::from(id)]),
+            Op::If(then, otherwise) => data.extend([
+                FrameData::from(4),
+                FrameData::Frame(then),
+                FrameData::Frame(otherwise),
+            ]),
+            Op::Loop(body) => data.extend([FrameData::from(5), FrameData::Frame(body)]),
+            Op::Frame(frame) => data.push(FrameData::Frame(frame)),
+            Op::Instruction(instruction) => data.push(FrameData::from(instruction.op() as i32)),
+            Op::Word(word) => data.push(word),
+        }
+        lines.extend(op_lines);
+    }
+    Frame {
+        data,
+        lines,
+        pc: 0,
+        ..template.clone()
+    }
+}
+
+fn optimize_frame(frame: &Frame) -> Frame {
+    let mut ops: Ops = decode(frame)
+        .into_iter()
+        .map(|(op, lines)| {
+            let op = match op {
+                Op::If(then, otherwise) => {
+                    Op::If(optimize_frame(&then), optimize_frame(&otherwise))
+                }
+                Op::Loop(body) => Op::Loop(optimize_frame(&body)),
+                Op::Frame(nested) => Op::Frame(optimize_frame(&nested)),
+                op => op,
+            };
+            (op, lines)
+        })
+        .collect();
+    while rewrite(&mut ops) {}
+    encode(frame, ops)
+}
+
+// Applies the first rewrite found, returning whether there was one.
+fn rewrite(ops: &mut Ops) -> bool {
+    if let Some(ret) = ops
+        .iter()
+        .position(|(op, _)| matches!(op, Op::Instruction(Instruction::Return { .. })))
+    {
+        if ret + 1 < ops.len() {
+            ops.truncate(ret + 1);
+            return true;
+        }
+    }
+    for i in 0..ops.len() {
+        let window: Vec<&Op> = ops[i..].iter().take(3).map(|(op, _)| op).collect();
+        match window.as_slice() {
+            [Op::Push(_), Op::Instruction(Instruction::Pop { .. }), ..] => {
+                ops.drain(i..i + 2);
+                return true;
+            }
+            [Op::Instruction(Instruction::Not { .. }), Op::Instruction(Instruction::Not { .. }), ..] =>
+            {
+                ops.drain(i..i + 2);
+                return true;
+            }
+            [Op::Push(x), Op::Instruction(Instruction::Not { .. }), ..] => {
+                let value = !*x;
+                let lines = ops[i].1.clone();
+                ops.splice(i..i + 2, [(Op::Push(value), lines)]);
+                return true;
+            }
+            [Op::Push(x), Op::Push(y), Op::Instruction(instruction)] => {
+                if let Some(value) = fold(instruction, *x, *y) {
+                    let lines = ops[i].1.clone();
+                    ops.splice(i..i + 3, [(Op::Push(value), lines)]);
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+// Evaluates a binary instruction the way the VM would, unless it would fault at run time.
+fn fold(instruction: &Instruction, x: i32, y: i32) -> Option<i32> {
+    Some(match instruction {
+        Instruction::Add { .. } => x.checked_add(y)?,
+        Instruction::Sub { .. } => x.checked_sub(y)?,
+        Instruction::Mul { .. } => x.checked_mul(y)?,
+        Instruction::Div { .. } => x.checked_div(y)?,
+        Instruction::Mod { .. } => x.checked_rem(y)?,
+        Instruction::And { .. } => x & y,
+        Instruction::OR { .. } => x | y,
+        Instruction::Xor { .. } => x ^ y,
+        Instruction::EQ { .. } => (x == y) as i32,
+        Instruction::Neq { .. } => (x != y) as i32,
+        Instruction::LT { .. } => (x < y) as i32,
+        Instruction::Leq { .. } => (x <= y) as i32,
+        Instruction::GT { .. } => (x > y) as i32,
+        Instruction::Geq { .. } => (x >= y) as i32,
+        Instruction::LShift { .. } => x.checked_shl(u32::try_from(y).ok()?)?,
+        Instruction::RShift { .. } => x.checked_shr(u32::try_from(y).ok()?)?,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drops_unreachable_after_return() {
+        let program = Program::from_file("sq.json".to_string()).unwrap();
+        let (optimized, report) = program.optimize();
+        assert_eq!(
+            report.functions[0],
+            FunctionReport {
+                name: "sq".to_string(),
+                before: 9,
+                after: 8,
+            }
+        );
+        assert_eq!(report.functions[1].before, report.functions[1].after);
+        assert!(optimized
+            .to_assembly()
+            .contains("    MUL\n    RETURN\n\n.init"));
+        program.verify_optimized(&optimized, 100_000).unwrap();
+    }
+
+    #[test]
+    fn test_peephole() {
+        let program = Program::from_assembly(
+            ".init 0 0:
+    PUSH    2
+    PUSH    3
+    ADD
+    PUSH    4
+    MUL
+    CALL    iprint
+    POP
+    PUSH    7
+    POP
+    PUSH    5
+    NOT
+    NOT
+    CALL    iprint
+    POP
+    PUSH    1
+    PUSH    0
+    DIV
+    LOOP
+        PUSH    6
+        NOT
+        CALL    iprint
+        RETURN
+        PUSH    1
+",
+        )
+        .unwrap();
+        let (optimized, report) = program.optimize();
+        let expected = Program::from_assembly(
+            ".init 0 0:
+    PUSH    20
+    CALL    iprint
+    POP
+    PUSH    5
+    CALL    iprint
+    POP
+    PUSH    1
+    PUSH    0
+    DIV
+    LOOP
+        PUSH    -7
+        CALL    iprint
+        RETURN
+",
+        )
+        .unwrap();
+        assert_eq!(optimized.functions, expected.functions);
+        assert_eq!(report.functions[0].before, 23);
+        assert_eq!(report.functions[0].after, 13);
+    }
+
+    #[test]
+    fn test_keeps_line_info() {
+        let program = Program::from_source_file("sieve.t".to_string()).unwrap();
+        let (optimized, _) = program.optimize();
+        fn check(frame: &Frame) {
+            assert_eq!(frame.lines.len(), frame.data.len());
+            for data in &frame.data {
+                if let FrameData::Frame(nested) = data {
+                    check(nested);
+                }
+            }
+        }
+        optimized.functions.iter().for_each(|f| check(&f.frame));
+    }
+
+    #[test]
+    fn test_verify() {
+        let program = Program::from_file("sieve.json".to_string()).unwrap();
+        let (optimized, _) = program.optimize();
+        program.verify_optimized(&optimized, 1_000_000).unwrap();
+
+        // sq computing n + n instead of n * n.
+        let program = Program::from_file("sq.json".to_string()).unwrap();
+        let mut broken = program.clone();
+        Arc::make_mut(&mut broken.functions[0]).frame.data[8] = FrameData::from(10);
+        let error = program.verify_optimized(&broken, 100_000).unwrap_err();
+        assert!(error.original.contains("3 squared equals 9"));
+        assert!(error.optimized.contains("3 squared equals 6"));
+    }
+}
@@ -1,17 +1,23 @@
-use crate::callable::Callable;
+use crate::debug_info::DebugInfo;
 use crate::frame::{Frame, FrameData};
 use crate::function::Function;
 use crate::instruction::Instruction;
-use serde_json::Value;
+use crate::program_parser::{ParserError, ProgramParser};
+use serde_json::{json, Value};
+use std::error::Error;
 use std::fmt::Display;
 use std::fs;
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Default)]
 pub struct Program {
     pub entry_point: usize,
     pub heap_size: usize,
     pub heap: Vec<(usize, i32)>,
-    pub functions: Vec<Function>,
+    // Shared with the call states of a running machine, so calls don't copy them.
+    pub functions: Vec<Arc<Function>>,
+    pub debug: Option<DebugInfo>,
 }
 
 impl Program {
@@ -25,7 +31,8 @@ impl Program {
             entry_point,
             heap_size,
             heap,
-            functions,
+            functions: functions.into_iter().map(Arc::new).collect(),
+            debug: None,
         }
     }
 
@@ -33,75 +40,159 @@ impl Program {
         ProgramBuilder::new()
     }
 
-    pub fn framedata_from_json(json: &Value) -> FrameData {
-        match json {
-            Value::Number(op) => match op.as_i64().unwrap() as i32 {
-                n @ -111..=-101 => FrameData::Callable(Callable::get_native(n), vec![]),
-                n @ 1..=27 => {
-                    FrameData::Instruction(Instruction::get_instruction(n as u32), vec![])
-                }
-                n => FrameData::Primitive(n),
-            },
-            Value::Array(val) => {
-                FrameData::Frame(val.iter().map(Program::framedata_from_json).collect())
-            }
-            _ => panic!("Invalid frame data"),
+    pub fn from_json(tape: &str) -> Result<Program, ParserError> {
+        ProgramParser::new(tape).parse()
+    }
+
+    // Loads a JSON or binary tape, telling them apart by the binary magic number.
+    pub fn from_file(file: String) -> Result<Program, ParserError> {
+        let tape = fs::read(&file).map_err(|err| ParserError {
+            path: String::new(),
+            message: format!("unable to read {}: {}", file, err),
+        })?;
+        if Program::is_binary(&tape) {
+            return Ok(Program::from_binary(&tape)?);
         }
+        let tape = String::from_utf8(tape).map_err(|_| ParserError {
+            path: String::new(),
+            message: format!("{} is not a json or binary tape", file),
+        })?;
+        Program::from_json(&tape)
     }
 
-    pub fn function_from_json(json: &Value) -> Function {
-        let id = json[0].as_u64().expect("frame id") as usize;
-        let name = json[1].as_str().expect("frame name").to_string();
-        let args = json[2].as_u64().unwrap() as usize;
-        let locals = json[3].as_u64().unwrap() as usize;
-        let frame_data: Vec<FrameData> = json[4]
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(Self::framedata_from_json)
-            .collect();
-        let mut frame_name = name.clone();
-        frame_name.push_str("-frame");
-        Function {
-            id,
-            name,
-            args,
-            locals,
-            frame: Frame {
-                id,
-                name: frame_name,
-                data: frame_data,
-                pc: 0,
-            },
+    /// Loads a tape, source file or assembly listing, telling them apart by extension: `.json`
+    /// and `.tvm` are tapes, `.t` is source and anything else is assembly.
+    pub fn from_path(path: &str) -> Result<Program, Box<dyn Error>> {
+        if path.ends_with(".json") || path.ends_with(".tvm") {
+            Ok(Program::from_file(path.to_string())?)
+        } else if path.ends_with(".t") {
+            Ok(Program::from_source_file(path.to_string())?)
+        } else {
+            Ok(Program::from_assembly_file(path.to_string())?)
         }
     }
 
-    pub fn from_file(file: String) -> Program {
-        let tape = fs::read_to_string(file).expect("Unable to read file");
-        let json: Value = serde_json::from_str(&tape).expect("Unable to parse json");
-        let allocation = json[0].as_array().unwrap();
-        let heap = json[1].as_array().unwrap();
-        let functions: Vec<Function> = json.as_array().unwrap()[2..]
-            .iter()
-            .map(Self::function_from_json)
-            .collect();
-        Program {
-            entry_point: allocation[0].as_u64().unwrap() as usize,
-            heap_size: allocation[1].as_u64().unwrap() as usize,
-            heap: heap
+    /// Reads the source file named in the debug info, looking next to `path`, the file the
+    /// program was loaded from, if it is not found.
+    pub fn read_source(&self, path: &str) -> Option<String> {
+        let source = self.debug.as_ref()?.source.as_ref()?;
+        fs::read_to_string(source).ok().or_else(|| {
+            let dir = Path::new(path).parent()?;
+            fs::read_to_string(dir.join(source)).ok()
+        })
+    }
+
+    // The tape layout read by `from_json`:
+    //
+    //     [[entry, heap_size], [[address, value], ...], [id, name, args, locals, frame], ...]
+    //
+    // followed by the debug info, if any.
+    pub fn to_json(&self) -> Value {
+        let mut tape = vec![
+            json!([self.entry_point, self.heap_size]),
+            Value::Array(self.heap.iter().map(|(a, v)| json!([a, v])).collect()),
+        ];
+        for function in &self.functions {
+            let mut entry = vec![
+                json!(function.id),
+                json!(function.name),
+                json!(function.args),
+                json!(function.locals),
+                Program::frame_to_json(&function.frame),
+            ];
+            if let Some(debug) = &function.debug {
+                entry.push(debug.to_json(&function.frame));
+            }
+            tape.push(Value::Array(entry));
+        }
+        if let Some(debug) = &self.debug {
+            tape.push(debug.to_json());
+        }
+        Value::Array(tape)
+    }
+
+    pub fn frame_to_json(frame: &Frame) -> Value {
+        Value::Array(
+            frame
+                .data
                 .iter()
-                .map(|x| {
-                    (
-                        x[0].as_u64().unwrap() as usize,
-                        x[1].as_i64().unwrap() as i32,
-                    )
+                .map(|data| match data {
+                    FrameData::Frame(nested) => Program::frame_to_json(nested),
+                    word => json!(word.get_id()),
                 })
                 .collect(),
-            functions,
+        )
+    }
+
+    // Writes the tape laid out in rows, as `sq.json` is: one per heap cell and one per
+    // instruction with its operand, with nested frames indented under their instruction.
+    pub fn write_file(&self, file: String) -> std::io::Result<()> {
+        fs::write(file, self.to_tape_string())
+    }
+
+    // The tape written by `write_file`.
+    pub fn to_tape_string(&self) -> String {
+        let mut rows = vec![format!("[{}, {}]", self.entry_point, self.heap_size)];
+        let heap: Vec<String> = self
+            .heap
+            .iter()
+            .map(|(address, value)| format!("[{}, {}]", address, value))
+            .collect();
+        rows.push(block(&heap, 2));
+        for function in &self.functions {
+            let mut entry = format!(
+                "[{}, {}, {}, {},\n    {}",
+                function.id,
+                json!(function.name),
+                function.args,
+                function.locals,
+                Program::frame_to_tape_string(&function.frame, 4)
+            );
+            if let Some(debug) = &function.debug {
+                entry.push_str(&format!(",\n    {}", debug.to_json(&function.frame)));
+            }
+            entry.push_str("\n  ]");
+            rows.push(entry);
         }
+        if let Some(debug) = &self.debug {
+            rows.push(debug.to_json().to_string());
+        }
+        format!("{}\n", block(&rows, 0))
+    }
+
+    fn frame_to_tape_string(frame: &Frame, indent: usize) -> String {
+        let mut rows = Vec::new();
+        let mut pc = 0;
+        while pc < frame.data.len() {
+            let data = &frame.data[pc];
+            pc += 1;
+            let row = match data {
+                FrameData::Frame(nested) => Program::frame_to_tape_string(nested, indent + 2),
+                // PUSH and CALL are followed by their operand.
+                FrameData::Instruction(Instruction::Push { .. } | Instruction::Call { .. }, _)
+                    if !matches!(frame.data.get(pc), None | Some(FrameData::Frame(_))) =>
+                {
+                    pc += 1;
+                    format!("{}, {}", data.get_id(), frame.data[pc - 1].get_id())
+                }
+                word => word.get_id().to_string(),
+            };
+            rows.push(row);
+        }
+        block(&rows, indent)
     }
 }
 
+// Rows inside brackets, indented by two more than the brackets at `indent`.
+fn block(rows: &[String], indent: usize) -> String {
+    if rows.is_empty() {
+        return "[]".to_string();
+    }
+    let pad = " ".repeat(indent);
+    let rows: Vec<String> = rows.iter().map(|row| format!("{}  {}", pad, row)).collect();
+    format!("[\n{}\n{}]", rows.join(",\n"), pad)
+}
+
 impl Display for Program {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Program {{")?;
@@ -128,8 +219,10 @@ impl Display for Program {
 #[derive(Default, Debug)]
 pub struct ProgramBuilder {
     entry_point: usize,
+    heap_size: Option<usize>,
     heap: Vec<(usize, i32)>,
     functions: Vec<Function>,
+    debug: Option<DebugInfo>,
 }
 
 impl ProgramBuilder {
@@ -142,6 +235,12 @@ impl ProgramBuilder {
         self
     }
 
+    // Defaults to the number of heap entries when not set.
+    pub fn heap_size(mut self, heap_size: usize) -> ProgramBuilder {
+        self.heap_size = Some(heap_size);
+        self
+    }
+
     pub fn heap(mut self, heap: Vec<(usize, i32)>) -> ProgramBuilder {
         self.heap = heap;
         self
@@ -152,7 +251,106 @@ impl ProgramBuilder {
         self
     }
 
+    pub fn debug(mut self, debug: DebugInfo) -> ProgramBuilder {
+        self.debug = Some(debug);
+        self
+    }
+
     pub fn build(self) -> Program {
-        Program::new(self.entry_point, self.heap.len(), self.heap, self.functions)
+        let heap_size = self.heap_size.unwrap_or(self.heap.len());
+        let mut program = Program::new(self.entry_point, heap_size, self.heap, self.functions);
+        program.debug = self.debug;
+        program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::Function;
+    use crate::instruction::Instruction;
+    use crate::test_utils::temp_dir;
+
+    #[test]
+    fn test_to_json_matches_shipped_tapes() {
+        for file in ["sq.json", "sieve.json"] {
+            let tape = fs::read_to_string(file).unwrap();
+            let expected: Value = serde_json::from_str(&tape).unwrap();
+            assert_eq!(Program::from_json(&tape).unwrap().to_json(), expected);
+        }
+    }
+
+    #[test]
+    fn test_tape_string_matches_sq_json() {
+        // The same words in the same rows as the hand written tape, which only differs in
+        // where it breaks lines around nested frames.
+        let tape = fs::read_to_string("sq.json").unwrap();
+        let written = Program::from_json(&tape).unwrap().to_tape_string();
+        let words = |text: &str| text.split_whitespace().collect::<String>();
+        assert_eq!(words(&written), words(&tape));
+        assert!(written.starts_with("[\n  [1, 36],\n  [\n    [65535, 0],\n"));
+        assert!(written.contains("\n  [0, \"sq\", 1, 0,\n    [\n      1, 1,\n      9,\n"));
+        assert!(written.contains("\n      8, -102,\n"));
+    }
+
+    #[test]
+    fn test_write_file_round_trip() {
+        let dir = temp_dir("program");
+        let path = dir.join("tape.json").to_str().unwrap().to_string();
+        for file in ["sq.json", "sieve.json"] {
+            let program = Program::from_file(file.to_string()).unwrap();
+            program.write_file(path.clone()).unwrap();
+            let read = Program::from_file(path.clone()).unwrap();
+            assert_eq!(read.entry_point, program.entry_point);
+            assert_eq!(read.heap_size, program.heap_size);
+            assert_eq!(read.heap, program.heap);
+            assert_eq!(read.functions, program.functions);
+            let shipped: Value = serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap();
+            assert_eq!(read.to_json(), shipped);
+        }
+        // Debug info is written as well.
+        let program = Program::from_source_file("sieve.t".to_string()).unwrap();
+        program.write_file(path.clone()).unwrap();
+        let read = Program::from_file(path).unwrap();
+        assert_eq!(read.debug, program.debug);
+        assert_eq!(read.functions, program.functions);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_debug_info_round_trip() {
+        let program = Program::from_source_file("sieve.t".to_string()).unwrap();
+        let read = Program::from_json(&program.to_json().to_string()).unwrap();
+        assert_eq!(read.debug, program.debug);
+        assert_eq!(read.functions, program.functions);
+    }
+
+    #[test]
+    fn test_built_program() {
+        let program = Program::builder()
+            .entry_point(0)
+            .heap(vec![(0, 104), (1, 0)])
+            .function(
+                Function::builder()
+                    .name("init".to_string())
+                    .frame(
+                        Frame::builder()
+                            .instruction(Instruction::get_instruction(1), vec![])
+                            .primitive(0)
+                            .instruction(Instruction::get_instruction(8), vec![])
+                            .callable(-102, vec![])
+                            .build(),
+                    )
+                    .build(),
+            )
+            .build();
+        assert_eq!(
+            program.to_json(),
+            json!([
+                [0, 2],
+                [[0, 104], [1, 0]],
+                [0, "init", 0, 0, [1, 0, 8, -102]]
+            ])
+        );
     }
 }
@@ -1,103 +1,266 @@
-use core::num::dec2flt::parse::parse_number;
-use serde_json::Value;
-use crate::callable::Callable;
-use crate::frame::{Frame, FrameBuilder, FrameData};
-use crate::function::{Function, FunctionBuilder};
-use crate::instruction::Instruction;
+use crate::binary::TapeError;
+use crate::debug_info::{DebugInfo, FunctionDebugInfo};
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
 use crate::program::{Program, ProgramBuilder};
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+
+// Parses JSON tapes:
+//
+//     [[entry, heap_size], [[address, value], ...], [id, name, args, locals, frame], ...]
+//
+// Errors name the JSON path of the offending value, such as `functions[1].frame[4]`, where
+// functions are numbered from the first one on the tape.
 
-#[derive(Debug, Default)]
-pub struct ProgramParser {
-    input: String,
-    root_name: String,
-    program_builder: ProgramBuilder,
-    current_value: Option<Value>,
-    previous_value: Option<Value>,
-    current_frame_data: Option<FrameData>,
-    previous_frame_data: Option<FrameData>,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParserError {
+    pub path: String,
+    pub message: String,
 }
 
-impl ProgramParser {
-    pub fn new(input: String, root_name: String) -> Self {
-        Self {
-            input,
-            root_name,
-            program_builder: ProgramBuilder::new(),
-            current_value: None,
-            previous_value: None,
-            current_frame_data: None,
-            previous_frame_data: None,
+impl Display for ParserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
         }
     }
+}
 
-    pub fn parse(&mut self) -> Program {
-        let json: Value = serde_json::from_str(&self.input).unwrap();
-        self.parse_value(&json);
-        self.program_builder.build()
-    }
+impl std::error::Error for ParserError {}
 
-    pub fn parse_function(&mut self, value: &Value) -> Function {
-        let mut function_builder = FunctionBuilder::default();
-        let name = value[1].as_str().expect("function name").to_string();
-        function_builder
-            .id(value[0].as_u64().expect("function id") as usize)
-            .name(name.clone())
-            .args(value[2].as_u64().expect("function args") as usize)
-            .locals(value[3].as_u64().expect("function locals") as usize)
-            .frame(self.parse_frame(&value[4], name));
-        return function_builder.build();
+impl From<TapeError> for ParserError {
+    fn from(error: TapeError) -> Self {
+        ParserError {
+            path: format!("offset {}", error.offset),
+            message: error.message,
+        }
     }
+}
 
-    pub fn parse_frame(&mut self, value: &Value, name: String) -> Frame {
-        let mut frame_builder = FrameBuilder::default();
-        frame_builder
-            .id(value[0].as_u64().expect("frame id") as usize)
-            .name(name)
-            // .function_id(value[1].as_u64().expect("frame function id") as usize)
-            // .pc(value[2].as_u64().expect("frame pc") as usize)
-            .data(self.parse_frame_data(&value[3]));
-        return frame_builder.build();
+#[derive(Debug)]
+pub struct ProgramParser<'a> {
+    input: &'a str,
+}
+
+impl<'a> ProgramParser<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { input }
     }
 
-    pub fn parse_frame_data(&mut self, value: &Value) -> FrameData {
-        let mut frame_data = FrameData::default();
-        for (key, value) in value.as_object().unwrap() {
-            match key.as_str() {
-                "type" => frame_data = frame_data.type_(value.as_str().unwrap().to_string()),
-                "value" => self.parse_value(value),
-                _ => panic!("Unknown key: {}", key),
+    pub fn parse(&self) -> Result<Program, ParserError> {
+        let json: Value = serde_json::from_str(self.input).map_err(|e| ParserError {
+            path: String::new(),
+            message: format!("invalid json: {}", e),
+        })?;
+        let tape = array(&json, "")?;
+        let header = tape.first().ok_or_else(|| error("", "missing header"))?;
+        let header = array(header, "header")?;
+        if header.len() != 2 {
+            return Err(error("header", "expected [entry, heap_size]"));
+        }
+        let mut builder = ProgramBuilder::new()
+            .entry_point(unsigned(&header[0], "header[0]")?)
+            .heap_size(unsigned(&header[1], "header[1]")?)
+            .heap(Self::parse_heap(
+                tape.get(1).ok_or_else(|| error("", "missing heap"))?,
+            )?);
+        let mut functions = 0;
+        for (i, value) in tape.iter().enumerate().skip(2) {
+            match value {
+                Value::Array(_) => {
+                    builder = builder.function(Self::parse_function(
+                        value,
+                        &format!("functions[{}]", functions),
+                    )?);
+                    functions += 1;
+                }
+                // The program debug info is an object after the functions.
+                Value::Object(_) => builder = builder.debug(DebugInfo::from_json(value, "debug")?),
+                _ => {
+                    return Err(error(
+                        &format!("[{}]", i),
+                        "expected a function or debug info",
+                    ))
+                }
             }
         }
-        frame_data
+        Ok(builder.build())
+    }
+
+    fn parse_heap(value: &Value) -> Result<Vec<(usize, i32)>, ParserError> {
+        array(value, "heap")?
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                let path = format!("heap[{}]", i);
+                match array(cell, &path)?.as_slice() {
+                    [address, value] => Ok((
+                        unsigned(address, &format!("{}[0]", path))?,
+                        word(value, &format!("{}[1]", path))?,
+                    )),
+                    _ => Err(error(&path, "expected [address, value]")),
+                }
+            })
+            .collect()
     }
 
-    pub fn parse_value(&mut self, value: &Value) -> FrameData {
-        self.current_value = Some(value.clone());
-        match (value, self.previous_frame_data.clone()) {
-            (Value::Number(op), _) => self.parse_number(op.as_i64().expect("number")),
-            (Value::Array(val), _) => self.parse_array(val),
-            _ => panic!("Invalid value. Expecting a number or an array"),
+    pub fn parse_function(value: &Value, path: &str) -> Result<Function, ParserError> {
+        let fields = array(value, path)?;
+        if !(5..=6).contains(&fields.len()) {
+            return Err(error(
+                path,
+                "expected [id, name, args, locals, frame] with optional debug info",
+            ));
         }
+        let id = unsigned(&fields[0], &format!("{}.id", path))?;
+        let name = fields[1]
+            .as_str()
+            .ok_or_else(|| error(&format!("{}.name", path), "expected a string"))?
+            .to_string();
+        let mut frame = Self::parse_frame(&fields[4], &format!("{}.frame", path))?;
+        frame.id = id;
+        frame.name = format!("{}-frame", name);
+        let mut builder = Function::builder()
+            .id(id)
+            .name(name)
+            .args(unsigned(&fields[2], &format!("{}.args", path))?)
+            .locals(unsigned(&fields[3], &format!("{}.locals", path))?);
+        // Tapes with debug info carry it as an optional sixth element.
+        if let Some(debug) = fields.get(5) {
+            if !debug.is_object() {
+                return Err(error(&format!("{}.debug", path), "expected an object"));
+            }
+            builder = builder.debug(FunctionDebugInfo::from_json(
+                debug,
+                &format!("{}.debug", path),
+                &mut frame,
+            )?);
+        }
+        Ok(builder.frame(frame).build())
     }
 
-    pub fn parse_number(&mut self, op: i64) -> FrameData {
-        return match (op as i32, self.previous_frame_data.clone()) {
-            (n @ 1..=27, None) => {
-                FrameData::Instruction(Instruction::get_instruction(n as u32), vec![])
-            },
-            (n @ -111..=-101, Some(FrameData::Instruction(Instruction::Call { .. }, ..))) => {
-                FrameData::Callable(Callable::get_native(n), vec![])
-            },
-            (n, _) => FrameData::Primitive(n)
+    pub fn parse_frame(value: &Value, path: &str) -> Result<Frame, ParserError> {
+        let mut builder = Frame::builder();
+        for (i, entry) in array(value, path)?.iter().enumerate() {
+            let path = format!("{}[{}]", path, i);
+            builder = builder.data(match entry {
+                Value::Array(_) => FrameData::Frame(Self::parse_frame(entry, &path)?),
+                Value::Number(_) => FrameData::from(word(entry, &path)?),
+                _ => return Err(error(&path, "expected a number or a frame")),
+            });
         }
+        Ok(builder.build())
     }
+}
 
-    pub fn parse_array(&mut self, array: &Vec<Value>) -> FrameData {
-        for value in array {
-            self.parse_value(value);
+pub(crate) fn error(path: &str, message: &str) -> ParserError {
+    ParserError {
+        path: path.to_string(),
+        message: message.to_string(),
+    }
+}
+
+pub(crate) fn array<'v>(value: &'v Value, path: &str) -> Result<&'v Vec<Value>, ParserError> {
+    value
+        .as_array()
+        .ok_or_else(|| error(path, "expected an array"))
+}
+
+pub(crate) fn unsigned(value: &Value, path: &str) -> Result<usize, ParserError> {
+    value
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| error(path, "expected a non-negative integer"))
+}
+
+pub(crate) fn word(value: &Value, path: &str) -> Result<i32, ParserError> {
+    value
+        .as_i64()
+        .and_then(|n| i32::try_from(n).ok())
+        .ok_or_else(|| error(path, "expected a 32-bit integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(input: &str) -> String {
+        ProgramParser::new(input).parse().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        let program = ProgramParser::new(
+            "[[0, 3], [[0, 104], [1, 105]], [0, \"init\", 0, 1, [1, 104, 9, [1, 2]]]]",
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(program.entry_point, 0);
+        assert_eq!(program.heap_size, 3);
+        assert_eq!(program.heap, vec![(0, 104), (1, 105)]);
+        let function = &program.functions[0];
+        assert_eq!(function.name, "init");
+        assert_eq!(function.locals, 1);
+        assert_eq!(function.frame.name, "init-frame");
+        assert_eq!(function.frame.data.len(), 4);
+        assert!(
+            matches!(&function.frame.data[3], FrameData::Frame(nested) if nested.data.len() == 2)
+        );
+    }
+
+    #[test]
+    fn test_shipped_tapes() {
+        for file in ["sq.json", "sieve.json"] {
+            let tape = std::fs::read_to_string(file).unwrap();
+            let program = ProgramParser::new(&tape).parse().unwrap();
+            assert_eq!(program.functions.len(), 2);
+            program.verify().unwrap();
         }
+    }
 
-        let mut frame_data = FrameData::default();
-        frame_data
+    #[test]
+    fn test_errors() {
+        assert!(parse_error("[[0, 1]").starts_with("invalid json: "));
+        assert_eq!(parse_error("{}"), "expected an array");
+        assert_eq!(parse_error("[]"), "missing header");
+        assert_eq!(parse_error("[[0]]"), "header: expected [entry, heap_size]");
+        assert_eq!(
+            parse_error("[[-1, 0], []]"),
+            "header[0]: expected a non-negative integer"
+        );
+        assert_eq!(parse_error("[[0, 0]]"), "missing heap");
+        assert_eq!(
+            parse_error("[[0, 0], [[1, 2], [3]]]"),
+            "heap[1]: expected [address, value]"
+        );
+        assert_eq!(
+            parse_error("[[0, 0], [[1, 4294967296]]]"),
+            "heap[0][1]: expected a 32-bit integer"
+        );
+        assert_eq!(
+            parse_error("[[0, 0], [], 7]"),
+            "[2]: expected a function or debug info"
+        );
+        assert_eq!(
+            parse_error("[[0, 0], [], [0, \"f\", 0, 0, []], [1, 2, 0, 0, []]]"),
+            "functions[1].name: expected a string"
+        );
+        assert_eq!(
+            parse_error(
+                "[[0, 0], [], [0, \"f\", 0, 0, []], [1, \"g\", 0, 0, [1, 2, 3, [4, \"x\"]]]]"
+            ),
+            "functions[1].frame[3][1]: expected a number or a frame"
+        );
+        assert_eq!(
+            parse_error("[[0, 0], [], [0, \"f\", 0, 0, [1, 2.5]]]"),
+            "functions[0].frame[1]: expected a 32-bit integer"
+        );
+        assert_eq!(
+            parse_error("[[0, 0], [], [0, \"f\", 0, 0]]"),
+            "functions[0]: expected [id, name, args, locals, frame] with optional debug info"
+        );
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,360 @@
+use crate::frame::FrameData;
+use crate::program::Program;
+use rand::Rng;
+use std::sync::Arc;
+
+// A small recursive interpreter that follows `leval` and `evalutate` in tvm.js line by line. It
+// is the reference the tick based state machine is checked against, so it stays as plain as
+// the original: nested frames are evaluated by recursion, and BREAK and RETURN unwind it.
+//
+// Natives also follow tvm.js. Timers cannot fire here, so `timer` only returns 0.
+
+// How a frame was left. tvm.js returns these as negative PCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    // -1, fell off the end of the frame.
+    End,
+    // -2, a BREAK was taken.
+    Break,
+    // -3, a RETURN.
+    Return,
+    // The observer asked to stop.
+    Stop,
+}
+
+// Called before every instruction with its opcode. Returning false stops the run.
+pub type Observer<'a> = dyn FnMut(&Reference, i32) -> bool + 'a;
+
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub memory: Box<[i32]>,
+    pub sp: usize,
+    pub fp: usize,
+    // End of the heap, `edata` in tvm.js.
+    pub heap_size: usize,
+    pub stdout: String,
+    // Scripted stdin, a line per iread or sread.
+    pub input: String,
+    // Names of the functions being called, innermost last.
+    pub calls: Vec<String>,
+    pub steps: usize,
+    pub program: Arc<Program>,
+}
+
+impl Reference {
+    pub fn new(program: impl Into<Arc<Program>>) -> Self {
+        let program = program.into();
+        let mut memory = vec![0; 65536].into_boxed_slice();
+        for (address, value) in &program.heap {
+            memory[*address] = *value;
+        }
+        Reference {
+            memory,
+            sp: 65535,
+            fp: 65535,
+            heap_size: program.heap_size,
+            stdout: String::new(),
+            input: String::new(),
+            calls: Vec::new(),
+            steps: 0,
+            program,
+        }
+    }
+
+    /// Runs the entry point for at most `max_steps` instructions, returning whether it finished.
+    pub fn run(&mut self, max_steps: usize) -> bool {
+        self.run_with(&mut |reference, _| reference.steps < max_steps)
+    }
+
+    /// Runs the entry point, calling `observer` before every instruction.
+    pub fn run_with(&mut self, observer: &mut Observer) -> bool {
+        self.call(self.program.entry_point as i32, observer) != Flow::Stop
+    }
+
+    fn push(&mut self, x: i32) {
+        self.memory[self.sp] = x;
+        self.sp -= 1;
+    }
+
+    fn pop(&mut self) -> i32 {
+        self.sp += 1;
+        self.memory[self.sp]
+    }
+
+    fn a2s(&self, mut x: usize) -> String {
+        let mut s = String::new();
+        while self.memory[x] != 0 {
+            s.push(self.memory[x] as u8 as char);
+            x += 1;
+        }
+        s
+    }
+
+    fn read_line(&mut self) -> String {
+        let end = self.input.find('\n').map_or(self.input.len(), |i| i + 1);
+        let line: String = self.input.drain(..end).collect();
+        line.trim_end_matches(['\r', '\n']).to_string()
+    }
+
+    fn write_string(&mut self, address: usize, s: &str) {
+        let mut i = 0;
+        for c in s.chars() {
+            self.memory[address + i] = c as i32;
+            i += 1;
+        }
+        self.memory[address + i] = 0;
+    }
+
+    fn leval(&mut self, l: &[FrameData], observer: &mut Observer) -> Flow {
+        let mut pc = 0;
+        loop {
+            match self.evaluate(l, pc, observer) {
+                Ok(next) => pc = next,
+                Err(flow) => return flow,
+            }
+        }
+    }
+
+    fn call(&mut self, n: i32, observer: &mut Observer) -> Flow {
+        match n {
+            -101 => {
+                let x = self.pop();
+                self.stdout += &x.to_string();
+                self.push(0);
+            }
+            -102 => {
+                let x = self.pop();
+                self.stdout += &self.a2s(x as usize);
+                self.push(0);
+            }
+            -103 => {
+                self.pop();
+                let x = self.read_line();
+                self.push(x.trim().parse().expect("Failed to parse input"));
+            }
+            -104 => {
+                let a = self.pop();
+                self.pop();
+                let x = self.read_line();
+                self.write_string(a as usize, &x);
+                self.push(0);
+            }
+            -105 => {
+                self.stdout.push('\n');
+                self.push(0);
+            }
+            -106 => {
+                let n = self.pop();
+                self.push(rand::thread_rng().gen_range(0..n));
+            }
+            -107 => {
+                self.pop();
+                self.pop();
+                self.push(0);
+            }
+            -108 => {
+                self.pop();
+                self.push(0);
+            }
+            -109 => {
+                let n = self.pop();
+                self.push(self.heap_size as i32);
+                self.heap_size = (self.heap_size as i32 + n) as usize;
+            }
+            -110 => {
+                self.pop();
+                self.push(0);
+            }
+            -111 => {
+                let s = self.pop();
+                let n = self.pop();
+                let istr = n.to_string();
+                self.write_string(s as usize, &istr);
+                self.push(istr.len() as i32);
+            }
+            n if n < 0 => panic!("Invalid function call {}", n),
+            n => {
+                let function = Arc::clone(&self.program.functions[n as usize]);
+                for _ in 0..function.locals {
+                    self.push(0);
+                }
+                self.memory[self.sp] = self.fp as i32;
+                self.fp = self.sp;
+                self.sp -= 1;
+                self.calls.push(function.name.clone());
+                if self.leval(&function.frame.data, observer) == Flow::Stop {
+                    return Flow::Stop;
+                }
+                self.calls.pop();
+                let r = self.pop();
+                self.sp = self.fp;
+                self.fp = self.memory[self.sp] as usize;
+                self.sp += function.args + function.locals;
+                self.push(r);
+            }
+        }
+        Flow::End
+    }
+
+    fn evaluate(
+        &mut self,
+        l: &[FrameData],
+        mut pc: usize,
+        observer: &mut Observer,
+    ) -> Result<usize, Flow> {
+        if pc >= l.len() {
+            return Err(Flow::End);
+        }
+        let ir = l[pc].get_id();
+        if !observer(self, ir) {
+            return Err(Flow::Stop);
+        }
+        self.steps += 1;
+        pc += 1;
+        match ir {
+            // push
+            1 => {
+                self.push(l[pc].get_id());
+                pc += 1;
+            }
+            // fetch
+            2 => {
+                let a = self.pop();
+                self.push(self.memory[a as usize]);
+            }
+            // store
+            3 => {
+                let v = self.pop();
+                let a = self.pop();
+                self.memory[a as usize] = v;
+            }
+            // if
+            4 => {
+                let x = self.pop();
+                let r = if x != 0 {
+                    let r = self.leval(frame(&l[pc]), observer);
+                    pc += 2;
+                    r
+                } else {
+                    pc += 1;
+                    let r = self.leval(frame(&l[pc]), observer);
+                    pc += 1;
+                    r
+                };
+                if r != Flow::End {
+                    return Err(r);
+                }
+            }
+            // loop
+            5 => {
+                loop {
+                    match self.leval(frame(&l[pc]), observer) {
+                        Flow::Break => break,
+                        Flow::End => {}
+                        r => return Err(r),
+                    }
+                }
+                pc += 1;
+            }
+            // break
+            6 => {
+                let x = self.pop();
+                if x != 0 {
+                    return Err(Flow::Break);
+                }
+            }
+            // return
+            7 => return Err(Flow::Return),
+            // call
+            8 => {
+                if self.call(l[pc].get_id(), observer) == Flow::Stop {
+                    return Err(Flow::Stop);
+                }
+                pc += 1;
+            }
+            // fpplus
+            9 => {
+                let a = self.pop();
+                self.push(a.wrapping_add(self.fp as i32));
+            }
+            // not
+            15 => {
+                let x = self.pop();
+                self.push(!x);
+            }
+            // pop
+            25 => {
+                self.pop();
+            }
+            10..=24 | 26 | 27 => {
+                let y = self.pop();
+                let x = self.pop();
+                self.push(binary(ir, x, y));
+            }
+            _ => panic!("unknown opcode {}", ir),
+        }
+        Ok(pc)
+    }
+}
+
+fn frame(data: &FrameData) -> &[FrameData] {
+    match data {
+        FrameData::Frame(frame) => &frame.data,
+        _ => panic!("expected a frame, found {}", data.get_id()),
+    }
+}
+
+// Results wrap like the 32 bit cells they are stored in.
+fn binary(ir: i32, x: i32, y: i32) -> i32 {
+    match ir {
+        10 => x.wrapping_add(y),
+        11 => x.wrapping_sub(y),
+        12 => x.wrapping_mul(y),
+        // Math.floor(x / y)
+        13 => x.div_euclid(y) - i32::from(y < 0 && x.rem_euclid(y) != 0),
+        14 => x.wrapping_rem(y),
+        16 => x & y,
+        17 => x | y,
+        18 => x ^ y,
+        19 => i32::from(x == y),
+        20 => i32::from(x != y),
+        21 => i32::from(x < y),
+        22 => i32::from(x <= y),
+        23 => i32::from(x > y),
+        24 => i32::from(x >= y),
+        26 => x.wrapping_shl(y as u32),
+        27 => x.wrapping_shr(y as u32),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shipped_tapes() {
+        for (file, expected) in [("sq.json", "sq.stdout"), ("sieve.json", "sieve.stdout")] {
+            let mut reference = Reference::new(Program::from_file(file.to_string()).unwrap());
+            assert!(reference.run(10_000_000));
+            assert_eq!(reference.stdout, std::fs::read_to_string(expected).unwrap());
+            assert_eq!((reference.sp, reference.fp), (65534, 65535));
+        }
+    }
+
+    #[test]
+    fn test_run_stops() {
+        let mut reference = Reference::new(Program::from_file("sieve.json".to_string()).unwrap());
+        assert!(!reference.run(50));
+        assert_eq!(reference.steps, 50);
+    }
+
+    #[test]
+    fn test_floor_division() {
+        assert_eq!(binary(13, 7, 2), 3);
+        assert_eq!(binary(13, -7, 2), -4);
+        assert_eq!(binary(13, 7, -2), -4);
+        assert_eq!(binary(13, -8, 2), -4);
+        assert_eq!(binary(14, -7, 2), -1);
+    }
+}
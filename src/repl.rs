@@ -0,0 +1,564 @@
+use crate::batch::panic_message;
+use crate::call_stack::{CallFrame, Variable};
+use crate::disassembler::Disassembler;
+use crate::tvm::Tvm;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Once};
+use std::thread;
+
+// A gdb-like debugger driven a line at a time, for SSH sessions and logs where the full-screen
+// debugger cannot be used. Commands come from any reader, so a command file drives it the same
+// way a terminal does.
+//
+// Steps are whole instructions rather than ticks: `step` stops at the next instruction, going
+// into calls, `next` stops at the next instruction of the same call or its callers, and
+// `finish` stops once the current call has returned.
+
+const HELP: &str = "\
+step [n]        execute n instructions, into calls
+next [n]        execute n instructions, over calls
+finish          run until the current function returns
+continue        run until a breakpoint, watch, input or halt
+break [fn]      stop when fn is called, or list breakpoints
+delete fn       remove a breakpoint
+watch [addr]    stop when the cell at addr changes, or list watches
+print x         show a cell by address, a variable of the current call, sp or fp
+input line      give a line to iread or sread
+stack           show the stack, top first
+bt              show the calls in progress
+regs            show sp, fp, the heap size, ticks and state
+disasm [fn]     list the current function or fn
+reset           start the program over, keeping breakpoints and watches
+quit
+";
+
+pub struct Repl {
+    pub tvm: Tvm,
+    // Watched addresses with the value last seen.
+    pub watches: BTreeMap<usize, i32>,
+    // Ticks a single command may run for before giving up.
+    pub max_ticks: usize,
+}
+
+// Why a command stopped running the machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Stop {
+    Stepped,
+    Breakpoint(String),
+    Watch { address: usize, old: i32, new: i32 },
+    Input,
+    Halted,
+    Fault(String),
+    TickLimit,
+}
+
+impl Display for Stop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stop::Stepped => Ok(()),
+            Stop::Breakpoint(function) => write!(f, "breakpoint at {}", function),
+            Stop::Watch { address, old, new } => {
+                write!(f, "watch {}: {} -> {}", address, old, new)
+            }
+            Stop::Input => write!(f, "waiting for input, give it with `input`"),
+            Stop::Halted => write!(f, "halted"),
+            Stop::Fault(message) => write!(f, "fault: {}", message),
+            Stop::TickLimit => write!(f, "stopped after too many ticks"),
+        }
+    }
+}
+
+impl Repl {
+    pub fn new(mut tvm: Tvm) -> Self {
+        // Program input is given with `input`, as stdin carries the commands.
+        tvm.input.get_or_insert_with(String::new);
+        Repl {
+            tvm,
+            watches: BTreeMap::new(),
+            max_ticks: 100_000_000,
+        }
+    }
+
+    /// Runs commands from `commands` until they run out or one quits, returning whether one
+    /// quit. With `echo`, commands are printed after the prompt, so a script's transcript reads
+    /// like a session.
+    pub fn run(
+        &mut self,
+        commands: impl BufRead,
+        out: &mut impl Write,
+        echo: bool,
+    ) -> io::Result<bool> {
+        let mut lines = commands.lines();
+        loop {
+            write!(out, "(tvm) ")?;
+            out.flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                writeln!(out)?;
+                return Ok(false);
+            };
+            if echo {
+                writeln!(out, "{}", line)?;
+            }
+            if !self.command(&line, out)? {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Runs a single command line, returning false if it was `quit`.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        let count = || argument.parse::<usize>().unwrap_or(1).max(1);
+        match command {
+            "" => {}
+            _ if command.starts_with('#') => {}
+            "step" | "s" => {
+                let stop = self.repeat(count(), |_, _| true);
+                self.report(&stop, out)?;
+            }
+            "next" | "n" => {
+                let stop = self.repeat(count(), |tvm, depth| tvm.call_depth() <= depth);
+                self.report(&stop, out)?;
+            }
+            "finish" => {
+                let stop = self.run_until(|tvm, depth| tvm.call_depth() < depth);
+                self.report(&stop, out)?;
+            }
+            "continue" | "c" => {
+                let stop = self.run_until(|_, _| false);
+                self.report(&stop, out)?;
+            }
+            "break" | "b" if argument.is_empty() => {
+                for function in &self.tvm.breakpoints {
+                    writeln!(out, "break {}", function)?;
+                }
+            }
+            "break" | "b" => {
+                if !self
+                    .tvm
+                    .program
+                    .functions
+                    .iter()
+                    .any(|f| f.name == argument)
+                {
+                    writeln!(out, "no function {}", argument)?;
+                } else {
+                    self.tvm.breakpoints.insert(argument.to_string());
+                    writeln!(out, "breakpoint at {}", argument)?;
+                }
+            }
+            "delete" | "d" => {
+                if !self.tvm.breakpoints.remove(argument) {
+                    writeln!(out, "no breakpoint at {}", argument)?;
+                }
+            }
+            "watch" if argument.is_empty() => {
+                for (address, value) in &self.watches {
+                    writeln!(out, "watch {} = {}", address, value)?;
+                }
+            }
+            "watch" => match self.address(argument) {
+                Some(address) => {
+                    let value = self.tvm.memory[address];
+                    self.watches.insert(address, value);
+                    writeln!(out, "watch {} = {}", address, value)?;
+                }
+                None => writeln!(out, "not an address: {}", argument)?,
+            },
+            "print" | "p" => self.print(argument, out)?,
+            "input" => {
+                if let Some(input) = &mut self.tvm.input {
+                    input.push_str(argument);
+                    input.push('\n');
+                }
+            }
+            "stack" => self.stack(out)?,
+            "bt" | "backtrace" => self.backtrace(out)?,
+            "regs" => {
+                let tvm = &self.tvm;
+                writeln!(out, "sp    {}", tvm.stack_pointer)?;
+                writeln!(out, "fp    {}", tvm.frame_pointer)?;
+                writeln!(out, "heap  {}", tvm.heap_size)?;
+                writeln!(out, "ticks {}", tvm.ticks)?;
+                writeln!(out, "state {}", tvm.state.get_name())?;
+            }
+            "disasm" => self.disasm(argument, out)?,
+            "reset" => {
+                self.tvm.reset();
+                for (address, value) in self.watches.iter_mut() {
+                    *value = self.tvm.memory[*address];
+                }
+                writeln!(out, "reset")?;
+            }
+            "help" | "h" => write!(out, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => writeln!(out, "unknown command {}, try help", command)?,
+        }
+        Ok(true)
+    }
+
+    // Steps `count` times, stopping early for anything but a plain step.
+    fn repeat(&mut self, count: usize, done: impl Fn(&Tvm, usize) -> bool) -> Stop {
+        for _ in 0..count {
+            let stop = self.run_until(&done);
+            if stop != Stop::Stepped {
+                return stop;
+            }
+        }
+        Stop::Stepped
+    }
+
+    fn run_until(&mut self, done: impl Fn(&Tvm, usize) -> bool) -> Stop {
+        run_until(&mut self.tvm, &mut self.watches, self.max_ticks, done)
+    }
+
+    // Prints why the machine stopped, any new output, and where it is.
+    fn report(&mut self, stop: &Stop, out: &mut impl Write) -> io::Result<()> {
+        if *stop != Stop::Stepped {
+            writeln!(out, "{}", stop)?;
+        }
+        if !self.tvm.stdout.is_empty() {
+            writeln!(out, "stdout: {:?}", self.tvm.stdout)?;
+            // Output is shown once, as it is printed.
+            self.tvm.stdout.clear();
+        }
+        if !matches!(stop, Stop::Halted | Stop::Fault(_)) {
+            writeln!(out, "{}", self.location())?;
+        }
+        Ok(())
+    }
+
+    // The function, PC path, source line and next instruction.
+    fn location(&self) -> String {
+        let Some(position) = self.tvm.state.get_code_position() else {
+            return "not started".to_string();
+        };
+        let mut location = format!("{} pc {}", position.function.name, path(&position.path));
+        if let Some(line) = position.line {
+            location.push_str(&format!(" line {}", line));
+        }
+        match self.tvm.next_instruction() {
+            Some(instruction) => location.push_str(&format!(": {}", instruction)),
+            None => location.push_str(&format!(": {}", self.tvm.state.get_name())),
+        }
+        location
+    }
+
+    fn address(&self, text: &str) -> Option<usize> {
+        let address = match text.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+            None => text.parse().ok()?,
+        };
+        (address < self.tvm.memory.len()).then_some(address)
+    }
+
+    fn print(&self, argument: &str, out: &mut impl Write) -> io::Result<()> {
+        let tvm = &self.tvm;
+        match argument {
+            "sp" => return writeln!(out, "sp = {}", tvm.stack_pointer),
+            "fp" => return writeln!(out, "fp = {}", tvm.frame_pointer),
+            _ => {}
+        }
+        if let Some(address) = self.address(argument) {
+            let value = tvm.memory[address];
+            return match u8::try_from(value).ok().filter(|c| (32..127).contains(c)) {
+                Some(c) => writeln!(out, "{} = {} '{}'", address, value, c as char),
+                None => writeln!(out, "{} = {}", address, value),
+            };
+        }
+        let calls = tvm.call_stack();
+        let variable = calls.first().and_then(|call| {
+            call.args
+                .iter()
+                .chain(&call.locals)
+                .find(|v| v.name == argument)
+        });
+        match variable {
+            Some(v) => writeln!(out, "{} = {} @{}", v.name, v.value, v.address),
+            None => writeln!(out, "no address or variable {}", argument),
+        }
+    }
+
+    fn stack(&self, out: &mut impl Write) -> io::Result<()> {
+        let tvm = &self.tvm;
+        for address in tvm.stack_pointer + 1..tvm.memory.len() {
+            let mark = if address == tvm.frame_pointer {
+                "  <- fp"
+            } else {
+                ""
+            };
+            writeln!(out, "{:>6} {}{}", address, tvm.memory[address], mark)?;
+        }
+        Ok(())
+    }
+
+    fn backtrace(&self, out: &mut impl Write) -> io::Result<()> {
+        for (i, call) in self.tvm.call_stack().iter().enumerate() {
+            writeln!(out, "#{} {}", i, frame_summary(call))?;
+        }
+        Ok(())
+    }
+
+    fn disasm(&self, argument: &str, out: &mut impl Write) -> io::Result<()> {
+        let position = self.tvm.state.get_code_position();
+        let function = if argument.is_empty() {
+            match &position {
+                Some(position) => Arc::clone(&position.function),
+                None => match self.tvm.program.functions.get(self.tvm.program.entry_point) {
+                    Some(function) => Arc::clone(function),
+                    None => return writeln!(out, "no program"),
+                },
+            }
+        } else {
+            match self
+                .tvm
+                .program
+                .functions
+                .iter()
+                .find(|f| f.name == argument)
+            {
+                Some(function) => Arc::clone(function),
+                None => return writeln!(out, "no function {}", argument),
+            }
+        };
+        let current = position
+            .filter(|p| p.function.id == function.id)
+            .map(|p| p.path);
+        for line in Disassembler::new(&self.tvm.program).function(&function) {
+            let mark = match (&line.path, &current) {
+                (Some(path), Some(current)) if path == current => "=> ",
+                _ => "   ",
+            };
+            writeln!(out, "{}{}", mark, line.text)?;
+        }
+        Ok(())
+    }
+}
+
+// Ticks until the machine is about to execute an instruction for which `done` holds, given
+// the call depth it started at, or something else stops it first.
+pub(crate) fn run_until(
+    tvm: &mut Tvm,
+    watches: &mut BTreeMap<usize, i32>,
+    max_ticks: usize,
+    done: impl Fn(&Tvm, usize) -> bool,
+) -> Stop {
+    let depth = tvm.call_depth();
+    let start = tvm.ticks;
+    // Panics are reported as faults rather than printed over the session.
+    let result = quietly(|| loop {
+        if tvm.is_halted() {
+            return Stop::Halted;
+        }
+        if tvm.is_awaiting_input() {
+            return Stop::Input;
+        }
+        tvm.step();
+        if tvm.is_halted() {
+            return Stop::Halted;
+        }
+        for (&address, old) in watches.iter_mut() {
+            let new = tvm.memory[address];
+            if new != *old {
+                let stop = Stop::Watch {
+                    address,
+                    old: *old,
+                    new,
+                };
+                *old = new;
+                return stop;
+            }
+        }
+        if let Some(function) = tvm.at_breakpoint() {
+            return Stop::Breakpoint(function.to_string());
+        }
+        if tvm.next_instruction().is_some() && done(tvm, depth) {
+            return Stop::Stepped;
+        }
+        if tvm.ticks - start >= max_ticks {
+            return Stop::TickLimit;
+        }
+    });
+    result.unwrap_or_else(|payload| Stop::Fault(panic_message(payload)))
+}
+
+thread_local! {
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+// Runs `f`, catching a panic without printing it. The panic hook is process-wide, so rather
+// than being swapped around every run, where concurrent runs would restore each other's hook,
+// it is wrapped once in a hook that stays quiet on threads inside `quietly`.
+fn quietly<T>(f: impl FnOnce() -> T) -> thread::Result<T> {
+    static WRAP_HOOK: Once = Once::new();
+    WRAP_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET.with(Cell::get) {
+                hook(info);
+            }
+        }));
+    });
+    let quiet = QUIET.with(|q| q.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    QUIET.with(|q| q.set(quiet));
+    result
+}
+
+fn path(pc: &[usize]) -> String {
+    if pc.is_empty() {
+        return "-".to_string();
+    }
+    pc.iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn frame_summary(call: &CallFrame) -> String {
+    let variables = |variables: &[Variable]| {
+        variables
+            .iter()
+            .map(|v| format!("{}={}", v.name, v.value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut summary = format!("{}({})", call.function.name, variables(&call.args));
+    summary.push_str(&format!(" pc {}", path(&call.pc)));
+    if let Some(line) = call.line {
+        summary.push_str(&format!(" line {}", line));
+    }
+    match call.frame_pointer {
+        Some(fp) => summary.push_str(&format!(" fp {}", fp)),
+        None => summary.push_str(" (calling)"),
+    }
+    if !call.locals.is_empty() {
+        summary.push_str(&format!(" locals {}", variables(&call.locals)));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+
+    fn session(program: Program, commands: &str) -> String {
+        let mut tvm = Tvm::default();
+        tvm.load(program);
+        let mut repl = Repl::new(tvm);
+        let mut out = Vec::new();
+        repl.run(commands.as_bytes(), &mut out, true).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn sq() -> Program {
+        Program::from_source_file("sq.t".to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let out = session(sq(), "step\nstep 3\nregs\nquit\nstep\n");
+        assert!(out.starts_with("(tvm) step\ninit pc 0 line 9: push\n"));
+        assert!(out
+            .contains("(tvm) step 3\nstdout: \"Table of squares:\\n\"\ninit pc 5 line 10: push\n"));
+        assert!(out.contains("(tvm) regs\nsp    65533\nfp    65534\nheap  36\nticks 6\n"));
+        assert!(out.ends_with("(tvm) quit\n"));
+
+        // `next` goes over the call to sq that `step` goes into.
+        let out = session(sq(), "break sq\ncontinue\nbt\nstep\nbt\n");
+        assert!(
+            out.contains("breakpoint at sq\nstdout: \"Table of squares:\\n1 squared equals \"\n")
+        );
+        assert!(out.contains("#0 sq(n=1) pc - (calling)\n#1 init() pc "));
+        assert!(out.contains("(tvm) step\nsq pc 0 line 3: push\n"));
+        let out = session(sq(), "break sq\ncontinue\ndelete sq\nfinish\nfinish\n");
+        assert!(out.contains("(tvm) finish\ninit pc "));
+        assert!(out.contains("(tvm) finish\nhalted\nstdout: "));
+    }
+
+    #[test]
+    fn test_next_over_calls() {
+        let mut tvm = Tvm::default();
+        tvm.load(sq());
+        let mut repl = Repl::new(tvm);
+        let mut out = Vec::new();
+        repl.command("break sq", &mut out).unwrap();
+        repl.command("continue", &mut out).unwrap();
+        repl.command("delete sq", &mut out).unwrap();
+        // Stepping from the call state enters sq, nexting from init does not.
+        repl.command("next", &mut out).unwrap();
+        assert_eq!(repl.tvm.call_depth(), 2);
+        repl.command("finish", &mut out).unwrap();
+        for _ in 0..20 {
+            repl.command("next", &mut out).unwrap();
+            assert!(repl.tvm.call_depth() <= 1 || repl.tvm.is_halted());
+        }
+    }
+
+    #[test]
+    fn test_watch_print_and_input() {
+        let program = Program::from_source(
+            "fun init() {\n    var x\n    x : iread(-1)\n    x : .x * 2\n    iprint(.x)\n}",
+        )
+        .unwrap();
+        let out = session(
+            program,
+            "continue\nprint x\nbt\nwatch 65535\ninput 21\ncontinue\nprint 65535\nprint x\ncontinue\nstack\ncontinue\ncontinue\n",
+        );
+        assert!(out.contains("(tvm) continue\nwaiting for input, give it with `input`\n"));
+        assert!(out.contains("(tvm) print x\nx = 0 @65535\n"));
+        assert!(out.contains("(tvm) bt\n#0 init() pc 10 line 3 fp 65534 locals x=0\n"));
+        assert!(out.contains("(tvm) watch 65535\nwatch 65535 = 0\n"));
+        assert!(out.contains("(tvm) continue\nwatch 65535: 0 -> 21\n"));
+        assert!(out.contains("(tvm) print 65535\n65535 = 21\n"));
+        assert!(out.contains("(tvm) continue\nwatch 65535: 21 -> 42\n"));
+        assert!(out.contains("(tvm) stack\n 65534 65535  <- fp\n 65535 42\n"));
+        assert!(out.contains("(tvm) continue\nhalted\nstdout: \"42\"\n"));
+        assert!(out.contains("(tvm) continue\nhalted\n(tvm) \n"));
+    }
+
+    #[test]
+    fn test_fault_disasm_and_reset() {
+        let program = Program::from_source("fun init() {\n    iprint(60 / iread(-1))\n}").unwrap();
+        let out = session(
+            program,
+            "input 0\ncontinue\nreset\ndisasm\nbreak nope\nfrob\n",
+        );
+        assert!(out.contains("(tvm) continue\nfault: attempt to divide by zero\n"));
+        assert!(out.contains("(tvm) reset\nreset\n(tvm) disasm\n"));
+        assert!(out.contains("no function nope\n"));
+        assert!(out.contains("unknown command frob, try help\n"));
+
+        let out = session(sq(), "step 2\ndisasm\n");
+        assert!(out.contains("\n=> "));
+    }
+
+    #[test]
+    fn test_faults_on_several_threads() {
+        // Each thread keeps only its own panics quiet, and only while it runs.
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    let program =
+                        Program::from_source("fun init() {\n    iprint(60 / iread(-1))\n}")
+                            .unwrap();
+                    let out = session(program, "input 0\ncontinue\n");
+                    assert!(out.contains("fault: attempt to divide by zero\n"));
+                    QUIET.with(Cell::get)
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(!thread.join().unwrap());
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use crate::program::Program;
+use crate::repl::{run_until, Stop};
+use crate::tvm::Tvm;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// A JSON-RPC 2.0 server for driving the machine from scripts and dashboards. Messages are JSON
+// objects, one per line, over a localhost TCP connection:
+//
+//     -> {"jsonrpc": "2.0", "id": 1, "method": "load", "params": {"path": "sq.json"}}
+//     <- {"jsonrpc": "2.0", "id": 1, "result": {...}}
+//
+// Requests are answered in order. `run` returns at once and the machine then runs in slices of
+// ticks, reading requests between slices so `pause` and `getState` can interrupt it. Things the
+// client did not ask for are pushed as notifications:
+//
+//     <- {"jsonrpc": "2.0", "method": "output", "params": {"text": "42"}}
+//     <- {"jsonrpc": "2.0", "method": "stopped", "params": {"reason": "breakpoint", ...}}
+//     <- {"jsonrpc": "2.0", "method": "halted", "params": {"ticks": 1234}}
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// The request was understood but could not be carried out, like loading a missing file.
+const FAILED: i64 = -32000;
+
+// An error response, as the code and message.
+type RpcError = (i64, String);
+
+pub struct RpcServer {
+    pub tvm: Tvm,
+    // Whether `run` was asked for and nothing has stopped it since.
+    pub running: bool,
+    // Ticks run between reads of the connection while running.
+    pub slice: usize,
+    // Notifications waiting to be sent.
+    notifications: Vec<Value>,
+}
+
+impl Default for RpcServer {
+    fn default() -> Self {
+        RpcServer::new()
+    }
+}
+
+impl RpcServer {
+    pub fn new() -> Self {
+        RpcServer {
+            // Program input is given with `input`, as there is no terminal to read it from.
+            tvm: Tvm {
+                input: Some(String::new()),
+                ..Tvm::default()
+            },
+            running: false,
+            slice: 10_000,
+            notifications: Vec::new(),
+        }
+    }
+
+    /// Serves clients on `address` one after another, for as long as the listener lasts. Only
+    /// loopback addresses are accepted, as any client can load files and run programs.
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> io::Result<()> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        if let Some(address) = addresses.iter().find(|a| !a.ip().is_loopback()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a loopback address", address),
+            ));
+        }
+        let listener = TcpListener::bind(&addresses[..])?;
+        for stream in listener.incoming() {
+            self.serve(stream?)?;
+        }
+        Ok(())
+    }
+
+    /// Serves a single client until it closes the connection. The machine is paused when it
+    /// does, and kept for the next client.
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut out = stream;
+        // Lines are read on their own thread, so the machine can run while waiting for them.
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let result = self.serve_lines(&receiver, &mut out);
+        self.running = false;
+        result
+    }
+
+    fn serve_lines(&mut self, lines: &Receiver<String>, out: &mut impl Write) -> io::Result<()> {
+        loop {
+            let line = if self.running {
+                match lines.try_recv() {
+                    Ok(line) => Some(line),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match lines.recv() {
+                    Ok(line) => Some(line),
+                    Err(_) => return Ok(()),
+                }
+            };
+            match line {
+                Some(line) => {
+                    if let Some(response) = self.handle_line(&line) {
+                        send(out, &response)?;
+                    }
+                }
+                None => self.run_slice(),
+            }
+            for notification in self.notifications.drain(..) {
+                send(out, &notification)?;
+            }
+        }
+    }
+
+    /// Answers a line holding a request, or returns None for a notification from the client.
+    pub fn handle_line(&mut self, line: &str) -> Option<Value> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, (PARSE_ERROR, e.to_string()))),
+        };
+        if !request.is_object() {
+            let error = (INVALID_REQUEST, "a request is an object".to_string());
+            return Some(error_response(Value::Null, error));
+        }
+        let id = request.get("id")?.clone();
+        let method = request["method"].as_str().unwrap_or_default();
+        Some(match self.handle(method, &request["params"]) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    /// Carries out a request, queueing any notifications it causes.
+    pub fn handle(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "load" => {
+                let path = string(params, "path")?;
+                let program = Program::from_path(path).map_err(|e| (FAILED, e.to_string()))?;
+                program.verify().map_err(|errors| {
+                    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    (FAILED, errors.join("\n"))
+                })?;
+                self.running = false;
+                self.tvm.load(program);
+                self.tvm.reset();
+                let functions: Vec<&str> = self
+                    .tvm
+                    .program
+                    .functions
+                    .iter()
+                    .map(|f| f.name.as_str())
+                    .collect();
+                Ok(json!({ "functions": functions }))
+            }
+            "step" => {
+                let count = params["count"].as_u64().unwrap_or(1).max(1);
+                self.running = false;
+                for _ in 0..count {
+                    let stop =
+                        run_until(&mut self.tvm, &mut BTreeMap::new(), self.slice, |_, _| true);
+                    if !self.stopped(stop) {
+                        break;
+                    }
+                }
+                self.push_output();
+                Ok(self.state())
+            }
+            "run" => {
+                self.running = !self.tvm.is_halted();
+                Ok(Value::Null)
+            }
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    self.push_output();
+                    self.notify("stopped", json!({ "reason": "pause" }));
+                }
+                Ok(self.state())
+            }
+            "reset" => {
+                self.running = false;
+                self.tvm.reset();
+                Ok(self.state())
+            }
+            "readMemory" => {
+                let address = number(params, "address")?;
+                let count = params["count"].as_u64().unwrap_or(1) as usize;
+                let cells = address
+                    .checked_add(count)
+                    .and_then(|end| self.tvm.memory.get(address..end))
+                    .ok_or((
+                        INVALID_PARAMS,
+                        format!("address {} is out of range", address),
+                    ))?;
+                Ok(json!(cells))
+            }
+            "writeMemory" => {
+                let address = number(params, "address")?;
+                let values = params["values"]
+                    .as_array()
+                    .ok_or((INVALID_PARAMS, "missing values".to_string()))?;
+                for (i, value) in values.iter().enumerate() {
+                    let value = value
+                        .as_i64()
+                        .and_then(|v| i32::try_from(v).ok())
+                        .ok_or((INVALID_PARAMS, format!("{} is not a cell value", value)))?;
+                    self.tvm
+                        .edit_cell(address + i, value)
+                        .map_err(|e| (INVALID_PARAMS, e.to_string()))?;
+                }
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => {
+                let functions = params["functions"]
+                    .as_array()
+                    .ok_or((INVALID_PARAMS, "missing functions".to_string()))?;
+                self.tvm.breakpoints.clear();
+                let mut verified = Vec::new();
+                for function in functions {
+                    let name = function.as_str().unwrap_or_default();
+                    let exists = self.tvm.program.functions.iter().any(|f| f.name == name);
+                    if exists {
+                        self.tvm.breakpoints.insert(name.to_string());
+                    }
+                    verified.push(exists);
+                }
+                Ok(json!({ "verified": verified }))
+            }
+            "input" => {
+                let text = string(params, "text")?;
+                if let Some(input) = &mut self.tvm.input {
+                    input.push_str(text);
+                    input.push('\n');
+                }
+                Ok(Value::Null)
+            }
+            "getState" => Ok(self.state()),
+            _ => Err((METHOD_NOT_FOUND, format!("no method {}", method))),
+        }
+    }
+
+    // Runs one slice of ticks, pausing if anything but the end of the slice stops it.
+    fn run_slice(&mut self) {
+        let stop = run_until(&mut self.tvm, &mut BTreeMap::new(), self.slice, |_, _| {
+            false
+        });
+        if stop != Stop::TickLimit {
+            self.running = false;
+            self.stopped(stop);
+        }
+        self.push_output();
+    }
+
+    // Queues the notification for a stop, returning whether execution can go on.
+    fn stopped(&mut self, stop: Stop) -> bool {
+        match stop {
+            Stop::Stepped | Stop::TickLimit | Stop::Watch { .. } => return true,
+            Stop::Halted => {
+                self.push_output();
+                self.notify("halted", json!({ "ticks": self.tvm.ticks }));
+            }
+            Stop::Breakpoint(function) => {
+                self.push_output();
+                let params = json!({ "reason": "breakpoint", "function": function });
+                self.notify("stopped", params);
+            }
+            Stop::Input => self.notify("stopped", json!({ "reason": "input" })),
+            Stop::Fault(message) => {
+                self.notify("stopped", json!({ "reason": "fault", "message": message }));
+            }
+        }
+        false
+    }
+
+    fn push_output(&mut self) {
+        if !self.tvm.stdout.is_empty() {
+            let text = std::mem::take(&mut self.tvm.stdout);
+            self.notify("output", json!({ "text": text }));
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.notifications
+            .push(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn state(&self) -> Value {
+        let tvm = &self.tvm;
+        let position = tvm.state.get_code_position();
+        let calls: Vec<String> = tvm
+            .call_stack()
+            .iter()
+            .map(|call| call.function.name.clone())
+            .collect();
+        json!({
+            "state": tvm.state.get_name(),
+            "running": self.running,
+            "halted": tvm.is_halted(),
+            "ticks": tvm.ticks,
+            "sp": tvm.stack_pointer,
+            "fp": tvm.frame_pointer,
+            "heapSize": tvm.heap_size,
+            "function": position.as_ref().map(|p| p.function.name.clone()),
+            "pc": position.as_ref().map(|p| p.path.clone()),
+            "line": position.and_then(|p| p.line),
+            "callStack": calls,
+            "next": tvm.next_instruction().map(|i| i.to_string()),
+        })
+    }
+}
+
+fn error_response(id: Value, (code, message): RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn send(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    writeln!(out, "{}", message)?;
+    out.flush()
+}
+
+fn string<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params[name]
+        .as_str()
+        .ok_or_else(|| (INVALID_PARAMS, format!("missing {}", name)))
+}
+
+fn number(params: &Value, name: &str) -> Result<usize, RpcError> {
+    params[name]
+        .as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| (INVALID_PARAMS, format!("missing {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    // A client speaking to a server on its own thread.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+        id: u64,
+        // Notifications read while waiting for a response.
+        notifications: Vec<Value>,
+    }
+
+    impl Client {
+        fn start() -> Client {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                RpcServer::new().serve(stream).unwrap();
+            });
+            let writer = TcpStream::connect(address).unwrap();
+            Client {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+                id: 0,
+                notifications: Vec::new(),
+            }
+        }
+
+        fn read(&mut self) -> Value {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+
+        fn call(&mut self, method: &str, params: Value) -> Value {
+            self.id += 1;
+            let request =
+                json!({ "jsonrpc": "2.0", "id": self.id, "method": method, "params": params });
+            send(&mut self.writer, &request).unwrap();
+            loop {
+                let message = self.read();
+                if message["id"] == self.id {
+                    return message;
+                }
+                self.notifications.push(message);
+            }
+        }
+
+        // Waits for a notification, returning it with those that came before.
+        fn wait_for(&mut self, method: &str) -> Vec<Value> {
+            loop {
+                if let Some(i) = self
+                    .notifications
+                    .iter()
+                    .position(|n| n["method"] == method)
+                {
+                    return self.notifications.drain(..=i).collect();
+                }
+                let message = self.read();
+                self.notifications.push(message);
+            }
+        }
+    }
+
+    #[test]
+    fn test_breakpoints_memory_and_halt() {
+        let mut client = Client::start();
+        let loaded = client.call("load", json!({ "path": "sq.json" }));
+        assert_eq!(loaded["result"]["functions"], json!(["sq", "init"]));
+        let set = client.call("setBreakpoints", json!({ "functions": ["sq", "cube"] }));
+        assert_eq!(set["result"]["verified"], json!([true, false]));
+
+        assert_eq!(client.call("run", json!({}))["result"], Value::Null);
+        let notifications = client.wait_for("stopped");
+        assert_eq!(notifications[0]["method"], "output");
+        assert_eq!(
+            notifications[0]["params"]["text"],
+            "Table of squares:\n1 squared equals "
+        );
+        let stopped = notifications.last().unwrap();
+        assert_eq!(stopped["params"]["reason"], "breakpoint");
+        assert_eq!(stopped["params"]["function"], "sq");
+
+        let state = client.call("getState", json!({}))["result"].clone();
+        assert_eq!(state["callStack"], json!(["sq", "init"]));
+        assert_eq!(state["running"], false);
+        let sp = state["sp"].as_u64().unwrap();
+        let top = client.call("readMemory", json!({ "address": sp + 1, "count": 1 }));
+        assert_eq!(top["result"], json!([1]));
+        // Squaring 5 instead of 1.
+        client.call("writeMemory", json!({ "address": sp + 1, "values": [5] }));
+        let state = client.call("step", json!({ "count": 2 }))["result"].clone();
+        assert_eq!(state["function"], "sq");
+
+        client.call("setBreakpoints", json!({ "functions": [] }));
+        client.call("run", json!({}));
+        let notifications = client.wait_for("halted");
+        let output: String = notifications
+            .iter()
+            .filter(|n| n["method"] == "output")
+            .map(|n| n["params"]["text"].as_str().unwrap())
+            .collect();
+        assert!(output.starts_with("25\n2 squared equals 4\n"));
+        assert_eq!(client.call("getState", json!({}))["result"]["halted"], true);
+    }
+
+    #[test]
+    fn test_pause_and_errors() {
+        let path = temp_dir("rpc-spin").join("spin.t");
+        std::fs::write(
+            &path,
+            "fun init() {\n    loop {\n        iprint(1)\n    }\n}",
+        )
+        .unwrap();
+        let mut client = Client::start();
+        client.call("load", json!({ "path": path.display().to_string() }));
+        client.call("run", json!({}));
+        client.wait_for("output");
+        let paused = client.call("pause", json!({}));
+        assert_eq!(paused["result"]["running"], false);
+        assert!(paused["result"]["ticks"].as_u64().unwrap() > 0);
+        let stopped = client.wait_for("stopped");
+        assert_eq!(stopped.last().unwrap()["params"]["reason"], "pause");
+
+        let missing = client.call("frobnicate", json!({}));
+        assert_eq!(missing["error"]["code"], METHOD_NOT_FOUND);
+        let out_of_range = client.call("readMemory", json!({ "address": 65536 }));
+        assert_eq!(out_of_range["error"]["code"], INVALID_PARAMS);
+        let not_found = client.call("load", json!({ "path": "missing.json" }));
+        assert_eq!(not_found["error"]["code"], FAILED);
+
+        send(&mut client.writer, &json!("not a request")).unwrap();
+        assert_eq!(client.read()["error"]["code"], INVALID_REQUEST);
+        writeln!(client.writer, "{{").unwrap();
+        assert_eq!(client.read()["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    fn test_listens_on_loopback_only() {
+        let error = RpcServer::new().listen("0.0.0.0:0").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "0.0.0.0:0 is not a loopback address");
+    }
+}
@@ -1,3 +1,4 @@
+use crate::log::{Category, Level};
 use crate::tvm::Tvm;
 
 pub trait StackHolder {
@@ -26,14 +27,24 @@ impl StackHolder for Tvm {
     fn pop(&mut self) -> i32 {
         self.stack_pointer += 1;
         let popped = self.memory[self.stack_pointer];
-        self.log.push_str(format!("Popping {} from stack\n", popped).as_str());
+        self.log.push(
+            self.ticks,
+            Level::Trace,
+            Category::Stack,
+            format_args!("Popping {} from stack", popped),
+        );
         // println!("Popping {} from stack", popped);
         popped
     }
 
     fn push(&mut self, value: i32) {
         // println!("Pushing {} to stack", value);
-        self.log.push_str(format!("Pushing {} to stack\n", value).as_str());
+        self.log.push(
+            self.ticks,
+            Level::Trace,
+            Category::Stack,
+            format_args!("Pushing {} to stack", value),
+        );
         self.memory[self.stack_pointer] = value;
         self.stack_pointer -= 1;
     }
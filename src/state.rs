@@ -1,9 +1,11 @@
 use crate::callable::{Callable, Caller};
 use crate::frame::{Frame, FrameEvaluator};
 use crate::instruction::Evaluator;
+use crate::log::{Category, Level};
 use crate::stack::StackHolder;
 use crate::tvm::Tvm;
 use enum_dispatch::enum_dispatch;
+use std::collections::VecDeque;
 use std::fmt::{Debug, Display, Formatter};
 
 #[cfg(test)]
@@ -20,26 +22,6 @@ pub enum TvmState {
 }
 
 impl TvmState {
-    pub fn get_previous_state(&self) -> Box<TvmState> {
-        match self {
-            TvmState::Waiting(state) => state.get_previous_state(),
-            TvmState::Call(state) => state.get_previous_state(),
-            TvmState::Eval(state) => state.get_previous_state(),
-            TvmState::FrameEval(state) => state.get_previous_state(),
-            TvmState::Halt(state) => state.get_previous_state(),
-        }
-    }
-
-    pub fn set_previous_state(&mut self, new_state: Box<TvmState>) {
-        match self {
-            TvmState::Waiting(_) => {}
-            TvmState::Call(state) => state.previous_state = new_state,
-            TvmState::Eval(state) => state.previous_state = new_state,
-            TvmState::FrameEval(state) => state.previous_state = new_state,
-            TvmState::Halt(state) => state.previous_state = new_state,
-        }
-    }
-
     pub fn set_result(&mut self, result: StateResult) {
         match self {
             TvmState::Waiting(state) => state.set_result(result),
@@ -50,14 +32,6 @@ impl TvmState {
         }
     }
 
-    pub fn get_depth(&self) -> usize {
-        if matches!(self, TvmState::Waiting(_)) {
-            1
-        } else {
-            self.get_previous_state().get_depth() + 1
-        }
-    }
-
     pub fn get_name(&self) -> String {
         match self {
             TvmState::Waiting(state) => state.to_string(),
@@ -67,16 +41,252 @@ impl TvmState {
             TvmState::Halt(state) => state.to_string(),
         }
     }
+
+    // A call of a program function rather than a native.
+    pub fn is_function_call(&self) -> bool {
+        matches!(
+            self,
+            TvmState::Call(CallState {
+                callable: Callable::Function(_),
+                ..
+            })
+        )
+    }
 }
 
 impl Display for TvmState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_name())
+    }
+}
+
+// The states the machine is in, innermost last. Each state carries on from the one below it
+// once it is done: a call returns to the frame that made it, a nested frame to its enclosing
+// frame. They are kept in a Vec rather than linked from one to the next, so guest recursion
+// costs heap rather than host stack, and the depth and parent are found without a walk.
+//
+// The bottom is always a waiting state, which is never popped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateStack {
+    states: Vec<TvmState>,
+    // How many of the states are function calls, kept up to date so it needs no walk.
+    calls: usize,
+}
+
+impl Default for StateStack {
+    fn default() -> Self {
+        StateStack {
+            states: vec![TvmState::Waiting(WaitingState)],
+            calls: 0,
+        }
+    }
+}
+
+impl StateStack {
+    pub fn new() -> Self {
+        StateStack::default()
+    }
+
+    /// The current state.
+    pub fn top(&self) -> &TvmState {
+        self.states
+            .last()
+            .expect("the waiting state is never popped")
+    }
+
+    // Must not turn the state into another kind, as the call count would then be wrong. Use
+    // `replace` for that.
+    fn top_mut(&mut self) -> &mut TvmState {
+        self.states
+            .last_mut()
+            .expect("the waiting state is never popped")
+    }
+
+    /// The state `n` below the current one, or the waiting state at the bottom if there are
+    /// not that many.
+    pub fn ancestor(&self, n: usize) -> &TvmState {
+        &self.states[self.states.len().saturating_sub(n + 1)]
+    }
+
+    /// The state the current one carries on from.
+    pub fn parent(&self) -> &TvmState {
+        self.ancestor(1)
+    }
+
+    /// How many states there are, counting the waiting state.
+    pub fn depth(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&TvmState> {
+        self.states.get(index)
+    }
+
+    pub fn push(&mut self, state: TvmState) {
+        self.calls += state.is_function_call() as usize;
+        self.states.push(state);
+    }
+
+    /// Drops the states from `depth` up, leaving the one below as the current state.
+    pub fn truncate(&mut self, depth: usize) {
+        let depth = depth.max(1).min(self.states.len());
+        let dropped = self.states[depth..]
+            .iter()
+            .filter(|state| state.is_function_call())
+            .count();
+        self.calls -= dropped;
+        self.states.truncate(depth);
+    }
+
+    /// Replaces the state at `index`, counted from the bottom.
+    pub fn replace(&mut self, index: usize, state: TvmState) {
+        self.calls += state.is_function_call() as usize;
+        self.calls -= self.states[index].is_function_call() as usize;
+        self.states[index] = state;
+    }
+
+    /// How many of the states are function calls, counting one that has not started yet.
+    pub fn call_count(&self) -> usize {
+        self.calls
+    }
+
+    /// The states with their index, innermost first.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &TvmState)> {
+        self.iter_below(self.states.len())
+    }
+
+    /// The bottom `depth` states with their index, innermost first.
+    pub fn iter_below(&self, depth: usize) -> impl Iterator<Item = (usize, &TvmState)> {
+        self.states[..depth.min(self.states.len())]
+            .iter()
+            .enumerate()
+            .rev()
+    }
+
+    /// The outermost state above the waiting state, or the waiting state if there is none.
+    pub fn root(&self) -> &TvmState {
+        &self.states[self.states.len().min(2) - 1]
+    }
+
+    pub fn get_name(&self) -> String {
+        self.top().get_name()
+    }
+
+    pub fn get_result(&self) -> StateResult {
+        self.top().get_result()
+    }
+
+    pub fn set_result(&mut self, result: StateResult) {
+        self.top_mut().set_result(result);
+    }
+}
+
+impl Display for StateStack {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, (_, state)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " <- ")?;
+            }
+            write!(f, "{}", state)?;
+        }
+        Ok(())
+    }
+}
+
+/// A change the debugger made to the machine between ticks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEdit {
+    Cell { address: usize, old: i32, new: i32 },
+    StackPointer { old: usize, new: usize },
+    FramePointer { old: usize, new: usize },
+}
+
+impl Display for MemoryEdit {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            TvmState::Waiting(state) => write!(f, "{}", state),
-            TvmState::Call(state) => write!(f, "{} <- {}", state, state.get_previous_state()),
-            TvmState::Eval(state) => write!(f, "{} <- {}", state, state.get_previous_state()),
-            TvmState::FrameEval(state) => write!(f, "{} <- {}", state, state.get_previous_state()),
-            TvmState::Halt(state) => write!(f, "{} <- {}", state, state.get_previous_state()),
+            MemoryEdit::Cell { address, old, new } => {
+                write!(f, "cell {}: {} -> {}", address, old, new)
+            }
+            MemoryEdit::StackPointer { old, new } => write!(f, "sp: {} -> {}", old, new),
+            MemoryEdit::FramePointer { old, new } => write!(f, "fp: {} -> {}", old, new),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HistoryEntry {
+    /// A state ticked, with the depth of the stack it was on top of.
+    State(usize, TvmState),
+    /// An edit made before the next tick.
+    Edit(MemoryEdit),
+}
+
+/// The last states ticked, with the edits made between them. Keeps nothing unless given a
+/// capacity, like the log, as only the debugger shows it.
+#[derive(Debug, Clone, Default)]
+pub struct StateHistory {
+    states: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl StateHistory {
+    pub const DEFAULT_CAPACITY: usize = 1_000;
+
+    pub fn new(capacity: usize) -> Self {
+        StateHistory {
+            capacity,
+            ..StateHistory::default()
+        }
+    }
+
+    pub fn off() -> Self {
+        StateHistory::default()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the size cap, dropping the oldest states if there are now too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.trim();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&mut self, depth: usize, state: TvmState) {
+        self.states.push_back(HistoryEntry::State(depth, state));
+        self.trim();
+    }
+
+    pub fn push_edit(&mut self, edit: MemoryEdit) {
+        self.states.push_back(HistoryEntry::Edit(edit));
+        self.trim();
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// The entries kept, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> + ExactSizeIterator {
+        self.states.iter()
+    }
+
+    fn trim(&mut self) {
+        while self.states.len() > self.capacity {
+            self.states.pop_front();
         }
     }
 }
@@ -94,15 +304,13 @@ pub enum StateResult {
 #[enum_dispatch(TvmState)]
 pub trait State: Debug + Display {
     fn tick(&mut self, tvm: &mut Tvm);
-    fn get_previous_state(&self) -> Box<TvmState>;
     fn get_result(&self) -> StateResult;
     fn set_result(&mut self, result: StateResult);
 }
 
 pub trait StateHolder {
-    fn get_state(&self) -> TvmState;
     fn set_state(&mut self, state: TvmState);
-    fn get_previous_state(&self) -> Box<TvmState>;
+    fn get_previous_state(&self) -> &TvmState;
     fn get_result(&self) -> StateResult;
     fn handle_result(&mut self, result: StateResult);
     fn tick(&mut self);
@@ -113,19 +321,13 @@ pub trait StateHolder {
 }
 
 impl StateHolder for Tvm {
-    fn get_state(&self) -> TvmState {
-        self.state.clone()
-    }
-
+    // Makes `state` the current state, carrying on from the one before once it is done.
     fn set_state(&mut self, state: TvmState) {
-        // println!("{} -> {}", self.state, state);
-        let temp = self.state.clone();
-        self.state = state;
-        self.state.set_previous_state(Box::new(temp));
+        self.state.push(state);
     }
 
-    fn get_previous_state(&self) -> Box<TvmState> {
-        self.state.get_previous_state()
+    fn get_previous_state(&self) -> &TvmState {
+        self.state.parent()
     }
 
     fn get_result(&self) -> StateResult {
@@ -133,90 +335,126 @@ impl StateHolder for Tvm {
     }
 
     fn handle_result(&mut self, result: StateResult) {
-        let previous_state = *self.get_previous_state();
-        // println!("Handling result: {:#?} for {}", result, previous_state);
-        // println!("Current state: {}", self.state);
         match result {
             StateResult::None => {}
             StateResult::Return => {
-                if let TvmState::Call(CallState {
-                    callable: Callable::Function(function),
-                    ..
-                }) = &self.state.clone()
-                {
+                let call = self
+                    .state
+                    .get_call_state()
+                    .map(|(index, call)| (index, call.callable.clone()));
+                if let Some((_, Callable::Function(function))) = &call {
                     // Handle the return of a function.
                     let r = self.pop();
+                    self.log.push(
+                        self.ticks,
+                        Level::Debug,
+                        Category::Call,
+                        format_args!("Returning {} from {}", r, function.name),
+                    );
                     self.stack_pointer = self.frame_pointer;
                     self.frame_pointer = self.memory[self.stack_pointer] as usize;
                     self.stack_pointer += function.args + function.locals;
                     self.push(r);
                 }
 
-                // If there is a nested frame. For instance a return statement within a loop, this will not work.
-                // For now, my rational is that the return call (within the execution of an eval state) will be within a frame eval state
-                // and that frame eval state will have a previous state of a call state. This is the call for the function that is being returned from.
-                // That call state will have a previous state of eval state. This is where we want to return to.
-                // This is only for non-native functions.
-                self.state = self.state.get_return_state().into();
+                // Nested frames (a return statement within a loop for instance) are discarded along with
+                // the function frame. The state before the call is the eval state of the caller, which was
+                // left pointing after the call instruction. The entry point has no caller, so returning
+                // from it halts.
+                match call {
+                    Some((index, _))
+                        if matches!(self.state.get(index - 1), Some(TvmState::Eval(_))) =>
+                    {
+                        self.state.truncate(index)
+                    }
+                    _ => self.state.push(TvmState::Halt(HaltState)),
+                }
             }
             StateResult::Break => {
                 // Assume break is in a loop somewhere.
-                let loop_state = self.state.get_loop_frame_eval_state();
-                if let Some(state) = loop_state {
-                    let mut temp = state;
-                    if let TvmState::Eval(EvalState { frame, .. }) = &mut temp {
-                        if frame.pc < frame.data.len() {
-                            frame.pc += 2;
-                        }
+                if let Some(index) = self.state.get_loop_frame_eval_state() {
+                    self.state.truncate(index + 1);
+                    if let TvmState::Eval(EvalState { frame, .. }) = self.state.top_mut() {
+                        // Skip over the loop body.
+                        frame.pc += 1;
                     }
-                    self.state = temp;
                 }
             }
             StateResult::Continue => {}
             StateResult::Halt => {
-                self.state = TvmState::Halt(HaltState {
-                    previous_state: Box::new(previous_state),
-                });
+                let index = self.state.depth() - 1;
+                self.state.replace(index, TvmState::Halt(HaltState));
             }
             StateResult::Exit => {
                 // Exit should exit the frame not the program.
-                self.log.push_str(format!("current state: {}\n", self.state.get_name()).as_str());
-                if let TvmState::Eval(EvalState { frame, .. }) = &self.state {
-                    self.log.push_str(format!("current frame: {}, pc: {}\n", frame.name, frame.pc).as_str());
-                    // frame.pc += 1;
+                self.log.push(
+                    self.ticks,
+                    Level::Debug,
+                    Category::State,
+                    format_args!("current state: {}", self.state.get_name()),
+                );
+                if let TvmState::Eval(EvalState { frame, .. }) = self.state.top() {
+                    self.log.push(
+                        self.ticks,
+                        Level::Debug,
+                        Category::State,
+                        format_args!("current frame: {}, pc: {}", frame.name, frame.pc),
+                    );
                 }
                 // get enclosing frame.
-                let mut enclosing_state = *previous_state.get_previous_state();
-                self.log.push_str(format!("enclosing state: {}\n", enclosing_state.get_name()).as_str());
-                if let TvmState::Eval(EvalState { frame, .. }) = &mut enclosing_state {
-                    self.log.push_str(format!("enclosing frame: {}, pc: {}\n", frame.name, frame.pc).as_str());
-                    frame.pc += 1;
-                } else {
-                    self.state = TvmState::Halt(HaltState {
-                        previous_state: Box::new(previous_state),
-                    });
-                    return;
+                let enclosing_state = self.state.ancestor(2);
+                self.log.push(
+                    self.ticks,
+                    Level::Debug,
+                    Category::State,
+                    format_args!("enclosing state: {}", enclosing_state.get_name()),
+                );
+                match enclosing_state {
+                    // The enclosing frame already points past the instruction that started this frame.
+                    TvmState::Eval(EvalState { frame, .. }) => {
+                        self.log.push(
+                            self.ticks,
+                            Level::Debug,
+                            Category::State,
+                            format_args!("enclosing frame: {}, pc: {}", frame.name, frame.pc),
+                        );
+                        let depth = self.state.depth();
+                        self.state.truncate(depth - 2);
+                    }
+                    // Falling off the end of a function returns the top of the stack.
+                    TvmState::Call(_) => self.handle_result(StateResult::Return),
+                    _ => {
+                        let index = self.state.depth() - 1;
+                        self.state.replace(index, TvmState::Halt(HaltState));
+                    }
                 }
-                self.state = enclosing_state;
             }
         }
     }
 
     fn tick(&mut self) {
-        self.log.push_str(format!("Tick {}: {}\n", self.ticks, self.state.get_name()).as_str());
-        self.state_history.push(self.state.clone());
-        let mut temp_state = self.state.clone();
-        // println!("Ticking: {}", temp_state);
+        // Naming the state allocates, so it is skipped when nothing would be logged.
+        if self.log.is_enabled(Level::Debug) {
+            self.log.push(
+                self.ticks,
+                Level::Debug,
+                Category::State,
+                format_args!("Tick {}: {}", self.ticks, self.state.get_name()),
+            );
+        }
+        // Cloning the state is skipped too when no history is kept.
+        if self.state_history.is_enabled() {
+            self.state_history
+                .push(self.state.depth(), self.state.top().clone());
+        }
+        let index = self.state.depth() - 1;
+        let mut temp_state = self.state.top().clone();
         temp_state.tick(self); // This is so the PC can persist. Hopefully.
-        temp_state.set_result(self.get_result()); // Update temp state with result.
-        if matches!(temp_state, TvmState::Eval(_)) && matches!(self.state, TvmState::Eval(_)) {
-            self.state = temp_state;
-        } else if matches!(self.state, TvmState::Call(_))
-            && !matches!(temp_state, TvmState::Call(_))
-        {
-            // println!("Setting state to: {}", temp_state);
-            // Assuming that temp_state is an EvalState, when we evaluate call state, we want to go back to the temp_state with incremented PC.
-            self.state.set_previous_state(Box::new(temp_state)); // This is so the PC can persist. Hopefully. After the call, we want to go back to the EvalState.
+        if matches!(temp_state, TvmState::Eval(_)) {
+            // The ticked copy has its PC moved on. Any call or nested frame it started is on top
+            // of it and returns to it with that PC.
+            temp_state.set_result(self.get_result());
+            self.state.replace(index, temp_state);
         }
         self.handle_result(self.get_result());
         self.ticks += 1;
@@ -226,7 +464,6 @@ impl StateHolder for Tvm {
         self.set_state(
             CallState {
                 callable,
-                previous_state: Box::new(self.get_state()),
                 result: StateResult::Continue,
             }
             .into(),
@@ -237,7 +474,6 @@ impl StateHolder for Tvm {
         self.set_state(
             FrameEvalState {
                 frame,
-                previous_state: Box::new(self.get_state()),
                 result: StateResult::Continue,
             }
             .into(),
@@ -248,7 +484,6 @@ impl StateHolder for Tvm {
         self.set_state(
             EvalState {
                 frame,
-                previous_state: Box::new(self.get_state()),
                 result: StateResult::Continue,
             }
             .into(),
@@ -256,7 +491,7 @@ impl StateHolder for Tvm {
     }
 
     fn should_continue(&self) -> bool {
-        !matches!(self.state, TvmState::Halt(_))
+        !matches!(self.state.top(), TvmState::Halt(_))
     }
 }
 
@@ -264,13 +499,7 @@ impl StateHolder for Tvm {
 pub struct WaitingState;
 
 impl State for WaitingState {
-    fn tick(&mut self, tvm: &mut Tvm) {
-        tvm.state = self.clone().into();
-    }
-
-    fn get_previous_state(&self) -> Box<TvmState> {
-        Box::new(TvmState::Waiting(Self))
-    }
+    fn tick(&mut self, _tvm: &mut Tvm) {}
 
     fn get_result(&self) -> StateResult {
         StateResult::None
@@ -295,20 +524,14 @@ impl Display for WaitingState {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CallState {
     pub callable: Callable,
-    pub previous_state: Box<TvmState>,
     pub result: StateResult,
 }
 
 impl State for CallState {
     fn tick(&mut self, tvm: &mut Tvm) {
-        tvm.state = self.clone().into();
         tvm.do_call(self.callable.clone());
     }
 
-    fn get_previous_state(&self) -> Box<TvmState> {
-        self.previous_state.clone()
-    }
-
     fn get_result(&self) -> StateResult {
         self.result.clone()
     }
@@ -323,7 +546,6 @@ impl Default for CallState {
     fn default() -> Self {
         Self {
             callable: Callable::Native(NativeFunction::Unknown(-999)),
-            previous_state: Box::new(WaitingState::default().into()),
             result: StateResult::Continue,
         }
     }
@@ -338,20 +560,14 @@ impl Display for CallState {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct EvalState {
     pub frame: Frame,
-    pub previous_state: Box<TvmState>,
     pub result: StateResult,
 }
 
 impl State for EvalState {
     fn tick(&mut self, tvm: &mut Tvm) {
-        tvm.state = self.clone().into();
         tvm.do_eval(&mut self.frame);
     }
 
-    fn get_previous_state(&self) -> Box<TvmState> {
-        self.previous_state.clone()
-    }
-
     fn get_result(&self) -> StateResult {
         self.result.clone()
     }
@@ -366,7 +582,6 @@ impl Default for EvalState {
     fn default() -> Self {
         Self {
             frame: Frame::default(),
-            previous_state: Box::new(WaitingState::default().into()),
             result: StateResult::Continue,
         }
     }
@@ -375,7 +590,11 @@ impl Default for EvalState {
 impl Display for EvalState {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         if self.frame.pc < self.frame.data.len() {
-            write!(f, "EvalState {} {} - {}", self.frame.name, self.frame.pc, self.frame.data[self.frame.pc])
+            write!(
+                f,
+                "EvalState {} {} - {}",
+                self.frame.name, self.frame.pc, self.frame.data[self.frame.pc]
+            )
         } else {
             write!(f, "EvalState {} {}", self.frame.name, self.frame.pc)
         }
@@ -386,20 +605,14 @@ impl Display for EvalState {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FrameEvalState {
     pub frame: Frame,
-    pub previous_state: Box<TvmState>,
     pub result: StateResult,
 }
 
 impl State for FrameEvalState {
     fn tick(&mut self, tvm: &mut Tvm) {
-        tvm.state = self.clone().into();
         tvm.do_frame_eval(self.frame.clone());
     }
 
-    fn get_previous_state(&self) -> Box<TvmState> {
-        self.previous_state.clone()
-    }
-
     fn get_result(&self) -> StateResult {
         self.result.clone()
     }
@@ -414,7 +627,6 @@ impl Default for FrameEvalState {
     fn default() -> Self {
         Self {
             frame: Frame::default(),
-            previous_state: Box::new(WaitingState::default().into()),
             result: StateResult::Continue,
         }
     }
@@ -427,19 +639,10 @@ impl Display for FrameEvalState {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct HaltState {
-    pub previous_state: Box<TvmState>,
-}
+pub struct HaltState;
 
 impl State for HaltState {
-    fn tick(&mut self, tvm: &mut Tvm) {
-        tvm.state = self.clone().into();
-        // println!("HaltState");
-    }
-
-    fn get_previous_state(&self) -> Box<TvmState> {
-        self.previous_state.clone()
-    }
+    fn tick(&mut self, _tvm: &mut Tvm) {}
 
     fn get_result(&self) -> StateResult {
         StateResult::Halt
@@ -451,9 +654,7 @@ impl State for HaltState {
 #[cfg(test)]
 impl Default for HaltState {
     fn default() -> Self {
-        Self {
-            previous_state: Box::new(WaitingState::default().into()),
-        }
+        Self
     }
 }
 
@@ -1,105 +1,123 @@
-use crate::frame::FrameData;
+use crate::callable::Callable;
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
 use crate::instruction::Instruction;
-use crate::state::{EvalState, TvmState};
-
-impl TvmState {
-    // Gets
-    pub fn get_return_state(&self) -> EvalState {
-        let mut state = self.clone();
-        while !matches!(state, TvmState::Call(_)) {
-            if let TvmState::Waiting(_) = state {
-                panic!("Waiting state has no return state");
-            }
-            state = *state.get_previous_state();
-        }
-        if let TvmState::Eval(state) = *state.get_previous_state() {
-            state
-        } else {
-            panic!("Expected EvalState. Found {}", *state.get_previous_state());
+use crate::state::{CallState, EvalState, StateStack, TvmState};
+use std::sync::Arc;
+
+// Where execution is within the function currently being evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodePosition {
+    pub function: Arc<Function>,
+    // Index of each nested frame being evaluated, ending with the PC of the innermost frame.
+    pub path: Vec<usize>,
+    // Source line of the innermost frame, if the tape has debug info.
+    pub line: Option<usize>,
+}
+
+impl StateStack {
+    // Gets the eval state the current call returns to.
+    pub fn get_return_state(&self) -> &EvalState {
+        let (index, _) = self
+            .get_call_state()
+            .expect("Waiting state has no return state");
+        match self.get(index - 1) {
+            Some(TvmState::Eval(state)) => state,
+            state => panic!("Expected EvalState. Found {:?}", state),
         }
     }
 
-    // Finds out if a previous frame is evaluating a loop.
+    // Finds the call that the current frame belongs to, with its index.
+    pub fn get_call_state(&self) -> Option<(usize, &CallState)> {
+        self.iter().find_map(|(index, state)| match state {
+            TvmState::Call(call) => Some((index, call)),
+            _ => None,
+        })
+    }
+
+    // Finds out if the current frame is the body of a loop. The enclosing frame is left
+    // pointing at the loop body while the body is evaluated.
     pub fn check_in_loop(&self) -> bool {
-        let mut state = self.clone();
-        // This will have optimal performance every time...
-        loop {
-            if let TvmState::Waiting(_) = state {
-                return false;
-            }
-            if let TvmState::Eval(EvalState { frame, .. }) = &mut state {
-                if !frame.data.is_empty() {
-                    let mut pc = frame.pc;
-                    if pc >= frame.data.len() {
-                        pc = frame.data.len() - 1;
-                    }
-                    if matches!(
-                    frame.data[pc],
-                    FrameData::Instruction(Instruction::Loop { .. }, _)
-                ) {
-                        return true;
-                    }
-                }
-            }
-            state = *state.get_previous_state();
-        }
+        matches!(self.ancestor(2), TvmState::Eval(EvalState { frame, .. }) if frame.is_evaluating_loop())
     }
 
-    pub fn get_loop_frame_eval_state(&self) -> Option<TvmState> {
-        let mut state = self.clone();
-        // This will have optimal performance every time...
-        loop {
-            if let TvmState::Waiting(_) = state {
-                return None;
-            }
-            if let TvmState::Eval(EvalState { frame, .. }) = state.clone() {
-                let mut pc = frame.pc;
-                if pc >= frame.data.len() {
-                    pc = frame.data.len() - 1;
-                }
-                if matches!(
-                    frame.data[pc],
-                    FrameData::Instruction(Instruction::Loop { .. }, _)
-                ) {
-                    return Some(state);
+    // Finds the index of the innermost frame that is evaluating a loop.
+    pub fn get_loop_frame_eval_state(&self) -> Option<usize> {
+        self.iter().find_map(|(index, state)| match state {
+            TvmState::Eval(EvalState { frame, .. }) if frame.is_evaluating_loop() => Some(index),
+            _ => None,
+        })
+    }
+
+    pub fn get_code_position(&self) -> Option<CodePosition> {
+        self.get_code_position_below(self.depth())
+    }
+
+    // Where execution would be with only the bottom `depth` states, as in a caller whose call
+    // is in progress.
+    pub fn get_code_position_below(&self, depth: usize) -> Option<CodePosition> {
+        let mut frames = Vec::new();
+        let mut function = None;
+        for (_, state) in self.iter_below(depth) {
+            match state {
+                TvmState::Waiting(_) => return None,
+                TvmState::Call(CallState {
+                    callable: Callable::Function(call),
+                    ..
+                }) => {
+                    function = Some(Arc::clone(call));
+                    break;
                 }
+                TvmState::Eval(EvalState { frame, .. }) => frames.push(frame),
+                _ => {}
             }
-            state = *state.get_previous_state();
         }
-    }
-
-    pub fn get_root_state(self) -> TvmState {
-        if self.is_root_state() {
-            return self;
+        let function = function?;
+        frames.reverse();
+        // Every nested frame knows where it sits in the frame that entered it.
+        let mut path: Vec<usize> = frames.iter().skip(1).map(|frame| frame.index).collect();
+        let line = frames.last().and_then(|frame| frame.get_line());
+        if let Some(innermost) = frames.last() {
+            path.push(innermost.pc);
         }
-        self.get_previous_state().get_root_state()
+        Some(CodePosition {
+            function,
+            path,
+            line,
+        })
     }
+}
 
-    // Just check if the previous state is waiting. If it is, then we can assume that it is the root state.
-    pub fn is_root_state(&self) -> bool {
-        matches!(*self.get_previous_state(), TvmState::Waiting(_))
+impl Frame {
+    // Whether the previous instruction was a LOOP, so the current data is its body.
+    pub fn is_evaluating_loop(&self) -> bool {
+        self.pc > 0
+            && matches!(
+                self.data.get(self.pc - 1),
+                Some(FrameData::Instruction(Instruction::Loop { .. }, _))
+            )
     }
 }
 
 #[cfg(test)]
 mod state_builder {
-    use crate::state::{CallState, EvalState, FrameEvalState, HaltState, TvmState, WaitingState};
+    use crate::state::{
+        CallState, EvalState, FrameEvalState, HaltState, StateStack, TvmState, WaitingState,
+    };
 
     pub struct StateBuilder {
-        state: TvmState
+        state: StateStack,
     }
 
     impl StateBuilder {
         pub fn new() -> Self {
             Self {
-                state: TvmState::Waiting(WaitingState)
+                state: StateStack::new(),
             }
         }
 
         fn add_state(&mut self, state: TvmState) {
-            let mut temp = state;
-            temp.set_previous_state(Box::new(self.state.clone()));
-            self.state = temp;
+            self.state.push(state);
         }
 
         pub fn waiting(mut self) -> Self {
@@ -123,32 +141,140 @@ mod state_builder {
         }
 
         pub fn halt(mut self) -> Self {
-            self.add_state(HaltState::default().into());
+            self.add_state(HaltState.into());
             self
         }
 
-        pub fn build(self) -> TvmState {
+        pub fn build(self) -> StateStack {
             self.state
         }
     }
 
-    impl From<TvmState> for StateBuilder {
-        fn from(state: TvmState) -> Self {
-            Self {
-                state
-            }
+    impl From<StateStack> for StateBuilder {
+        fn from(state: StateStack) -> Self {
+            Self { state }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::state::{EvalState, TvmState};
     use super::state_builder::*;
+    use crate::program::Program;
+    use crate::stack::StackHolder;
+    use crate::state::{EvalState, StateHolder};
+    use crate::tvm::Tvm;
+
+    fn tick_until(tvm: &mut Tvm, condition: impl Fn(&Tvm) -> bool) {
+        while !condition(tvm) {
+            assert!(!tvm.is_halted());
+            tvm.tick();
+        }
+    }
+
+    #[test]
+    fn test_get_code_position() {
+        let mut tvm = Tvm::default();
+        assert_eq!(tvm.state.get_code_position(), None);
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        tvm.start();
+        tvm.tick();
+        tvm.tick();
+        let position = tvm.state.get_code_position().unwrap();
+        assert_eq!(position.function.name, "init");
+        assert_eq!(position.path, vec![0]);
+        assert_eq!(position.line, None);
+
+        // Inside the loop body, about to call sq.
+        tick_until(
+            &mut tvm,
+            |tvm| matches!(tvm.state.get_code_position(), Some(position) if position.path == vec![12, 24]),
+        );
+        let position = tvm.state.get_code_position().unwrap();
+        assert_eq!(position.function.name, "init");
+
+        tick_until(
+            &mut tvm,
+            |tvm| matches!(tvm.state.get_code_position(), Some(position) if !position.path.is_empty() && position.function.name == "sq"),
+        );
+        let position = tvm.state.get_code_position().unwrap();
+        assert_eq!(position.path, vec![0]);
+    }
+
+    #[test]
+    fn test_get_code_position_in_if() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_source(
+                "fun init() {\n    if 0 {\n        nl()\n    } else {\n        iprint(7)\n    }\n}",
+            )
+            .unwrap(),
+        );
+        tvm.start();
+        tick_until(
+            &mut tvm,
+            |tvm| matches!(tvm.state.get_code_position(), Some(position) if position.path.len() == 2),
+        );
+        let position = tvm.state.get_code_position().unwrap();
+        assert_eq!(position.path, vec![4, 0]);
+        assert_eq!(position.line, Some(5));
+    }
+
+    #[test]
+    fn test_get_code_position_in_taken_branch_of_loop() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_source(
+                "fun init() {\n    loop {\n        until 0\n        if 1 {\n            nl()\n        }\n    }\n}",
+            )
+            .unwrap(),
+        );
+        tvm.start();
+        tick_until(
+            &mut tvm,
+            |tvm| matches!(tvm.state.get_code_position(), Some(position) if position.path.len() == 3),
+        );
+        // The loop body, then the first of the two frames after the IF at 5.
+        let position = tvm.state.get_code_position().unwrap();
+        assert_eq!(position.path, vec![1, 6, 0]);
+        assert_eq!(position.line, Some(5));
+    }
+
+    #[test]
+    fn test_deep_recursion() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_source(
+                "fun down(n) {\n    if .n < 1 {\n        return 0\n    }\n    return down(.n - 1) + 1\n}\n\nfun init() {\n    iprint(down(15000))\n}",
+            )
+            .unwrap(),
+        );
+        let mut deep = String::new();
+        while !tvm.is_halted() {
+            tvm.step();
+            if deep.is_empty() && tvm.state.depth() == 45_000 {
+                // Showing the whole stack does not recurse either.
+                deep = tvm.state.to_string();
+                // The running call count agrees with a walk of the states.
+                let calls = tvm
+                    .state
+                    .iter()
+                    .filter(|(_, s)| s.is_function_call())
+                    .count();
+                assert_eq!(tvm.call_depth(), calls);
+                assert!(calls > 10_000);
+            }
+        }
+        assert_eq!(tvm.stdout, "15000");
+        assert!(deep.ends_with(" <- WaitingState"));
+        assert_eq!(deep.matches(" <- ").count(), 44_999);
+    }
 
     #[test]
     fn test_get_return_state() {
+        // A call returns to the eval state of the frame that made it, which sits below the call.
         let state = StateBuilder::new()
+            .eval()
             .call()
             .frame_eval()
             .eval()
@@ -158,4 +284,154 @@ mod tests {
 
         assert!(matches!(state.get_return_state(), EvalState { .. }));
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "Expected EvalState")]
+    fn test_entry_point_has_no_return_state() {
+        // The entry point is called straight from the waiting state.
+        StateBuilder::new()
+            .call()
+            .frame_eval()
+            .eval()
+            .build()
+            .get_return_state();
+    }
+
+    #[test]
+    fn test_get_call_state() {
+        let state = StateBuilder::new().call().frame_eval().eval().build();
+        assert!(state.get_call_state().is_some());
+
+        let state = StateBuilder::new().waiting().halt().build();
+        assert!(state.get_call_state().is_none());
+        assert!(state.get_code_position().is_none());
+    }
+
+    fn run_assembly(source: &str) -> Tvm {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_assembly(source).unwrap());
+        tvm.start();
+        while !tvm.is_halted() {
+            assert!(tvm.ticks < 10_000, "did not halt: {}", tvm.stdout);
+            tvm.tick();
+        }
+        tvm
+    }
+
+    #[test]
+    fn test_if_branch_runs_once() {
+        // The taken branch returns to the enclosing frame past the IF rather than evaluating
+        // the IF again.
+        let tvm = run_assembly(
+            r#"
+.init 0 0:
+    PUSH    1
+    IF
+        PUSH    7
+        CALL    iprint
+        POP
+    ELSE
+        PUSH    8
+        CALL    iprint
+        POP
+    PUSH    9
+    CALL    iprint
+    POP
+"#,
+        );
+        assert_eq!(tvm.stdout, "79");
+    }
+
+    #[test]
+    fn test_frame_nested_in_loop_body() {
+        // Leaving a branch inside a loop body continues the body; only the end of the body
+        // itself goes round the loop again.
+        let tvm = run_assembly(
+            r#"
+.init 0 1:
+    LOOP
+        PUSH    1
+        FPPLUS
+        FETCH
+        PUSH    3
+        GEQ
+        BREAK
+        PUSH    1
+        IF
+            PUSH    1
+            CALL    iprint
+            POP
+        PUSH    1
+        FPPLUS
+        PUSH    1
+        FPPLUS
+        FETCH
+        PUSH    1
+        ADD
+        STORE
+    PUSH    9
+    CALL    iprint
+    POP
+"#,
+        );
+        assert_eq!(tvm.stdout, "1119");
+    }
+
+    #[test]
+    fn test_return_restores_frame() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_assembly(
+                r#"
+.f 1 2:
+    PUSH    1
+    FPPLUS
+    PUSH    4
+    STORE
+    PUSH    3
+    FPPLUS
+    FETCH
+    RETURN
+
+.init 0 0:
+    PUSH    5
+    CALL    .f
+    CALL    iprint
+    POP
+"#,
+            )
+            .unwrap(),
+        );
+        tvm.start();
+        let mut before = (tvm.stack_pointer, tvm.frame_pointer);
+        while tvm.call_depth() < 2 {
+            before = (tvm.stack_pointer, tvm.frame_pointer);
+            tvm.tick();
+        }
+        tick_until(&mut tvm, |tvm| tvm.call_depth() == 1);
+        // The argument has been replaced by the result and the caller's frame is back.
+        assert_eq!((tvm.stack_pointer, tvm.frame_pointer), before);
+        assert_eq!(tvm.peek(), 5);
+    }
+
+    #[test]
+    fn test_falling_off_function_returns() {
+        // Running past the last instruction of a function returns the top of the stack to
+        // the caller; only the entry point halts the machine that way.
+        let tvm = run_assembly(
+            r#"
+.f 0 0:
+    PUSH    4
+
+.init 0 0:
+    CALL    .f
+    CALL    iprint
+    POP
+    PUSH    9
+    CALL    iprint
+    POP
+"#,
+        );
+        assert_eq!(tvm.stdout, "49");
+    }
+}
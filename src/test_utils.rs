@@ -0,0 +1,13 @@
+use std::fs;
+use std::path::PathBuf;
+
+// Helpers shared by the unit tests.
+
+// An empty directory for a test's files. The id of the test process is part of it, so
+// concurrent runs of the suite do not share files.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tvm-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
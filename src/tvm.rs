@@ -1,41 +1,62 @@
-use crate::callable::Caller;
+use crate::callable::{Callable, Caller};
+use crate::frame::FrameData;
 use crate::function::Function;
+use crate::heap::Allocation;
+use crate::instruction::Instruction;
+use crate::log::{Category, Level, Log};
+use crate::native::NativeFunction;
 use crate::program::Program;
-use crate::state::{StateHolder, TvmState, WaitingState};
-use std::fmt::Display;
-use tui::widgets::{ListState, TableState};
+use crate::stack::StackHolder;
+use crate::state::{
+    CallState, EvalState, MemoryEdit, StateHistory, StateHolder, StateStack, TvmState,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Tvm {
-    pub memory: [i32; 65536],
+    pub memory: Box<[i32]>,
     pub stack_pointer: usize,
     pub frame_pointer: usize,
     pub heap_size: usize,
-    pub state: TvmState,
+    pub state: StateStack,
     pub ticks: usize,
     pub stdout: String,
-    pub program: Program,
-    pub table_state: TableState,
-    pub log: String,
-    pub log_state: ListState,
-    pub state_history: Vec<TvmState>,
+    // Scripted stdin for iread and sread. Terminal stdin is read when this is None.
+    pub input: Option<String>,
+    pub program: Arc<Program>,
+    // Off unless enabled, as only the debugger shows it.
+    pub log: Log,
+    // Off unless enabled, as only the debugger shows it.
+    pub state_history: StateHistory,
+    // Live blocks handed out by alloc, in address order.
+    pub allocations: Vec<Allocation>,
+    // Tick of the last store to each address written by the program. Off unless enabled, like
+    // the log, as only the debugger shows it.
+    pub writes: Option<HashMap<usize, usize>>,
+    // Names of the functions to stop at when they are called. Kept across resets.
+    pub breakpoints: BTreeSet<String>,
 }
 
 impl Default for Tvm {
     fn default() -> Self {
         Tvm {
-            memory: [0; 65536],
+            memory: vec![0; 65536].into_boxed_slice(),
             stack_pointer: 65535,
             frame_pointer: 65535,
             heap_size: 0,
-            state: TvmState::Waiting(WaitingState),
+            state: StateStack::new(),
             ticks: 0,
             stdout: String::new(),
-            program: Program::default(),
-            table_state: TableState::default(),
-            log: String::new(),
-            log_state: ListState::default(),
-            state_history: Vec::new(),
+            input: None,
+            program: Arc::default(),
+            log: Log::off(),
+            state_history: StateHistory::off(),
+            allocations: Vec::new(),
+            writes: None,
+            breakpoints: BTreeSet::new(),
         }
     }
 }
@@ -62,31 +83,196 @@ impl Display for Tvm {
     }
 }
 
+/// Errors from calling into the machine from Rust.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TvmError {
+    UnknownFunction(String),
+    ArgumentCount {
+        function: String,
+        expected: usize,
+        found: usize,
+    },
+    // The machine is in the middle of running something else.
+    Busy,
+    // An address outside memory, or a stack or frame pointer outside the stack.
+    OutOfRange {
+        name: String,
+        value: usize,
+    },
+    // A called function was still running after the given number of ticks.
+    TickLimit(usize),
+}
+
+impl Display for TvmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TvmError::UnknownFunction(name) => write!(f, "unknown function {}", name),
+            TvmError::ArgumentCount {
+                function,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} takes {} arguments but {} were given",
+                function, expected, found
+            ),
+            TvmError::Busy => write!(f, "the machine is already running"),
+            TvmError::OutOfRange { name, value } => write!(f, "{} {} is out of range", name, value),
+            TvmError::TickLimit(ticks) => write!(f, "no return after {} ticks", ticks),
+        }
+    }
+}
+
+impl std::error::Error for TvmError {}
+
+/// A function of the loaded program, by name or by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionRef<'a> {
+    Name(&'a str),
+    Id(usize),
+}
+
+impl<'a> From<&'a str> for FunctionRef<'a> {
+    fn from(name: &'a str) -> Self {
+        FunctionRef::Name(name)
+    }
+}
+
+impl From<usize> for FunctionRef<'_> {
+    fn from(id: usize) -> Self {
+        FunctionRef::Id(id)
+    }
+}
+
 impl Tvm {
+    /// Calls a function of the loaded program with `args` and runs it for at most `max_ticks`
+    /// until it returns, giving its return value. Memory is kept between calls, and the stack is
+    /// left as it was found, so the machine can be called again or started afterwards. That
+    /// includes a call that hits the limit.
+    pub fn call_function<'a>(
+        &mut self,
+        function: impl Into<FunctionRef<'a>>,
+        args: &[i32],
+        max_ticks: usize,
+    ) -> Result<i32, TvmError> {
+        let function = match function.into() {
+            FunctionRef::Name(name) => self
+                .program
+                .functions
+                .iter()
+                .find(|f| f.name == name)
+                .ok_or_else(|| TvmError::UnknownFunction(name.to_string()))?,
+            FunctionRef::Id(id) => self
+                .program
+                .functions
+                .get(id)
+                .ok_or_else(|| TvmError::UnknownFunction(id.to_string()))?,
+        }
+        .clone();
+        if function.args != args.len() {
+            return Err(TvmError::ArgumentCount {
+                function: function.name.clone(),
+                expected: function.args,
+                found: args.len(),
+            });
+        }
+        if !matches!(self.state.top(), TvmState::Waiting(_) | TvmState::Halt(_)) {
+            return Err(TvmError::Busy);
+        }
+        let (stack_pointer, frame_pointer) = (self.stack_pointer, self.frame_pointer);
+        // Arguments are pushed first to last, as a CALL instruction expects them.
+        for arg in args {
+            self.push(*arg);
+        }
+        self.state = StateStack::new();
+        self.call(Callable::Function(function));
+        // With no caller to return to, returning from the function halts.
+        let mut ticks = 0;
+        while !self.is_halted() {
+            if ticks == max_ticks {
+                self.state = StateStack::new();
+                self.stack_pointer = stack_pointer;
+                self.frame_pointer = frame_pointer;
+                return Err(TvmError::TickLimit(max_ticks));
+            }
+            self.tick();
+            ticks += 1;
+        }
+        self.state = StateStack::new();
+        Ok(self.pop())
+    }
+
+    /// Calls the entry point of the loaded program. Ticks then evaluate it.
     pub fn start(&mut self) {
         self.call(self.get_callable(self.program.entry_point as i32));
     }
 
-    pub fn load(&mut self, program: Program) {
-        self.program = program;
-        self.heap_size = self.program.heap_size as usize;
+    /// Advances the machine by one tick, starting the program first if it is not running yet.
+    pub fn step(&mut self) {
+        if matches!(self.state.top(), TvmState::Waiting(_)) {
+            self.start();
+        }
+        self.tick();
+    }
+
+    /// Runs until the program halts or `max_ticks` have passed, returning whether it halted.
+    pub fn run(&mut self, max_ticks: usize) -> bool {
+        while !self.is_halted() && self.ticks < max_ticks {
+            self.step();
+        }
+        self.is_halted()
+    }
+
+    /// Loads a program and its initial heap. The machine is not started.
+    pub fn load(&mut self, program: impl Into<Arc<Program>>) {
+        self.program = program.into();
+        self.heap_size = self.program.heap_size;
         for (location, value) in &self.program.heap {
             self.memory[*location] = *value;
         }
     }
 
+    /// Clears memory and output and reloads the current program.
     pub fn reset(&mut self) {
-        self.memory = [0; 65536];
+        self.memory = vec![0; 65536].into_boxed_slice();
         self.stack_pointer = 65535;
         self.frame_pointer = 65535;
         self.heap_size = 0;
-        self.state = TvmState::Waiting(WaitingState);
+        self.state = StateStack::new();
         self.ticks = 0;
         self.stdout = String::new();
-        self.table_state = TableState::default();
-        self.state_history = Vec::new();
-        self.log.push_str("Reset\n");
-        self.load(self.program.clone());
+        self.state_history.clear();
+        self.allocations = Vec::new();
+        if let Some(writes) = &mut self.writes {
+            writes.clear();
+        }
+        self.log.push(
+            self.ticks,
+            Level::Info,
+            Category::Debugger,
+            format_args!("Reset"),
+        );
+        self.load(Arc::clone(&self.program));
+    }
+
+    /// Reads a line of input for iread and sread, from the scripted input if there is one.
+    /// Scripted input is used up a line at a time and reads as empty once exhausted.
+    pub fn read_line(&mut self, prompt: &str) -> String {
+        match &mut self.input {
+            Some(input) => {
+                let end = input.find('\n').map_or(input.len(), |i| i + 1);
+                input.drain(..end).collect()
+            }
+            None => {
+                print!("{}", prompt);
+                let _ = std::io::stdout().flush();
+                let mut line = String::new();
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .expect("Failed to read line");
+                line
+            }
+        }
     }
 
     pub fn a2s(&mut self, address: usize) -> String {
@@ -99,13 +285,84 @@ impl Tvm {
         s
     }
 
+    /// Writes a cell on behalf of the program, remembering when it was written if writes are
+    /// tracked.
+    pub fn store(&mut self, address: usize, value: i32) {
+        self.memory[address] = value;
+        if let Some(writes) = &mut self.writes {
+            writes.insert(address, self.ticks);
+        }
+    }
+
+    /// Overwrites a cell from the debugger, noting the old and new value in the log and the
+    /// state history.
+    pub fn edit_cell(&mut self, address: usize, value: i32) -> Result<(), TvmError> {
+        let Some(&old) = self.memory.get(address) else {
+            return Err(TvmError::OutOfRange {
+                name: "address".to_string(),
+                value: address,
+            });
+        };
+        self.store(address, value);
+        self.record_edit(MemoryEdit::Cell {
+            address,
+            old,
+            new: value,
+        });
+        Ok(())
+    }
+
+    /// Moves the stack pointer from the debugger. It has to stay above the heap.
+    pub fn edit_stack_pointer(&mut self, value: usize) -> Result<(), TvmError> {
+        self.check_pointer("sp", value)?;
+        self.record_edit(MemoryEdit::StackPointer {
+            old: self.stack_pointer,
+            new: value,
+        });
+        self.stack_pointer = value;
+        Ok(())
+    }
+
+    /// Moves the frame pointer from the debugger. It has to stay above the heap.
+    pub fn edit_frame_pointer(&mut self, value: usize) -> Result<(), TvmError> {
+        self.check_pointer("fp", value)?;
+        self.record_edit(MemoryEdit::FramePointer {
+            old: self.frame_pointer,
+            new: value,
+        });
+        self.frame_pointer = value;
+        Ok(())
+    }
+
+    fn record_edit(&mut self, edit: MemoryEdit) {
+        self.log.push(
+            self.ticks,
+            Level::Info,
+            Category::Debugger,
+            format_args!("Edited {}", edit),
+        );
+        if self.state_history.is_enabled() {
+            self.state_history.push_edit(edit);
+        }
+    }
+
+    fn check_pointer(&self, name: &str, value: usize) -> Result<(), TvmError> {
+        if value < self.heap_size || value >= self.memory.len() {
+            return Err(TvmError::OutOfRange {
+                name: name.to_string(),
+                value,
+            });
+        }
+        Ok(())
+    }
+
     pub fn write_string(&mut self, address: usize, s: String) {
         let mut i = address;
         for c in s.chars() {
-            self.memory[i] = c as i32;
+            self.store(i, c as i32);
             i += 1;
         }
-        self.memory[i] = 0;
+        self.store(i, 0);
     }
 
     pub fn get_active_memory(&self) -> Vec<(usize, i32)> {
@@ -138,12 +395,48 @@ impl Tvm {
         s
     }
 
-    pub fn get_function(&self, id: usize) -> Function {
+    pub fn get_function(&self, id: usize) -> Arc<Function> {
         self.program.functions[id].clone()
     }
 
     pub fn is_halted(&self) -> bool {
-        matches!(self.state, TvmState::Halt(_))
+        matches!(self.state.top(), TvmState::Halt(_))
+    }
+
+    /// The function with a breakpoint that the next tick is about to call, if any.
+    pub fn at_breakpoint(&self) -> Option<&str> {
+        match self.state.top() {
+            TvmState::Call(CallState {
+                callable: Callable::Function(function),
+                ..
+            }) if self.breakpoints.contains(&function.name) => Some(&function.name),
+            _ => None,
+        }
+    }
+
+    /// The instruction the next tick executes, if the machine is about to execute one.
+    pub fn next_instruction(&self) -> Option<&Instruction> {
+        match self.state.top() {
+            TvmState::Eval(EvalState { frame, .. }) => match frame.data.get(frame.pc) {
+                Some(FrameData::Instruction(instruction, _)) => Some(instruction),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the next tick reads a line of scripted input that has not been given yet.
+    pub fn is_awaiting_input(&self) -> bool {
+        let reading = matches!(
+            self.state.top(),
+            TvmState::Call(CallState {
+                callable: Callable::Native(
+                    NativeFunction::IRead { .. } | NativeFunction::SRead { .. }
+                ),
+                ..
+            })
+        );
+        reading && self.input.as_ref().is_some_and(|input| input.is_empty())
     }
 }
 
@@ -151,7 +444,7 @@ impl Tvm {
 mod tests {
     use super::*;
     use crate::callable;
-    use crate::state::CallState;
+    use crate::state::{CallState, HistoryEntry, WaitingState};
 
     fn get_test_program() -> Program {
         Program::builder()
@@ -175,12 +468,13 @@ mod tests {
         assert_eq!(tvm.stack_pointer, 65535);
         assert_eq!(tvm.frame_pointer, 65535);
         assert_eq!(tvm.heap_size, 0);
-        assert_eq!(tvm.state, TvmState::Waiting(WaitingState));
+        assert_eq!(*tvm.state.top(), TvmState::Waiting(WaitingState));
         assert_eq!(tvm.ticks, 0);
-        assert_eq!(
-            tvm.get_previous_state(),
-            Box::new(TvmState::Waiting(WaitingState))
-        );
+        // Headless machines log nothing unless asked to.
+        assert_eq!(tvm.log.capacity(), 0);
+        assert!(!tvm.state_history.is_enabled());
+        assert_eq!(tvm.writes, None);
+        assert_eq!(tvm.get_previous_state(), &TvmState::Waiting(WaitingState));
     }
 
     #[test]
@@ -203,7 +497,7 @@ mod tests {
         let program = get_test_program();
         tvm.load(program);
         tvm.start();
-        let state = tvm.state;
+        let state = tvm.state.top();
         assert!(matches!(state, TvmState::Call(_)));
         assert!(
             matches!(state, TvmState::Call(CallState { callable: callable::Callable::Function(function), .. }) if function.id == 0 && function.name == "init")
@@ -228,6 +522,34 @@ mod tests {
         assert_eq!(tvm.a2s(0), "world");
     }
 
+    #[test]
+    fn test_sread() {
+        // sread takes a prompt address, or -1 for the default prompt, and a buffer address.
+        let mut tvm = Tvm {
+            input: Some("bob\nrest\n".to_string()),
+            ..Tvm::default()
+        };
+        tvm.load(
+            Program::from_assembly(
+                ".init 0 0:
+    PUSH    7
+    PUSH    -1
+    PUSH    20
+    CALL    sread
+    POP
+    CALL    iprint
+    POP
+",
+            )
+            .unwrap(),
+        );
+        assert!(tvm.run(100));
+        assert_eq!(tvm.a2s(20), "bob");
+        // Both arguments were popped, leaving the 7 below them, and one line of input used.
+        assert_eq!(tvm.stdout, "7");
+        assert_eq!(tvm.input.as_deref(), Some("rest\n"));
+    }
+
     #[test]
     fn test_get_active_memory() {
         let mut tvm = Tvm::default();
@@ -252,12 +574,7 @@ mod tests {
         let mut tvm = Tvm::default();
         let program = get_test_program();
         tvm.load(program);
-        assert_eq!(
-            tvm.get_stack_vec(),
-            vec![
-                (65535, 0)
-            ]
-        );
+        assert_eq!(tvm.get_stack_vec(), vec![(65535, 0)]);
     }
 
     #[test]
@@ -317,4 +634,165 @@ mod tests {
 
         assert_eq!(tvm.ticks, 12);
     }
+
+    #[test]
+    fn test_state_history() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        assert!(tvm.run(100_000));
+        assert!(tvm.state_history.is_empty());
+
+        tvm.reset();
+        tvm.state_history.set_capacity(3);
+        assert!(tvm.run(100_000));
+        // Only the last states are kept, however long the run.
+        assert_eq!(tvm.state_history.len(), 3);
+        let last = tvm.state_history.iter().last().unwrap();
+        assert!(matches!(last, HistoryEntry::State(_, TvmState::Eval(_))));
+    }
+
+    #[test]
+    fn test_edits_in_state_history() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        tvm.state_history.set_capacity(10);
+        tvm.step();
+        tvm.edit_cell(40, 42).unwrap();
+        tvm.edit_frame_pointer(65533).unwrap();
+        tvm.step();
+        // The edits sit between the states ticked before and after them.
+        let entries: Vec<&HistoryEntry> = tvm.state_history.iter().collect();
+        let edits: Vec<(usize, &MemoryEdit)> = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entry)| match entry {
+                HistoryEntry::Edit(edit) => Some((i, edit)),
+                HistoryEntry::State(..) => None,
+            })
+            .collect();
+        assert_eq!(
+            edits,
+            [
+                (
+                    1,
+                    &MemoryEdit::Cell {
+                        address: 40,
+                        old: 0,
+                        new: 42
+                    }
+                ),
+                (
+                    2,
+                    &MemoryEdit::FramePointer {
+                        old: 65534,
+                        new: 65533
+                    }
+                )
+            ]
+        );
+        assert!(matches!(entries[0], HistoryEntry::State(..)));
+        assert!(matches!(entries[3], HistoryEntry::State(..)));
+    }
+
+    #[test]
+    fn test_call_function() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        assert_eq!(tvm.call_function("sq", &[7], 1000), Ok(49));
+        assert_eq!(tvm.stack_pointer, 65535);
+        assert_eq!(tvm.frame_pointer, 65535);
+        assert_eq!(tvm.call_function(0, &[12], 1000), Ok(144));
+        assert_eq!(tvm.stack_pointer, 65535);
+
+        // Still usable for a normal run.
+        assert!(tvm.run(100_000));
+        assert!(tvm
+            .stdout
+            .starts_with("Table of squares:\n1 squared equals 1\n"));
+    }
+
+    #[test]
+    fn test_call_function_arguments() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_source(
+                "fun sub(a, b) {\n    return .a - .b\n}\nfun twice(n) {\n    var m\n    m : sub(.n, 0 - .n)\n    iprint(.m)\n    return .m\n}\nfun init() {\n}",
+            )
+            .unwrap(),
+        );
+        assert_eq!(tvm.call_function("sub", &[10, 3], 1000), Ok(7));
+        assert_eq!(tvm.call_function("twice", &[21], 1000), Ok(42));
+        assert_eq!(tvm.stdout, "42");
+    }
+
+    #[test]
+    fn test_call_function_errors() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        assert_eq!(
+            tvm.call_function("cube", &[2], 1000),
+            Err(TvmError::UnknownFunction("cube".to_string()))
+        );
+        assert_eq!(
+            tvm.call_function(5, &[], 1000),
+            Err(TvmError::UnknownFunction("5".to_string()))
+        );
+        let error = tvm.call_function("sq", &[], 1000).unwrap_err();
+        assert_eq!(error.to_string(), "sq takes 1 arguments but 0 were given");
+        tvm.step();
+        assert_eq!(tvm.call_function("sq", &[2], 1000), Err(TvmError::Busy));
+    }
+
+    #[test]
+    fn test_call_function_tick_limit() {
+        let mut tvm = Tvm::default();
+        tvm.load(
+            Program::from_source("fun spin() {\n    loop {\n    }\n}\nfun init() {\n}").unwrap(),
+        );
+        let error = tvm.call_function("spin", &[], 1000).unwrap_err();
+        assert_eq!(error, TvmError::TickLimit(1000));
+        assert_eq!(error.to_string(), "no return after 1000 ticks");
+        // Left ready for the next call.
+        assert_eq!(tvm.stack_pointer, 65535);
+        assert_eq!(tvm.frame_pointer, 65535);
+        assert_eq!(tvm.call_function("init", &[], 1000), Ok(0));
+    }
+
+    #[test]
+    fn test_edits() {
+        let mut tvm = Tvm {
+            log: Log::new(10),
+            writes: Some(HashMap::new()),
+            ..Tvm::default()
+        };
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        tvm.edit_cell(40, 42).unwrap();
+        assert_eq!(tvm.memory[40], 42);
+        assert_eq!(tvm.writes.as_ref().unwrap().get(&40), Some(&0));
+        tvm.edit_stack_pointer(65530).unwrap();
+        tvm.edit_frame_pointer(65533).unwrap();
+        assert_eq!((tvm.stack_pointer, tvm.frame_pointer), (65530, 65533));
+        let messages: Vec<&str> = tvm.log.records().map(|r| r.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "Edited cell 40: 0 -> 42",
+                "Edited sp: 65535 -> 65530",
+                "Edited fp: 65535 -> 65533"
+            ]
+        );
+        assert!(tvm.log.records().all(|r| r.category == Category::Debugger));
+        // The state history is off, so it keeps no edits either.
+        assert!(tvm.state_history.is_empty());
+
+        assert_eq!(
+            tvm.edit_cell(65536, 1).unwrap_err().to_string(),
+            "address 65536 is out of range"
+        );
+        assert_eq!(
+            tvm.edit_stack_pointer(10).unwrap_err().to_string(),
+            "sp 10 is out of range"
+        );
+        assert_eq!(tvm.stack_pointer, 65530);
+    }
 }
@@ -1,21 +1,731 @@
-use tui::widgets::{ListItem, ListState, TableState};
-use crate::state::TvmState;
+use crate::batch::panic_message;
+use crate::call_stack::{CallFrame, Variable};
+use crate::disassembler::Disassembler;
+use crate::log::{Category, Filter, Level, Log, Record};
+use crate::program::Program;
+use crate::state::{HistoryEntry, StateHistory, TvmState};
 use crate::tvm::Tvm;
+use crossterm::event::KeyModifiers;
+use crossterm::{
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    execute,
+    terminal::{
+        disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, SetTitle,
+    },
+};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{error::Error, io};
+use tui::layout::{Direction, Rect};
+use tui::text::Spans;
+use tui::widgets::{Clear, List, ListItem, ListState, Paragraph, TableState};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Cell, Row, Table},
+    Frame, Terminal,
+};
 
+/// Runs the terminal debugger on `tvm` until the user quits. `path` is the file its program was
+/// loaded from, which the debugger reloads from and opens other files next to.
+pub fn run(tvm: &mut Tvm, path: &str) -> Result<(), Box<dyn Error>> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+    let mut session = Session::new(tvm, path);
+    // Panics are reported in the UI rather than printed over it. The hook is swapped once for
+    // the session, as swapping it around every tick races with other threads panicking.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        run_tvm(&mut terminal, tvm, &mut session)
+    }));
+    panic::set_hook(hook);
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+    match res {
+        Ok(Err(err)) => eprintln!("Error: {}", err),
+        // A panic in the debugger itself, which the silent hook did not print.
+        Err(payload) => return Err(panic_message(payload).into()),
+        Ok(Ok(())) => {}
+    }
+    Ok(())
+}
+
+/// The file being debugged. Reloading reads it from disk again, so a recompiled tape is picked
+/// up without restarting the debugger.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub path: String,
+    // Text of the source file named in the debug info, if it could be read.
+    pub source: Option<String>,
+}
+
+impl Session {
+    pub fn new(tvm: &Tvm, path: &str) -> Self {
+        Session {
+            path: path.to_string(),
+            source: tvm.program.read_source(path),
+        }
+    }
+
+    /// Loads and verifies the program at `path` and resets `tvm` to run it. The machine is left
+    /// alone if the file does not load or verify. Breakpoints are kept only when reloading.
+    pub fn open(&mut self, tvm: &mut Tvm, path: &str) -> Result<(), String> {
+        let program = Program::from_path(path).map_err(|e| e.to_string())?;
+        if let Err(errors) = program.verify() {
+            return Err(format!(
+                "failed verification with {} errors, first: {}",
+                errors.len(),
+                errors[0]
+            ));
+        }
+        if path != self.path {
+            tvm.breakpoints.clear();
+        }
+        self.source = program.read_source(path);
+        self.path = path.to_string();
+        tvm.program = Arc::new(program);
+        tvm.reset();
+        Ok(())
+    }
+
+    pub fn reload(&mut self, tvm: &mut Tvm) -> Result<(), String> {
+        let path = self.path.clone();
+        self.open(tvm, &path)
+    }
+
+    /// The file name, without its directory.
+    pub fn name(&self) -> &str {
+        Path::new(&self.path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.path)
+    }
+}
+
+/// The files in `dir` the debugger can open, tapes and source files, in name order.
+pub fn program_files(dir: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let openable = path
+            .extension()
+            .is_some_and(|e| e == "json" || e == "tvm" || e == "t");
+        if openable && path.is_file() {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Lists program files to open in place of the current one.
+#[derive(Debug, Clone, Default)]
+pub struct FileDialog {
+    pub files: Vec<String>,
+    pub state: ListState,
+}
+
+/// Widget state of the terminal debugger, kept apart from the machine it shows.
+#[derive(Debug, Default)]
 pub struct TvmUI {
     pub stack_state: TableState,
+    // Selects a heap address. Only the rows from heap_offset that fit are rendered.
     pub heap_state: TableState,
+    pub heap_offset: usize,
+    pub calls_state: ListState,
     pub log_state: ListState,
+    // Which log records the log pane shows.
+    pub log_filter: Filter,
+    // How many shown records the log pane is scrolled back from the newest.
+    pub log_scroll: usize,
     pub history_state: ListState,
+    pub prompt: Option<Prompt>,
+    // Whether the machine ticks on its own.
+    pub running: bool,
+    // Index into SPEEDS.
+    pub speed: usize,
+    // Why the machine last stopped running.
+    pub pause: Option<Pause>,
+    pub dialog: Option<FileDialog>,
+    // Outcome of the last open, reload or edit, shown in the title bar.
+    pub message: Option<String>,
+    // The pane moved by the arrow keys. Cells of the stack and heap panes are edited by `e`.
+    pub focus: Pane,
 }
 
-impl Tvm {
-    pub fn selected_to_active(&self) -> usize {
-        self.get_active_memory()
-            .binary_search_by(|(k, _)| k.cmp(&self.table_state.selected().unwrap_or(0)))
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pane {
+    #[default]
+    Stack,
+    Heap,
+    Calls,
+    Log,
+}
+
+// Ticks per frame of continuous running. The last runs as many ticks as fit in a frame.
+pub const SPEEDS: [usize; 5] = [1, 10, 100, 1000, usize::MAX];
+const FRAME: Duration = Duration::from_millis(33);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pause {
+    Halted,
+    // The machine panicked, with the panic message.
+    Fault(String),
+    Breakpoint(String),
+    Input,
+}
+
+impl Display for Pause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pause::Halted => write!(f, "halted"),
+            Pause::Fault(message) => write!(f, "fault: {}", message),
+            Pause::Breakpoint(function) => write!(f, "breakpoint at {}", function),
+            Pause::Input => write!(f, "waiting for input"),
+        }
+    }
+}
+
+// A line being typed at the bottom of a pane. Keys go to the prompt while it is open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prompt {
+    pub kind: PromptKind,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptKind {
+    // Jump to a heap address.
+    HeapAddress,
+    // A line of program input for iread or sread.
+    Input,
+    // Toggle a breakpoint on a function.
+    Breakpoint,
+    // Text to look for in the log.
+    LogSearch,
+    // A new value for the memory cell at an address.
+    Cell(usize),
+    StackPointer,
+    FramePointer,
+}
+
+impl Prompt {
+    pub fn new(kind: PromptKind) -> Self {
+        Prompt {
+            kind,
+            text: String::new(),
+        }
+    }
+
+    // What is being edited, for the title of the pane showing it.
+    fn edit_title(&self) -> Option<String> {
+        let target = match self.kind {
+            PromptKind::Cell(address) => format!("cell {}", address),
+            PromptKind::StackPointer => "sp".to_string(),
+            PromptKind::FramePointer => "fp".to_string(),
+            _ => return None,
+        };
+        Some(format!(
+            "Set {} (decimal, 0x hex or 'c'): {}_",
+            target, self.text
+        ))
+    }
+}
+
+/// Parses a value typed into the memory editor: decimal, hex with a `0x` prefix, either signed,
+/// or a character in single quotes.
+pub fn parse_value(text: &str) -> Option<i32> {
+    let text = text.trim();
+    if let Some(c) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c as i32),
+            _ => None,
+        };
+    }
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<i64>().ok()?,
+    };
+    let value = if negative { -value } else { value };
+    // Hex is allowed to fill all 32 bits, so 0xffffffff is -1.
+    i32::try_from(value)
+        .ok()
+        .or_else(|| (!negative && value <= u32::MAX as i64).then_some(value as u32 as i32))
+}
+
+impl TvmUI {
+    pub fn selected_to_active(&self, tvm: &Tvm) -> usize {
+        tvm.get_active_memory()
+            .binary_search_by(|(k, _)| k.cmp(&self.stack_state.selected().unwrap_or(0)))
             .unwrap_or(0)
     }
 
+    // Selects the top of the stack, the first row of the stack pane.
+    pub fn update_stack_state(&mut self) {
+        self.stack_state.select(Some(0));
+    }
+
+    // Moves the selection of the focused memory pane by `rows`.
+    pub fn scroll(&mut self, tvm: &Tvm, rows: isize) {
+        match self.focus {
+            Pane::Stack => {
+                let selected = self.stack_state.selected().unwrap_or(0) as isize + rows;
+                let last = tvm.memory.len() - 1 - tvm.stack_pointer;
+                self.stack_state
+                    .select(Some((selected.max(0) as usize).min(last)));
+            }
+            Pane::Heap => self.scroll_heap(tvm, rows),
+            Pane::Calls => {
+                let selected = self.calls_state.selected().unwrap_or(0) as isize + rows;
+                let last = tvm.call_stack().len().saturating_sub(1);
+                self.calls_state
+                    .select(Some((selected.max(0) as usize).min(last)));
+            }
+            // Down is towards the newest record.
+            Pane::Log => {
+                let last = tvm.log.filtered(&self.log_filter).count().saturating_sub(1);
+                let scroll = self.log_scroll as isize - rows;
+                self.log_scroll = (scroll.max(0) as usize).min(last);
+            }
+        }
+    }
+
+    // Address of the selected cell of the focused memory pane.
+    pub fn selected_cell(&self, tvm: &Tvm) -> Option<usize> {
+        match self.focus {
+            Pane::Stack => Some(tvm.stack_pointer + self.stack_state.selected()?)
+                .filter(|address| *address < tvm.memory.len()),
+            Pane::Heap => self.heap_state.selected().filter(|a| *a < tvm.heap_size),
+            Pane::Calls | Pane::Log => None,
+        }
+    }
+
+    pub fn toggle_focus(&mut self) {
+        self.focus = match self.focus {
+            Pane::Stack => Pane::Heap,
+            Pane::Heap => Pane::Calls,
+            Pane::Calls => Pane::Log,
+            Pane::Log => Pane::Stack,
+        };
+    }
+
+    // Opens the editor on the selected cell.
+    pub fn edit_selected(&mut self, tvm: &Tvm) {
+        match self.selected_cell(tvm) {
+            Some(address) => self.prompt = Some(Prompt::new(PromptKind::Cell(address))),
+            None => self.message = Some("no cell selected".to_string()),
+        }
+    }
+
+    // Moves the heap selection by `rows`, keeping it on the heap.
+    pub fn scroll_heap(&mut self, tvm: &Tvm, rows: isize) {
+        let selected = self.heap_state.selected().unwrap_or(0) as isize + rows;
+        self.select_heap(tvm, selected.max(0) as usize);
+    }
+
+    pub fn select_heap(&mut self, tvm: &Tvm, address: usize) {
+        let last = tvm.heap_size.saturating_sub(1);
+        self.heap_state.select(Some(address.min(last)));
+    }
+
+    // Lists the program files in `dir`, selecting the open one.
+    pub fn open_dialog(&mut self, session: &Session, dir: &Path) {
+        match program_files(dir) {
+            Ok(files) if files.is_empty() => self.message = Some("no programs to open".to_string()),
+            Ok(files) => {
+                let mut state = ListState::default();
+                let open = files
+                    .iter()
+                    .position(|f| Path::new(f) == Path::new(&session.path));
+                state.select(Some(open.unwrap_or(0)));
+                self.dialog = Some(FileDialog { files, state });
+            }
+            Err(err) => self.message = Some(format!("unable to list {}: {}", dir.display(), err)),
+        }
+    }
+
+    fn dialog_key(&mut self, tvm: &mut Tvm, session: &mut Session, code: KeyCode) {
+        let Some(dialog) = &mut self.dialog else {
+            return;
+        };
+        let selected = dialog.state.selected().unwrap_or(0);
+        match code {
+            KeyCode::Down => dialog
+                .state
+                .select(Some((selected + 1).min(dialog.files.len() - 1))),
+            KeyCode::Up => dialog.state.select(Some(selected.saturating_sub(1))),
+            KeyCode::Enter => {
+                let path = dialog.files[selected].clone();
+                self.dialog = None;
+                let result = session.open(tvm, &path);
+                self.loaded(session, result, "opened");
+            }
+            KeyCode::Esc => self.dialog = None,
+            _ => {}
+        }
+    }
+
+    pub fn reload(&mut self, tvm: &mut Tvm, session: &mut Session) {
+        let result = session.reload(tvm);
+        self.loaded(session, result, "reloaded");
+    }
+
+    // Starts the widgets afresh on a newly loaded program, or reports why it did not load.
+    fn loaded(&mut self, session: &Session, result: Result<(), String>, done: &str) {
+        match result {
+            Ok(()) => {
+                *self = TvmUI {
+                    speed: self.speed,
+                    message: Some(done.to_string()),
+                    ..TvmUI::default()
+                };
+                self.update_stack_state();
+            }
+            Err(err) => self.message = Some(format!("unable to load {}: {}", session.path, err)),
+        }
+    }
+
+    // Handles a key while a prompt is open.
+    fn prompt_key(&mut self, tvm: &mut Tvm, code: KeyCode) {
+        let Some(prompt) = &mut self.prompt else {
+            return;
+        };
+        match code {
+            KeyCode::Char(c) if prompt.kind != PromptKind::HeapAddress || c.is_ascii_digit() => {
+                prompt.text.push(c)
+            }
+            KeyCode::Backspace => {
+                prompt.text.pop();
+            }
+            KeyCode::Enter => {
+                let prompt = self.prompt.take().unwrap();
+                match prompt.kind {
+                    PromptKind::HeapAddress => {
+                        if let Ok(address) = prompt.text.parse() {
+                            self.select_heap(tvm, address);
+                        }
+                    }
+                    PromptKind::Input => {
+                        if let Some(input) = &mut tvm.input {
+                            input.push_str(&prompt.text);
+                            input.push('\n');
+                        }
+                        self.pause = None;
+                    }
+                    PromptKind::Breakpoint => {
+                        if !tvm.breakpoints.remove(&prompt.text) {
+                            tvm.breakpoints.insert(prompt.text);
+                        }
+                    }
+                    PromptKind::LogSearch => {
+                        self.log_filter.search = prompt.text;
+                        self.log_scroll = 0;
+                    }
+                    PromptKind::Cell(_) | PromptKind::StackPointer | PromptKind::FramePointer => {
+                        self.edit(tvm, &prompt)
+                    }
+                }
+            }
+            KeyCode::Esc => {
+                // Input is still needed, so running has to stop until it is given.
+                if prompt.kind == PromptKind::Input {
+                    self.running = false;
+                }
+                self.prompt = None;
+            }
+            _ => {}
+        }
+    }
+
+    // Keys of the log pane: digits show and hide categories, `v` raises the lowest level shown.
+    pub fn log_key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char(c @ '1'..='5') => {
+                let category = Category::ALL[c as usize - '1' as usize];
+                self.log_filter.toggle(category);
+            }
+            KeyCode::Char('v') => {
+                self.log_filter.level = match self.log_filter.level {
+                    Level::Trace => Level::Debug,
+                    Level::Debug => Level::Info,
+                    Level::Info => Level::Trace,
+                }
+            }
+            _ => return false,
+        }
+        self.log_scroll = 0;
+        true
+    }
+
+    fn edit(&mut self, tvm: &mut Tvm, prompt: &Prompt) {
+        let Some(value) = parse_value(&prompt.text) else {
+            self.message = Some(format!("not a value: {}", prompt.text));
+            return;
+        };
+        let pointer = match (prompt.kind, usize::try_from(value)) {
+            (PromptKind::Cell(_), _) => 0,
+            (_, Ok(pointer)) => pointer,
+            (_, Err(_)) => {
+                self.message = Some("pointers cannot be negative".to_string());
+                return;
+            }
+        };
+        let edited = match prompt.kind {
+            PromptKind::Cell(address) => tvm.edit_cell(address, value),
+            PromptKind::StackPointer => tvm.edit_stack_pointer(pointer),
+            PromptKind::FramePointer => tvm.edit_frame_pointer(pointer),
+            _ => unreachable!(),
+        };
+        self.message = Some(match edited {
+            Ok(()) => "edited".to_string(),
+            Err(err) => err.to_string(),
+        });
+        if prompt.kind == PromptKind::StackPointer {
+            self.update_stack_state();
+        }
+    }
+
+    // A faulted tick leaves the machine half way through popping its states, so it is not run
+    // again until it is reset or reloaded.
+    fn faulted(&self) -> bool {
+        matches!(self.pause, Some(Pause::Fault(_)))
+    }
+
+    /// Starts the program, unless it has started already or faulted.
+    pub fn start(&mut self, tvm: &mut Tvm) {
+        if !self.faulted() && matches!(tvm.state.top(), TvmState::Waiting(_)) {
+            tvm.start();
+        }
+        self.update_stack_state();
+    }
+
+    pub fn toggle_running(&mut self, tvm: &mut Tvm) {
+        if self.running || tvm.is_halted() || self.faulted() {
+            self.running = false;
+        } else {
+            self.running = true;
+            self.pause = None;
+        }
+    }
+
+    pub fn change_speed(&mut self, faster: bool) {
+        self.speed = match faster {
+            true => (self.speed + 1).min(SPEEDS.len() - 1),
+            false => self.speed.saturating_sub(1),
+        };
+    }
+
+    // Runs a frame's worth of ticks while running.
+    pub fn run_frame(&mut self, tvm: &mut Tvm) {
+        let start = Instant::now();
+        let mut ticks = 0;
+        while self.running && ticks < SPEEDS[self.speed] && start.elapsed() < FRAME {
+            self.step(tvm);
+            ticks += 1;
+        }
+    }
+
+    /// Ticks once, stopping to run when the machine halts, faults, reaches a breakpoint or asks
+    /// for input that has not been typed yet.
+    pub fn step(&mut self, tvm: &mut Tvm) {
+        if self.prompt.is_some() || self.faulted() {
+            return;
+        }
+        if tvm.is_awaiting_input() {
+            self.pause = Some(Pause::Input);
+            self.prompt = Some(Prompt::new(PromptKind::Input));
+            return;
+        }
+        // ui::run keeps panics from being printed over the UI.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| tvm.step()));
+        let pause = match result {
+            Err(payload) => Some(Pause::Fault(panic_message(payload))),
+            Ok(()) if tvm.is_halted() => Some(Pause::Halted),
+            Ok(()) => tvm
+                .at_breakpoint()
+                .map(|name| Pause::Breakpoint(name.to_string())),
+        };
+        if pause.is_some() {
+            self.running = false;
+            self.pause = pause;
+        }
+        self.update_stack_state();
+    }
+
+    fn status(&self) -> String {
+        let speed = match SPEEDS[self.speed] {
+            usize::MAX => "unlimited".to_string(),
+            ticks => ticks.to_string(),
+        };
+        match (&self.prompt, self.running, &self.pause) {
+            (
+                Some(Prompt {
+                    kind: PromptKind::Input,
+                    ..
+                }),
+                ..,
+            ) => "waiting for input".to_string(),
+            (_, true, _) => format!("running, {} ticks/frame", speed),
+            (_, false, Some(pause @ Pause::Fault(_))) => {
+                format!("stopped, {}; r resets, R reloads", pause)
+            }
+            (_, false, Some(pause)) => format!("paused, {}", pause),
+            _ => format!("paused, {} ticks/frame", speed),
+        }
+    }
+}
+
+// Cells written this many ticks ago or less are highlighted in the heap pane.
+const RECENT_WRITE_TICKS: usize = 16;
+
+// A call per item, innermost first: the function and where it is, then its arguments and locals.
+fn calls_to_list_items(calls: &[CallFrame]) -> Vec<ListItem<'static>> {
+    let path = |pc: &[usize]| {
+        if pc.is_empty() {
+            // The body frame has not started yet.
+            return "-".to_string();
+        }
+        pc.iter()
+            .map(usize::to_string)
+            .collect::<Vec<_>>()
+            .join(".")
+    };
+    let variables = |variables: &[Variable]| {
+        variables
+            .iter()
+            .map(|v| format!("{}={} @{}", v.name, v.value, v.address))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    calls
+        .iter()
+        .map(|call| {
+            let mut heading = format!("{} pc {}", call.function.name, path(&call.pc));
+            if let Some(line) = call.line {
+                heading.push_str(&format!(" line {}", line));
+            }
+            match call.frame_pointer {
+                Some(fp) => heading.push_str(&format!(" fp {}", fp)),
+                None => heading.push_str(" (calling)"),
+            }
+            if let Some((caller, pc)) = &call.return_to {
+                heading.push_str(&format!(", returns to {} pc {}", caller, path(pc)));
+            }
+            let mut lines = vec![Spans::from(heading)];
+            if !call.args.is_empty() {
+                lines.push(Spans::from(format!("  args: {}", variables(&call.args))));
+            }
+            if !call.locals.is_empty() {
+                lines.push(Spans::from(format!(
+                    "  locals: {}",
+                    variables(&call.locals)
+                )));
+            }
+            ListItem::new(lines)
+        })
+        .collect()
+}
+
+// Counts and the filter in use. Hidden categories are left out of the list.
+fn log_title(tvm: &Tvm, filter: &Filter, shown: usize) -> String {
+    let mut title = format!("Log ({} of {}", shown, tvm.log.len());
+    if tvm.log.dropped > 0 {
+        title.push_str(&format!(", {} dropped", tvm.log.dropped));
+    }
+    title.push(')');
+    let categories: Vec<String> = Category::ALL
+        .iter()
+        .enumerate()
+        .filter(|(_, category)| filter.categories.contains(category))
+        .map(|(i, category)| format!("{}:{}", i + 1, category))
+        .collect();
+    title.push_str(&format!(" [{}] {}+", categories.join(" "), filter.level));
+    if !filter.search.is_empty() {
+        title.push_str(&format!(" /{}", filter.search));
+    }
+    title
+}
+
+// Borders of the focused pane are highlighted.
+fn pane_block(title: String, focused: bool) -> Block<'static> {
+    let style = match focused {
+        true => Style::default().fg(Color::Yellow),
+        false => Style::default(),
+    };
+    Block::default()
+        .borders(Borders::ALL)
+        .border_style(style)
+        .title(title)
+}
+
+fn heap_to_rows(tvm: &Tvm, range: Range<usize>) -> Vec<Row<'static>> {
+    tvm.heap_cells(range)
+        .into_iter()
+        .map(|cell| {
+            let char = if (32..127).contains(&cell.value) {
+                format!("'{}'", cell.value as u8 as char)
+            } else {
+                String::new()
+            };
+            let mut notes = Vec::new();
+            if let Some(allocation) = cell.allocation.filter(|a| a.address == cell.address) {
+                notes.push(format!("alloc {}", allocation.size));
+            }
+            if let Some(string) = &cell.string {
+                notes.push(format!("{:?}", string));
+            }
+            let address_style = match cell.allocation {
+                Some(_) => Style::default().fg(Color::Cyan),
+                None => Style::default(),
+            };
+            let char_style = match cell.in_string {
+                true => Style::default().fg(Color::Green),
+                false => Style::default(),
+            };
+            let row_style = match cell.written.map(|tick| tvm.ticks.saturating_sub(tick)) {
+                // Written by the last tick.
+                Some(age) if age <= 1 => Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+                Some(age) if age <= RECENT_WRITE_TICKS => Style::default().fg(Color::Yellow),
+                _ => Style::default(),
+            };
+            Row::new(vec![
+                Cell::from(cell.address.to_string()).style(address_style),
+                Cell::from(cell.value.to_string()),
+                Cell::from(char).style(char_style),
+                Cell::from(notes.join(" ")),
+            ])
+            .style(row_style)
+        })
+        .collect()
+}
+
+impl Tvm {
     pub fn active_to_all(&self, active: usize) -> usize {
         self.get_active_memory()[active].0
     }
@@ -26,29 +736,783 @@ impl Tvm {
             .unwrap_or(0)
     }
 
-    pub fn update_table_state(&mut self) {
-        self.table_state.select(Some(self.all_to_selected(self.stack_pointer)));
+    fn get_tabs(depth: usize) -> String {
+        " ".repeat(depth)
     }
 
-    pub fn log_to_list_items(log: &str) -> Vec<ListItem> {
-        log
-            .lines()
-            .map(|l| ListItem::new(l.to_string()))
+    pub fn state_history_to_list_items<'a>(
+        state_history: impl Iterator<Item = &'a HistoryEntry>,
+    ) -> Vec<ListItem<'a>> {
+        state_history
+            .map(|entry| match entry {
+                HistoryEntry::State(depth, l) => {
+                    ListItem::new(format!("{}{}", Self::get_tabs(*depth), l.get_name()))
+                }
+                HistoryEntry::Edit(edit) => ListItem::new(format!("edited {}", edit)),
+            })
             .collect()
     }
 
-    fn get_tabs(state: &TvmState) -> String {
-        let mut out = String::new();
-        for _ in 0..state.get_depth() {
-            out.push(' ');
+    // Title, lines and highlighted line of the code pane. Shows the original source when the tape
+    // carries line info for the current position, and the disassembled function otherwise.
+    pub fn code_to_list_items(
+        &self,
+        source: Option<&str>,
+    ) -> (String, Vec<ListItem<'static>>, Option<usize>) {
+        let position = self.state.get_code_position();
+        let source_name = self.program.debug.as_ref().and_then(|d| d.source.clone());
+        if let (Some(source), Some(name), Some(line)) =
+            (source, source_name, position.as_ref().and_then(|p| p.line))
+        {
+            let items = source
+                .lines()
+                .enumerate()
+                .map(|(i, l)| ListItem::new(format!("{:>4} {}", i + 1, l)))
+                .collect();
+            return (format!("Source: {}", name), items, Some(line - 1));
         }
-        out
+        let (function, path) = match position {
+            Some(position) => (position.function, Some(position.path)),
+            None => match self.program.functions.get(self.program.entry_point) {
+                Some(function) => (function.clone(), None),
+                None => return ("Code".to_string(), vec![], None),
+            },
+        };
+        let lines = Disassembler::new(&self.program).function(&function);
+        let selected = lines
+            .iter()
+            .position(|l| l.path.is_some() && l.path == path);
+        let items = lines.into_iter().map(|l| ListItem::new(l.text)).collect();
+        (format!("Code: {}", function.name), items, selected)
     }
+}
 
-    pub fn state_history_to_list_items(state_history: &[TvmState]) -> Vec<ListItem> {
-        state_history
+fn run_tvm<B: Backend>(
+    terminal: &mut Terminal<B>,
+    tvm: &mut Tvm,
+    session: &mut Session,
+) -> io::Result<()> {
+    let mut widgets = TvmUI::default();
+    // Program input is typed into the UI rather than read from the terminal.
+    tvm.input.get_or_insert_with(String::new);
+    if tvm.log.capacity() == 0 {
+        tvm.log.set_capacity(Log::DEFAULT_CAPACITY);
+    }
+    if !tvm.state_history.is_enabled() {
+        tvm.state_history
+            .set_capacity(StateHistory::DEFAULT_CAPACITY);
+    }
+    tvm.writes.get_or_insert_with(HashMap::new);
+    let mut title = String::new();
+    loop {
+        if title != session.path {
+            title = session.path.clone();
+            execute!(io::stdout(), SetTitle(format!("tvm: {}", session.name())))?;
+        }
+        terminal.draw(|f| ui(f, tvm, &mut widgets, session))?;
+        // Waiting for a key would stall a running machine, so the UI polls between frames.
+        if !event::poll(FRAME)? {
+            widgets.run_frame(tvm);
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if widgets.prompt.is_some() {
+                widgets.prompt_key(tvm, key.code);
+                continue;
+            }
+            if widgets.dialog.is_some() {
+                widgets.dialog_key(tvm, session, key.code);
+                continue;
+            }
+            if widgets.focus == Pane::Log && widgets.log_key(key.code) {
+                continue;
+            }
+            match (key.code, key.modifiers) {
+                (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(()),
+                (KeyCode::Char('u'), KeyModifiers::CONTROL) => widgets.update_stack_state(),
+                (KeyCode::Char('t'), KeyModifiers::NONE) => widgets.step(tvm),
+                (KeyCode::Char(' '), _) => widgets.toggle_running(tvm),
+                (KeyCode::Char('+'), _) | (KeyCode::Char('='), _) => widgets.change_speed(true),
+                (KeyCode::Char('-'), _) => widgets.change_speed(false),
+                (KeyCode::Char('b'), KeyModifiers::NONE) => {
+                    widgets.prompt = Some(Prompt::new(PromptKind::Breakpoint))
+                }
+                (KeyCode::Char('s'), KeyModifiers::NONE) => widgets.start(tvm),
+                (KeyCode::Char('r'), KeyModifiers::NONE) => {
+                    tvm.reset();
+                    widgets = TvmUI {
+                        speed: widgets.speed,
+                        ..TvmUI::default()
+                    };
+                    widgets.update_stack_state();
+                }
+                (KeyCode::Tab, _) => widgets.toggle_focus(),
+                (KeyCode::Down, _) => widgets.scroll(tvm, 1),
+                (KeyCode::Up, _) => widgets.scroll(tvm, -1),
+                (KeyCode::PageDown, _) => widgets.scroll(tvm, 16),
+                (KeyCode::PageUp, _) => widgets.scroll(tvm, -16),
+                (KeyCode::Home, _) => widgets.scroll(tvm, isize::MIN / 2),
+                (KeyCode::End, _) => widgets.scroll(tvm, isize::MAX / 2),
+                (KeyCode::Char('e'), KeyModifiers::NONE) => widgets.edit_selected(tvm),
+                (KeyCode::Char('/'), _) => {
+                    widgets.focus = Pane::Log;
+                    widgets.prompt = Some(Prompt {
+                        kind: PromptKind::LogSearch,
+                        text: widgets.log_filter.search.clone(),
+                    })
+                }
+                (KeyCode::Char('p'), KeyModifiers::NONE) => {
+                    widgets.prompt = Some(Prompt::new(PromptKind::StackPointer))
+                }
+                (KeyCode::Char('f'), KeyModifiers::NONE) => {
+                    widgets.prompt = Some(Prompt::new(PromptKind::FramePointer))
+                }
+                (KeyCode::Char('g'), KeyModifiers::NONE) => {
+                    widgets.prompt = Some(Prompt::new(PromptKind::HeapAddress))
+                }
+                (KeyCode::Char('o'), KeyModifiers::NONE) => {
+                    widgets.open_dialog(session, Path::new("."))
+                }
+                (KeyCode::Char('R'), _) => widgets.reload(tvm, session),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn ui<B: Backend>(f: &mut Frame<B>, tvm: &mut Tvm, widgets: &mut TvmUI, session: &Session) {
+    let main_layout = Layout::default()
+        .constraints(
+            [
+                Constraint::Percentage(25),
+                Constraint::Percentage(40),
+                Constraint::Percentage(35),
+            ]
+            .as_ref(),
+        )
+        .margin(3)
+        .direction(Direction::Horizontal)
+        .split(f.size());
+
+    let selected_style = Style::default().add_modifier(Modifier::REVERSED);
+    let normal_style = Style::default().bg(Color::Blue);
+    let header_cells = ["Address", "Value"]
+        .iter()
+        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Red)));
+    let header = Row::new(header_cells)
+        .style(normal_style)
+        .height(1)
+        .bottom_margin(1);
+    let calls = tvm.call_stack();
+    // The selection may be left past the end by a return.
+    let selected_call = widgets
+        .calls_state
+        .selected()
+        .map(|i| i.min(calls.len().saturating_sub(1)));
+    widgets
+        .calls_state
+        .select(selected_call.filter(|_| !calls.is_empty()));
+    let selected_cells = selected_call
+        .and_then(|i| calls.get(i))
+        .and_then(CallFrame::stack_cells);
+    let stack = tvm.get_stack_vec();
+    let rows = stack.iter().map(|(k, v)| {
+        let cells = vec![Cell::from(k.to_string()), Cell::from(v.to_string())];
+        match &selected_cells {
+            Some(cells_of_call) if cells_of_call.contains(k) => {
+                Row::new(cells).style(Style::default().fg(Color::Magenta))
+            }
+            _ => Row::new(cells),
+        }
+    });
+    // Edits of the heap show in the heap pane, everything else here.
+    let stack_title = match widgets.prompt.as_ref() {
+        Some(prompt) if !matches!(prompt.kind, PromptKind::Cell(a) if a < tvm.heap_size) => {
+            prompt.edit_title()
+        }
+        _ => None,
+    }
+    .unwrap_or_else(|| format!("Stack (sp {}, fp {})", tvm.stack_pointer, tvm.frame_pointer));
+    let t = Table::new(rows)
+        .header(header)
+        .block(pane_block(stack_title, widgets.focus == Pane::Stack))
+        .highlight_style(selected_style)
+        .highlight_symbol(">> ")
+        .widths(&[
+            Constraint::Percentage(50),
+            Constraint::Length(30),
+            Constraint::Min(10),
+        ]);
+
+    let memory_layout = Layout::default()
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .margin(0)
+        .direction(Direction::Vertical)
+        .split(main_layout[0]);
+
+    let heap_header = Row::new(
+        ["Address", "Int", "Char", ""]
             .iter()
-            .map(|l| ListItem::new(format!("{}{}", Self::get_tabs(l), l.get_name())))
-            .collect()
+            .map(|h| Cell::from(*h).style(Style::default().fg(Color::Red))),
+    )
+    .style(normal_style)
+    .height(1)
+    .bottom_margin(1);
+    let heap_title = match &widgets.prompt {
+        Some(Prompt {
+            kind: PromptKind::HeapAddress,
+            text,
+        }) => format!("Heap, go to address: {}_", text),
+        Some(
+            prompt @ Prompt {
+                kind: PromptKind::Cell(address),
+                ..
+            },
+        ) if *address < tvm.heap_size => prompt.edit_title().unwrap(),
+        _ => format!("Heap ({} cells, g to go to)", tvm.heap_size),
+    };
+    // Only the rows that fit are described, scrolled to keep the selected address in view.
+    let height = memory_layout[1].height.saturating_sub(4).max(1) as usize;
+    let selected = widgets.heap_state.selected().unwrap_or(0);
+    widgets.heap_offset = widgets
+        .heap_offset
+        .clamp(selected.saturating_sub(height - 1), selected)
+        .min(tvm.heap_size.saturating_sub(height));
+    let mut heap_state = TableState::default();
+    heap_state.select(
+        widgets
+            .heap_state
+            .selected()
+            .and_then(|address| address.checked_sub(widgets.heap_offset)),
+    );
+    let heap = Table::new(heap_to_rows(
+        tvm,
+        widgets.heap_offset..widgets.heap_offset + height,
+    ))
+    .header(heap_header)
+    .block(pane_block(heap_title, widgets.focus == Pane::Heap))
+    .highlight_style(selected_style)
+    .highlight_symbol(">> ")
+    .widths(&[
+        Constraint::Length(8),
+        Constraint::Length(12),
+        Constraint::Length(5),
+        Constraint::Percentage(60),
+    ]);
+
+    let state_layout = Layout::default()
+        .constraints(
+            [
+                Constraint::Percentage(10),
+                Constraint::Percentage(55),
+                Constraint::Percentage(35),
+            ]
+            .as_ref(),
+        )
+        .margin(0)
+        .direction(Direction::Vertical)
+        .split(main_layout[1]);
+
+    let state_title = match &widgets.prompt {
+        Some(Prompt {
+            kind: PromptKind::Input,
+            text,
+        }) => format!("Input: {}_", text),
+        Some(Prompt {
+            kind: PromptKind::Breakpoint,
+            text,
+        }) => format!("Toggle breakpoint on function: {}_", text),
+        _ => format!("Current State ({})", widgets.status()),
+    };
+    let breakpoints = match tvm.breakpoints.is_empty() {
+        true => String::new(),
+        false => format!(
+            "\nbreakpoints: {}",
+            tvm.breakpoints
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let state = Paragraph::new(format!("{}{}", tvm.state.get_name(), breakpoints))
+        .block(Block::default().borders(Borders::ALL).title(state_title));
+
+    let (code_title, code_items, code_selected) = tvm.code_to_list_items(session.source.as_deref());
+    let code = List::new(code_items)
+        .block(Block::default().borders(Borders::ALL).title(code_title))
+        .highlight_style(selected_style)
+        .highlight_symbol(">> ");
+    let mut code_state = ListState::default();
+    code_state.select(code_selected);
+
+    // Only the newest states that fit are turned into items, as in the log pane.
+    let height = state_layout[2].height.saturating_sub(2) as usize;
+    let history = tvm.state_history.iter();
+    let shown = history.len().min(height);
+    let history_items =
+        Tvm::state_history_to_list_items(history.skip(tvm.state_history.len() - shown));
+    let state_history = List::new(history_items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("State History"),
+        )
+        .highlight_style(selected_style)
+        .highlight_symbol(">> ");
+
+    let mut state_history_state = ListState::default();
+    state_history_state.select(shown.checked_sub(1));
+
+    f.render_widget(state, state_layout[0]);
+    f.render_stateful_widget(code, state_layout[1], &mut code_state);
+    f.render_stateful_widget(state_history, state_layout[2], &mut state_history_state);
+
+    let output_layout = Layout::default()
+        .constraints(
+            [
+                Constraint::Percentage(35),
+                Constraint::Percentage(35),
+                Constraint::Percentage(30),
+            ]
+            .as_ref(),
+        )
+        .margin(0)
+        .direction(Direction::Vertical)
+        .split(main_layout[2]);
+    let call_list = List::new(calls_to_list_items(&calls))
+        .block(pane_block(
+            format!("Call Stack ({} calls)", calls.len()),
+            widgets.focus == Pane::Calls,
+        ))
+        .highlight_style(selected_style)
+        .highlight_symbol(">> ");
+    let stdout = Paragraph::new(tvm.stdout.as_ref())
+        .block(Block::default().borders(Borders::ALL).title("Stdout"));
+    // Only the records that fit are turned into items, however long the log is.
+    let shown: Vec<&Record> = tvm.log.filtered(&widgets.log_filter).collect();
+    let height = output_layout[2].height.saturating_sub(2) as usize;
+    let end = shown.len().saturating_sub(widgets.log_scroll);
+    let start = end.saturating_sub(height);
+    let log_items: Vec<ListItem> = shown[start..end]
+        .iter()
+        .map(|record| {
+            let style = match record.level {
+                Level::Trace => Style::default().fg(Color::DarkGray),
+                Level::Debug => Style::default(),
+                Level::Info => Style::default().fg(Color::Cyan),
+            };
+            ListItem::new(record.to_string()).style(style)
+        })
+        .collect();
+    let log_title = match &widgets.prompt {
+        Some(Prompt {
+            kind: PromptKind::LogSearch,
+            text,
+        }) => format!("Log, search: {}_", text),
+        _ => log_title(tvm, &widgets.log_filter, shown.len()),
+    };
+    let log = List::new(log_items)
+        .block(pane_block(log_title, widgets.focus == Pane::Log))
+        .highlight_style(selected_style)
+        .highlight_symbol(">> ");
+    widgets.log_state = ListState::default();
+    widgets.log_state.select(end.checked_sub(start + 1));
+    f.render_stateful_widget(t, memory_layout[0], &mut widgets.stack_state);
+    f.render_stateful_widget(heap, memory_layout[1], &mut heap_state);
+    f.render_widget(stdout, output_layout[0]);
+    f.render_stateful_widget(call_list, output_layout[1], &mut widgets.calls_state);
+    f.render_stateful_widget(log, output_layout[2], &mut widgets.log_state);
+
+    // The title bar sits in the top margin.
+    let size = f.size();
+    let message = widgets
+        .message
+        .as_ref()
+        .map_or(String::new(), |m| format!(" - {}", m));
+    let title = Paragraph::new(format!(
+        "{}{}  (o open, R reload, r reset, Tab pane, e edit, p sp, f fp, / search log)",
+        session.name(),
+        message
+    ))
+    .style(Style::default().add_modifier(Modifier::BOLD));
+    if size.height > 1 {
+        f.render_widget(title, Rect::new(3, 1, size.width.saturating_sub(6), 1));
+    }
+
+    if let Some(dialog) = &mut widgets.dialog {
+        let height = (dialog.files.len() as u16 + 2).min(size.height.saturating_sub(6));
+        let width = size.width / 2;
+        let area = Rect::new((size.width - width) / 2, 3, width, height);
+        let files = dialog
+            .files
+            .iter()
+            .map(|file| ListItem::new(file.as_str()))
+            .collect::<Vec<_>>();
+        let list = List::new(files)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Open (Enter to open, Esc to cancel)"),
+            )
+            .highlight_style(selected_style)
+            .highlight_symbol(">> ");
+        f.render_widget(Clear, area);
+        f.render_stateful_widget(list, area, &mut dialog.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::program::Program;
+    use crate::test_utils::temp_dir;
+    use tui::backend::TestBackend;
+
+    fn render(tvm: &mut Tvm, widgets: &mut TvmUI) -> String {
+        render_session(tvm, widgets, &Session::default())
+    }
+
+    fn render_session(tvm: &mut Tvm, widgets: &mut TvmUI, session: &Session) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(240, 60)).unwrap();
+        terminal.draw(|f| ui(f, tvm, widgets, session)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|row| {
+                row.iter()
+                    .map(|cell| cell.symbol.as_str())
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_heap_pane() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        let mut widgets = TvmUI::default();
+        let screen = render(&mut tvm, &mut widgets);
+        assert!(screen.contains("Heap (36 cells"));
+        assert!(screen.contains("\"Table of squares:\\n\""));
+
+        widgets.prompt = Some(Prompt::new(PromptKind::HeapAddress));
+        for code in [KeyCode::Char('2'), KeyCode::Char('x'), KeyCode::Char('0')] {
+            widgets.prompt_key(&mut tvm, code);
+        }
+        assert!(render(&mut tvm, &mut widgets).contains("go to address: 20_"));
+        widgets.prompt_key(&mut tvm, KeyCode::Enter);
+        assert_eq!(widgets.prompt, None);
+        assert_eq!(widgets.heap_state.selected(), Some(20));
+        widgets.scroll_heap(&tvm, 100);
+        assert_eq!(widgets.heap_state.selected(), Some(35));
+        // The pane only holds part of the heap, so it scrolls to the selection.
+        assert!(render(&mut tvm, &mut widgets).contains(">> 35"));
+        assert!(widgets.heap_offset > 0);
+        widgets.scroll_heap(&tvm, -100);
+        assert_eq!(widgets.heap_state.selected(), Some(0));
+        assert!(render(&mut tvm, &mut widgets).contains(">> 0 "));
+        assert_eq!(widgets.heap_offset, 0);
+    }
+
+    fn run_until_paused(tvm: &mut Tvm, widgets: &mut TvmUI) {
+        if !widgets.running {
+            widgets.toggle_running(tvm);
+        }
+        for _ in 0..100_000 {
+            if !widgets.running || widgets.prompt.is_some() {
+                return;
+            }
+            widgets.run_frame(tvm);
+        }
+        panic!("never paused");
+    }
+
+    #[test]
+    fn test_run_to_breakpoint_and_halt() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        tvm.breakpoints.insert("sq".to_string());
+        let mut widgets = TvmUI {
+            speed: SPEEDS.len() - 1,
+            ..TvmUI::default()
+        };
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(widgets.pause, Some(Pause::Breakpoint("sq".to_string())));
+        assert_eq!(tvm.stdout, "Table of squares:\n1 squared equals ");
+        assert_eq!(widgets.status(), "paused, breakpoint at sq");
+
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(widgets.pause, Some(Pause::Breakpoint("sq".to_string())));
+        assert!(tvm.stdout.ends_with("2 squared equals "));
+
+        tvm.breakpoints.clear();
+        widgets.change_speed(false);
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(widgets.pause, Some(Pause::Halted));
+        assert!(tvm.stdout.ends_with("100\n"));
+        // A halted machine does not start running again.
+        widgets.toggle_running(&mut tvm);
+        assert!(!widgets.running);
+    }
+
+    #[test]
+    fn test_pause_for_input_and_fault() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_source("fun init() {\n    iprint(60 / iread(-1))\n}").unwrap());
+        tvm.input = Some(String::new());
+        let mut widgets = TvmUI::default();
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(widgets.pause, Some(Pause::Input));
+        assert!(widgets.running);
+        assert!(render(&mut tvm, &mut widgets).contains("Input: _"));
+        for code in [KeyCode::Char('1'), KeyCode::Char('2'), KeyCode::Enter] {
+            widgets.prompt_key(&mut tvm, code);
+        }
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(widgets.pause, Some(Pause::Halted));
+        assert_eq!(tvm.stdout, "5");
+
+        tvm.reset();
+        tvm.input = Some("0\n".to_string());
+        let mut widgets = TvmUI::default();
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(
+            widgets.pause,
+            Some(Pause::Fault("attempt to divide by zero".to_string()))
+        );
+        assert_eq!(
+            widgets.status(),
+            "stopped, fault: attempt to divide by zero; r resets, R reloads"
+        );
+        // The faulted machine is not run, stepped or started again.
+        let (ticks, depth) = (tvm.ticks, tvm.state.depth());
+        widgets.toggle_running(&mut tvm);
+        assert!(!widgets.running);
+        widgets.step(&mut tvm);
+        widgets.start(&mut tvm);
+        assert_eq!(tvm.ticks, ticks);
+        assert_eq!(tvm.state.depth(), depth);
+    }
+
+    #[test]
+    fn test_open_and_reload() {
+        let dir = temp_dir("ui-open");
+        let one = dir.join("one.t");
+        fs::write(&one, "fun init() {\n    iprint(1)\n}").unwrap();
+        fs::copy("sq.json", dir.join("sq.json")).unwrap();
+        fs::write(dir.join("notes.txt"), "not a program").unwrap();
+
+        let one = one.to_str().unwrap();
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_path(one).unwrap());
+        let mut session = Session::new(&tvm, one);
+        let mut widgets = TvmUI::default();
+        tvm.breakpoints.insert("init".to_string());
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(tvm.stdout, "1");
+        assert!(render_session(&mut tvm, &mut widgets, &session).contains("one.t  (o open"));
+
+        // A recompiled file is picked up by reloading, keeping the breakpoints.
+        fs::write(dir.join("one.t"), "fun init() {\n    iprint(2)\n}").unwrap();
+        widgets.reload(&mut tvm, &mut session);
+        assert_eq!(widgets.message.as_deref(), Some("reloaded"));
+        assert_eq!(tvm.stdout, "");
+        assert!(tvm.breakpoints.contains("init"));
+        tvm.breakpoints.clear();
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(tvm.stdout, "2");
+
+        // A broken file leaves the loaded program alone.
+        fs::write(dir.join("one.t"), "fun init( {").unwrap();
+        widgets.reload(&mut tvm, &mut session);
+        assert!(widgets
+            .message
+            .as_ref()
+            .unwrap()
+            .starts_with("unable to load"));
+        assert_eq!(tvm.stdout, "2");
+
+        widgets.open_dialog(&session, &dir);
+        let dialog = widgets.dialog.as_ref().unwrap();
+        assert_eq!(dialog.files.len(), 2);
+        assert_eq!(dialog.state.selected(), Some(0));
+        assert!(render_session(&mut tvm, &mut widgets, &session).contains("sq.json"));
+        widgets.dialog_key(&mut tvm, &mut session, KeyCode::Down);
+        widgets.dialog_key(&mut tvm, &mut session, KeyCode::Enter);
+        assert!(widgets.dialog.is_none());
+        assert_eq!(session.name(), "sq.json");
+        assert!(tvm.program.functions.iter().any(|f| f.name == "sq"));
+        run_until_paused(&mut tvm, &mut widgets);
+        assert_eq!(tvm.stdout, fs::read_to_string("sq.stdout").unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn type_line(widgets: &mut TvmUI, tvm: &mut Tvm, text: &str) {
+        for c in text.chars() {
+            widgets.prompt_key(tvm, KeyCode::Char(c));
+        }
+        widgets.prompt_key(tvm, KeyCode::Enter);
+    }
+
+    #[test]
+    fn test_memory_editor() {
+        let mut tvm = Tvm {
+            log: Log::new(100),
+            ..Tvm::default()
+        };
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        tvm.run(30);
+        let mut widgets = TvmUI::default();
+        widgets.update_stack_state();
+
+        // The second cell of the stack.
+        widgets.scroll(&tvm, 1);
+        let address = tvm.stack_pointer + 1;
+        assert_eq!(widgets.selected_cell(&tvm), Some(address));
+        widgets.edit_selected(&tvm);
+        assert!(render(&mut tvm, &mut widgets)
+            .contains(&format!("Set cell {} (decimal, 0x hex or 'c'): _", address)));
+        type_line(&mut widgets, &mut tvm, "0x10");
+        assert_eq!(tvm.memory[address], 16);
+
+        widgets.toggle_focus();
+        widgets.scroll(&tvm, 2);
+        widgets.edit_selected(&tvm);
+        assert!(render(&mut tvm, &mut widgets).contains("Set cell 2 (decimal"));
+        type_line(&mut widgets, &mut tvm, "'A'");
+        assert_eq!(tvm.memory[2], 65);
+        widgets.edit_selected(&tvm);
+        type_line(&mut widgets, &mut tvm, "twelve");
+        assert_eq!(widgets.message.as_deref(), Some("not a value: twelve"));
+        assert_eq!(tvm.memory[2], 65);
+
+        let sp = tvm.stack_pointer;
+        widgets.prompt = Some(Prompt::new(PromptKind::StackPointer));
+        type_line(&mut widgets, &mut tvm, &(sp - 2).to_string());
+        assert_eq!(tvm.stack_pointer, sp - 2);
+        widgets.prompt = Some(Prompt::new(PromptKind::FramePointer));
+        type_line(&mut widgets, &mut tvm, "-1");
+        assert_eq!(
+            widgets.message.as_deref(),
+            Some("pointers cannot be negative")
+        );
+        widgets.prompt = Some(Prompt::new(PromptKind::FramePointer));
+        type_line(&mut widgets, &mut tvm, "3");
+        assert_eq!(widgets.message.as_deref(), Some("fp 3 is out of range"));
+        let edits: Vec<&str> = tvm
+            .log
+            .records()
+            .filter(|r| r.category == Category::Debugger)
+            .map(|r| r.message.as_str())
+            .collect();
+        assert!(edits[0].starts_with(&format!("Edited cell {}: ", address)));
+        assert_eq!(edits[1], "Edited cell 2: 98 -> 65");
+        assert_eq!(edits[2], format!("Edited sp: {} -> {}", sp, sp - 2));
+    }
+
+    #[test]
+    fn test_parse_value() {
+        assert_eq!(parse_value("42"), Some(42));
+        assert_eq!(parse_value(" -7 "), Some(-7));
+        assert_eq!(parse_value("0x1F"), Some(31));
+        assert_eq!(parse_value("-0x10"), Some(-16));
+        assert_eq!(parse_value("0xffffffff"), Some(-1));
+        assert_eq!(parse_value("'a'"), Some(97));
+        assert_eq!(parse_value("'ab'"), None);
+        assert_eq!(parse_value("4294967296"), None);
+        assert_eq!(parse_value("x"), None);
+    }
+
+    #[test]
+    fn test_call_stack_pane() {
+        let mut tvm = Tvm::default();
+        tvm.load(Program::from_source_file("sq.t".to_string()).unwrap());
+        tvm.breakpoints.insert("sq".to_string());
+        let mut widgets = TvmUI::default();
+        run_until_paused(&mut tvm, &mut widgets);
+        tvm.step();
+        let screen = render(&mut tvm, &mut widgets);
+        assert!(screen.contains("sq pc - fp"));
+        tvm.step();
+        let screen = render(&mut tvm, &mut widgets);
+        assert!(screen.contains("Call Stack (2 calls)"));
+        assert!(screen.contains(&format!(
+            "sq pc 0 line 3 fp {}, returns to init pc",
+            tvm.frame_pointer
+        )));
+        assert!(screen.contains("  args: n=1 @"));
+
+        widgets.toggle_focus();
+        widgets.toggle_focus();
+        assert_eq!(widgets.focus, Pane::Calls);
+        widgets.scroll(&tvm, 5);
+        assert_eq!(widgets.calls_state.selected(), Some(1));
+        let mut terminal = Terminal::new(TestBackend::new(240, 60)).unwrap();
+        terminal
+            .draw(|f| ui(f, &mut tvm, &mut widgets, &Session::default()))
+            .unwrap();
+        // The stack rows of init are highlighted, those of sq are not.
+        let init_fp = tvm.memory[tvm.frame_pointer] as usize;
+        let buffer = terminal.backend().buffer();
+        let row_color = |address: usize| {
+            let y = (0..buffer.area.height)
+                .find(|y| {
+                    let line: String = (4..20).map(|x| buffer.get(x, *y).symbol.as_str()).collect();
+                    line.trim_start_matches(['>', ' ', '│'])
+                        .starts_with(&address.to_string())
+                })
+                .unwrap();
+            buffer.get(8, y).fg
+        };
+        assert_eq!(row_color(init_fp), Color::Magenta);
+        assert_eq!(row_color(tvm.frame_pointer), Color::Reset);
+    }
+
+    #[test]
+    fn test_log_pane() {
+        let mut tvm = Tvm {
+            log: Log::new(50),
+            ..Tvm::default()
+        };
+        tvm.load(Program::from_file("sq.json".to_string()).unwrap());
+        tvm.run(30);
+        assert_eq!(tvm.log.len(), 50);
+        let mut widgets = TvmUI::default();
+        let screen = render(&mut tvm, &mut widgets);
+        let dropped = tvm.log.dropped;
+        assert!(screen.contains(&format!(
+            "Log (50 of 50, {} dropped) [1:stack 2:state 3:call 4:native 5:debugger] trace+",
+            dropped
+        )));
+
+        widgets.focus = Pane::Log;
+        assert!(widgets.log_key(KeyCode::Char('1')));
+        assert!(widgets.log_key(KeyCode::Char('v')));
+        assert!(!widgets.log_key(KeyCode::Char('x')));
+        let shown = tvm.log.filtered(&widgets.log_filter).count();
+        assert!(shown > 0 && shown < 50);
+        assert!(tvm
+            .log
+            .filtered(&widgets.log_filter)
+            .all(|r| r.category != Category::Stack && r.level >= Level::Debug));
+        assert!(
+            render(&mut tvm, &mut widgets).contains("[2:state 3:call 4:native 5:debugger] debug+")
+        );
+
+        widgets.prompt = Some(Prompt::new(PromptKind::LogSearch));
+        assert!(render(&mut tvm, &mut widgets).contains("Log, search: _"));
+        type_line(&mut widgets, &mut tvm, "Calling");
+        let screen = render(&mut tvm, &mut widgets);
+        assert!(screen.contains("native   Calling iprint"));
+        assert!(!screen.contains("Tick "));
+
+        widgets.log_filter = Filter::default();
+        widgets.scroll(&tvm, -1000);
+        assert_eq!(widgets.log_scroll, 49);
+        widgets.scroll(&tvm, 1000);
+        assert_eq!(widgets.log_scroll, 0);
     }
 }
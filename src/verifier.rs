@@ -0,0 +1,410 @@
+use crate::frame::{Frame, FrameData};
+use crate::function::Function;
+use crate::instruction::Instruction;
+use crate::native::NativeFunction;
+use crate::program::Program;
+use std::fmt::{Display, Formatter};
+
+// Static checks run on a tape before it is executed, so malformed tapes are reported up front
+// instead of panicking mid-run.
+//
+// Besides the structure of every frame, the stack depth is tracked relative to the start of each
+// function: both branches of an IF must leave the same depth, a LOOP body must leave the depth
+// it started with, and every BREAK of a loop must exit it at the same depth.
+
+const MEMORY_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifierError {
+    // Function name followed by the index of each nested frame, e.g. `init[12][3]`.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for VerifierError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for VerifierError {}
+
+impl Program {
+    pub fn verify(&self) -> Result<(), Vec<VerifierError>> {
+        let mut verifier = Verifier {
+            program: self,
+            errors: Vec::new(),
+            function: "program".to_string(),
+            path: Vec::new(),
+            loops: Vec::new(),
+        };
+        verifier.program();
+        if verifier.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(verifier.errors)
+        }
+    }
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    errors: Vec<VerifierError>,
+    function: String,
+    path: Vec<usize>,
+    // Depth at which each enclosing loop is exited, once a BREAK has been seen.
+    loops: Vec<Option<usize>>,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&mut self, index: Option<usize>, message: String) {
+        let mut path = self.function.clone();
+        for i in self.path.iter().chain(&index) {
+            path.push_str(&format!("[{}]", i));
+        }
+        self.errors.push(VerifierError { path, message });
+    }
+
+    fn program(&mut self) {
+        if self.program.entry_point >= self.program.functions.len() {
+            self.error(
+                None,
+                format!("entry point {} does not exist", self.program.entry_point),
+            );
+        }
+        if self.program.heap_size > MEMORY_SIZE {
+            self.error(
+                None,
+                format!("heap size {} exceeds memory", self.program.heap_size),
+            );
+        }
+        for (address, _) in &self.program.heap {
+            if *address >= MEMORY_SIZE {
+                self.error(None, format!("heap address {} out of range", address));
+            }
+        }
+        for (index, function) in self.program.functions.iter().enumerate() {
+            self.function(index, function);
+        }
+    }
+
+    fn function(&mut self, index: usize, function: &Function) {
+        self.function = function.name.clone();
+        if function.id != index {
+            self.error(
+                None,
+                format!("id {} does not match position {}", function.id, index),
+            );
+        }
+        self.frame(&function.frame, Some(0));
+    }
+
+    fn nested_frame(&mut self, frame: &Frame, index: usize, depth: Option<usize>) -> Option<usize> {
+        self.path.push(index);
+        let depth = self.frame(frame, depth);
+        self.path.pop();
+        depth
+    }
+
+    // Checks a frame entered at `depth`, returning the depth it is left at. `None` stands for
+    // code that cannot be reached, such as after a RETURN.
+    fn frame(&mut self, frame: &Frame, mut depth: Option<usize>) -> Option<usize> {
+        let data = &frame.data;
+        let mut pc = 0;
+        while pc < data.len() {
+            let index = pc;
+            pc += 1;
+            let instruction = match &data[index] {
+                FrameData::Instruction(instruction, _) => instruction,
+                FrameData::Frame(nested) => {
+                    depth = self.nested_frame(nested, index, depth);
+                    continue;
+                }
+                other => {
+                    self.error(Some(index), format!("unexpected word {}", other.get_id()));
+                    continue;
+                }
+            };
+            depth = match instruction {
+                Instruction::Push { .. } | Instruction::Call { .. } => {
+                    let operand = match data.get(pc) {
+                        Some(FrameData::Frame(_)) | None => {
+                            self.error(
+                                Some(index),
+                                format!("{} is missing its operand", instruction.mnemonic()),
+                            );
+                            continue;
+                        }
+                        Some(operand) => operand.get_id(),
+                    };
+                    pc += 1;
+                    if matches!(instruction, Instruction::Push { .. }) {
+                        depth.map(|d| d + 1)
+                    } else {
+                        match self.call_args(operand) {
+                            Ok(args) => self.effect(index, depth, args, 1),
+                            Err(message) => {
+                                self.error(Some(index), message);
+                                None
+                            }
+                        }
+                    }
+                }
+                Instruction::IF { .. } => {
+                    let (Some(FrameData::Frame(then)), Some(FrameData::Frame(otherwise))) =
+                        (data.get(pc), data.get(pc + 1))
+                    else {
+                        self.error(Some(index), "IF must be followed by two frames".to_string());
+                        continue;
+                    };
+                    let depth = self.effect(index, depth, 1, 0);
+                    let then_depth = self.nested_frame(then, pc, depth);
+                    let else_depth = self.nested_frame(otherwise, pc + 1, depth);
+                    pc += 2;
+                    match (then_depth, else_depth) {
+                        (Some(t), Some(e)) if t != e => {
+                            self.error(
+                                Some(index),
+                                format!(
+                                    "IF branches leave different stack depths (then {}, else {})",
+                                    t, e
+                                ),
+                            );
+                            Some(t)
+                        }
+                        (t, e) => t.or(e),
+                    }
+                }
+                Instruction::Loop { .. } => {
+                    let Some(FrameData::Frame(body)) = data.get(pc) else {
+                        self.error(Some(index), "LOOP must be followed by a frame".to_string());
+                        continue;
+                    };
+                    self.loops.push(None);
+                    let body_depth = self.nested_frame(body, pc, depth);
+                    pc += 1;
+                    if let (Some(start), Some(end)) = (depth, body_depth) {
+                        if start != end {
+                            self.error(
+                                Some(index),
+                                format!(
+                                    "LOOP body changes stack depth by {}",
+                                    end as i64 - start as i64
+                                ),
+                            );
+                        }
+                    }
+                    self.loops.pop().unwrap()
+                }
+                Instruction::Break { .. } => {
+                    let exit = self.effect(index, depth, 1, 0);
+                    match self.loops.last().copied() {
+                        None => self.error(Some(index), "BREAK outside of LOOP".to_string()),
+                        Some(Some(previous)) if exit.is_some() && exit != Some(previous) => {
+                            self.error(
+                                Some(index),
+                                format!(
+                                    "BREAK exits loop at depth {}, expected {}",
+                                    exit.unwrap(),
+                                    previous
+                                ),
+                            );
+                        }
+                        Some(_) => {
+                            if exit.is_some() {
+                                *self.loops.last_mut().unwrap() = exit;
+                            }
+                        }
+                    }
+                    exit
+                }
+                Instruction::Return { .. } => {
+                    self.effect(index, depth, 1, 0);
+                    None
+                }
+                Instruction::Fetch { .. }
+                | Instruction::FPPlus { .. }
+                | Instruction::Not { .. } => self.effect(index, depth, 1, 1),
+                Instruction::Store { .. } => self.effect(index, depth, 2, 0),
+                Instruction::Pop { .. } => self.effect(index, depth, 1, 0),
+                Instruction::Unknown(op) => {
+                    self.error(Some(index), format!("unknown instruction {}", op));
+                    None
+                }
+                _ => self.effect(index, depth, 2, 1),
+            };
+        }
+        depth
+    }
+
+    // Number of arguments taken by a call target.
+    fn call_args(&self, id: i32) -> Result<usize, String> {
+        match id {
+            n @ -111..=-101 => Ok(NativeFunction::get_native(n).args() as usize),
+            n if n < 0 => Err(format!("unknown native {}", n)),
+            n => match self.program.functions.get(n as usize) {
+                Some(function) => Ok(function.args),
+                None => Err(format!("call to missing function {}", n)),
+            },
+        }
+    }
+
+    fn effect(
+        &mut self,
+        index: usize,
+        depth: Option<usize>,
+        pops: usize,
+        pushes: usize,
+    ) -> Option<usize> {
+        let depth = depth?;
+        if depth < pops {
+            self.error(
+                Some(index),
+                format!("stack underflow: needs {} values, has {}", pops, depth),
+            );
+            return None;
+        }
+        Some(depth - pops + pushes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn errors(listing: &str) -> Vec<String> {
+        match Program::from_assembly(listing).unwrap().verify() {
+            Ok(()) => vec![],
+            Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_shipped_tapes() {
+        Program::from_file("sq.json".to_string())
+            .unwrap()
+            .verify()
+            .unwrap();
+        Program::from_file("sieve.json".to_string())
+            .unwrap()
+            .verify()
+            .unwrap();
+        Program::from_source_file("sieve.t".to_string())
+            .unwrap()
+            .verify()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_structure() {
+        let mut program = Program::from_file("sq.json".to_string()).unwrap();
+        let init = &mut Arc::make_mut(&mut program.functions[1]).frame.data;
+        // The LOOP body of init, and the operand of its last PUSH.
+        init.remove(12);
+        init.pop();
+        program.heap.push((70000, 1));
+        Arc::make_mut(&mut program.functions[0])
+            .frame
+            .data
+            .push(FrameData::from(8));
+        let errors: Vec<String> = program
+            .verify()
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                "program: heap address 70000 out of range",
+                "sq[12]: CALL is missing its operand",
+                "init[11]: LOOP must be followed by a frame",
+                "init[12]: PUSH is missing its operand",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_call_targets() {
+        assert_eq!(
+            errors(
+                ".init 0 0:
+    CALL    .f
+    CALL    7
+    CALL    -120
+    CALL    -99
+.f 0 0:
+    PUSH    0
+"
+            ),
+            vec![
+                "init[2]: call to missing function 7",
+                "init[4]: unknown native -120",
+                "init[6]: unknown native -99",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        assert_eq!(
+            errors(
+                ".init 0 0:
+    PUSH    1
+    IF
+        PUSH    1
+        PUSH    2
+    ELSE
+        PUSH    3
+    LOOP
+        PUSH    1
+        PUSH    1
+        BREAK
+    ADD
+.f 0 0:
+    PUSH    0
+    IF
+        PUSH    1
+        RETURN
+    ELSE
+        PUSH    2
+    LOOP
+        PUSH    1
+        BREAK
+        PUSH    1
+        PUSH    1
+        BREAK
+        POP
+    BREAK
+"
+            ),
+            vec![
+                "init[2]: IF branches leave different stack depths (then 2, else 1)",
+                "init[5]: LOOP body changes stack depth by 1",
+                "f[6][7]: BREAK exits loop at depth 2, expected 1",
+                "f[7]: BREAK outside of LOOP",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_native_args() {
+        // stoptimer pops only the timer id.
+        assert!(errors(
+            ".init 0 0:
+    PUSH    1
+    CALL    stoptimer
+"
+        )
+        .is_empty());
+        assert_eq!(
+            errors(
+                ".init 0 0:
+    CALL    stoptimer
+"
+            ),
+            vec!["init[0]: stack underflow: needs 1 values, has 0"]
+        );
+    }
+}